@@ -0,0 +1,98 @@
+//! Replays historical rates in the Yahoo format against the seller while the
+//! buyer buys the dips of 5 % below the weekly high, prints the outcome and
+//! writes the HTML report.
+//!
+//! ```text
+//! cargo run --example backtest -- [rates.csv] [report.html]
+//! ```
+
+use {
+    chrono::Duration,
+    rand::{rngs::StdRng, SeedableRng},
+    std::{env, path::PathBuf},
+};
+
+use broker::{
+    backtest::{self, report, FillModel},
+    buyer, history,
+    models::{Fee, Liquidation},
+    prelude::*,
+    seller::HoldingPeriod,
+};
+
+const HISTORICAL_DATA_PATH: &str =
+    "tests/data/btc_usd_2019_02_01-2020_08_19.csv";
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let data = args
+        .next()
+        .unwrap_or_else(|| HISTORICAL_DATA_PATH.to_string());
+    let report_path = args.next().map_or_else(
+        || env::temp_dir().join("broker_backtest.html"),
+        PathBuf::from,
+    );
+
+    // * initial investment of $2k
+    // * We always buy BTC for $250, at most twice a week.
+    // * Offers which are not filled within 3 days expire.
+    let config = backtest::Config {
+        fee: Fee::Percentage(Percentage::new(25, 2)),
+        min_margin: Percentage::new(10, 0),
+        holding_period: HoldingPeriod::default(),
+        trailing: None,
+        liquidation: Liquidation::default(),
+        volatility: None,
+        investment: Cash::new(2_000, 0),
+        buyer: buyer::Config {
+            reference: buyer::Reference::RecentHigh,
+            window: Duration::weeks(1),
+            sampling: Duration::days(1),
+            dip: Percentage::new(5, 0),
+            cooldown: Duration::days(2),
+            spending: Cash::new(250, 0),
+            weekly_budget: Cash::new(500, 0),
+        },
+        fill: FillModel {
+            expiry: Duration::days(3),
+            ..FillModel::default()
+        },
+        dca_interval: Duration::weeks(1),
+    };
+
+    let candles = history::load(&data, history::Format::Yahoo)?;
+    let outcome =
+        backtest::run(&candles, &config, &mut StdRng::from_entropy())?;
+
+    let total_monthly_margin: Cash =
+        outcome.monthly_margin.values().copied().sum();
+    let avg_monthly_margin =
+        total_monthly_margin / Cash::from(outcome.monthly_margin.len());
+    println!("Monthly margin:");
+    for (month, margin) in &outcome.monthly_margin {
+        println!("[{}] ${}", month, margin.round_dp(2));
+    }
+    println!(
+        "Total ${}, avg ${}",
+        total_monthly_margin.round_dp(2),
+        avg_monthly_margin.round_dp(2),
+    );
+    println!(
+        "Ended up with {} BTC and net ${}.",
+        outcome.btc.round_dp(6),
+        (outcome.cash - config.investment).round_dp(2)
+    );
+    for baseline in &outcome.baselines {
+        println!(
+            "{:?}: return {}%, broker's alpha {}%",
+            baseline.strategy,
+            baseline.return_pct.round_dp(2),
+            baseline.alpha.round_dp(2),
+        );
+    }
+
+    report::write(&report_path, &data, &candles, &outcome)?;
+    println!("Report written to {}", report_path.display());
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+//! Naive strategies which the broker is compared against. They are run over
+//! the same candles with the same capital, and buy at the same conservative
+//! rate as the simulated buyer in the backtest.
+
+use chrono::Duration;
+
+use crate::{history::Candle, prelude::*};
+
+/// The strategies the broker is compared against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Spends all the capital on the first candle and holds.
+    BuyAndHold,
+    /// Spends equal parts of the capital in fixed intervals and holds.
+    DollarCostAveraging,
+    /// Never trades.
+    CashOnly,
+    /// The broker with the fixed min margin, when the backtested broker
+    /// adjusts it to volatility.
+    FixedMargin,
+}
+
+/// How a baseline strategy did compared to the broker.
+#[derive(Debug, Clone, Copy)]
+pub struct Baseline {
+    pub strategy: Strategy,
+    /// Value of cash and bitcoins at the close of the last candle.
+    pub equity: Cash,
+    /// Profit in percent of the initial capital.
+    pub return_pct: Percentage,
+    /// By how many percentage points did the broker beat the strategy.
+    pub alpha: Percentage,
+}
+
+/// Computes the baselines for given candles and the broker's final equity.
+pub fn compare(
+    candles: &[Candle],
+    investment: Cash,
+    dca_interval: Duration,
+    broker_equity: Cash,
+) -> Vec<Baseline> {
+    let broker_return = return_pct(investment, broker_equity);
+
+    [
+        (Strategy::BuyAndHold, buy_and_hold(candles, investment)),
+        (
+            Strategy::DollarCostAveraging,
+            dollar_cost_averaging(candles, investment, dca_interval),
+        ),
+        (Strategy::CashOnly, investment),
+    ]
+    .iter()
+    .map(|(strategy, equity)| {
+        let return_pct = return_pct(investment, *equity);
+        Baseline {
+            strategy: *strategy,
+            equity: *equity,
+            return_pct,
+            alpha: broker_return - return_pct,
+        }
+    })
+    .collect()
+}
+
+/// Profit in percent of the initial capital.
+pub fn return_pct(investment: Cash, equity: Cash) -> Percentage {
+    if investment == Cash::new(0, 0) {
+        Percentage::new(0, 0)
+    } else {
+        (equity - investment) / investment * Decimal::new(100, 0)
+    }
+}
+
+/// Spends all capital on the first candle. Returns the equity at the end.
+pub fn buy_and_hold(candles: &[Candle], investment: Cash) -> Cash {
+    match (candles.first(), candles.last()) {
+        (Some(first), Some(last)) => {
+            investment / buying_rate(first) * last.close
+        }
+        _ => investment,
+    }
+}
+
+/// Splits the capital into equal parts, one for each interval in the
+/// replayed period, and spends one part on the first candle of each interval.
+/// Returns the equity at the end.
+pub fn dollar_cost_averaging(
+    candles: &[Candle],
+    investment: Cash,
+    interval: Duration,
+) -> Cash {
+    let (first, last) = match (candles.first(), candles.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return investment,
+    };
+
+    let interval = interval.num_seconds().max(1);
+    let span = (last.time - first.time).num_seconds();
+    let parts = span / interval + 1;
+    let spending = investment / Decimal::from(parts);
+
+    let mut cash = investment;
+    let mut btc = Btc::new(0, 0);
+    let start = first.time.timestamp();
+    let mut next_purchase = start;
+    for candle in candles {
+        let time = candle.time.timestamp();
+        if time >= next_purchase && cash >= spending {
+            btc += spending / buying_rate(candle);
+            cash -= spending;
+            // After a gap in the candles we don't catch up on the missed
+            // intervals, the next purchase is in the next interval.
+            next_purchase = start + ((time - start) / interval + 1) * interval;
+        }
+    }
+
+    cash + btc * last.close
+}
+
+// We buy at a rate between the candle's average and high, same as the
+// simulated buyer.
+fn buying_rate(candle: &Candle) -> BtcExchangeRate {
+    let mid = candle.mid();
+    mid + (candle.high - mid) / Decimal::new(2, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn should_compute_baselines_and_alpha() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        // The price doubles, halves and then quadruples every week.
+        let candles: Vec<_> = [100, 200, 100, 400]
+            .iter()
+            .enumerate()
+            .map(|(week, price)| {
+                Candle::from_tick(
+                    start + Duration::weeks(week as i64),
+                    Decimal::new(*price, 0),
+                    Decimal::new(1, 0),
+                )
+            })
+            .collect();
+        let investment = Cash::new(1_000, 0);
+
+        assert_eq!(Cash::new(4_000, 0), buy_and_hold(&candles, investment));
+        // $250 each week buys 2.5 + 1.25 + 2.5 + 0.625 BTC.
+        assert_eq!(
+            Cash::new(2_750, 0),
+            dollar_cost_averaging(&candles, investment, Duration::weeks(1))
+        );
+
+        // Weeks 1 and 2 are missing, still only one purchase is made in
+        // week 3. $200 each week buys 2 + 1 + 0.5 BTC.
+        let gap: Vec<_> = [(0, 100), (21, 200), (22, 200), (28, 400)]
+            .iter()
+            .map(|(day, price)| {
+                Candle::from_tick(
+                    start + Duration::days(*day),
+                    Decimal::new(*price, 0),
+                    Decimal::new(1, 0),
+                )
+            })
+            .collect();
+        assert_eq!(
+            Cash::new(1_800, 0),
+            dollar_cost_averaging(&gap, investment, Duration::weeks(1))
+        );
+
+        let baselines = compare(
+            &candles,
+            investment,
+            Duration::weeks(2),
+            Cash::new(2_000, 0),
+        );
+        let alphas: Vec<_> = baselines
+            .iter()
+            .map(|b| (b.strategy, b.return_pct, b.alpha))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    Strategy::BuyAndHold,
+                    Percentage::new(300, 0),
+                    Percentage::new(-200, 0)
+                ),
+                // $500 in the first and the third week buys 5 + 5 BTC.
+                (
+                    Strategy::DollarCostAveraging,
+                    Percentage::new(300, 0),
+                    Percentage::new(-200, 0)
+                ),
+                (
+                    Strategy::CashOnly,
+                    Percentage::new(0, 0),
+                    Percentage::new(100, 0)
+                ),
+            ],
+            alphas
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Simulates how offers placed at the marketplace get filled when replaying
+//! historical candles. We only know the open, high, low and close of each
+//! period, therefore the simulation errs on the side of caution: a limit sell
+//! is never filled unless the candle's high traded through the limit price.
+//! Like at the marketplace, it's then filled at its limit, never lower.
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    rand::Rng,
+};
+
+use crate::{history::Candle, models::Offer, prelude::*, pricing::markup};
+
+/// Configures how pessimistic the fill simulation is.
+#[derive(Debug, Clone, Copy)]
+pub struct FillModel {
+    /// How many percent above the limit price does a candle have to trade
+    /// for our offer to be filled. Slippage shows as fills we miss rather
+    /// than worse rates, as a limit order never sells below its limit. This
+    /// covers for the rate reported in the historical data not being exactly
+    /// what the order book offered.
+    pub slippage: Percentage,
+    /// Probability between 0 and 1 that our offer is filled when a candle
+    /// trades through its limit price. There might be other offers at the
+    /// same price which are ahead of ours in the queue. The dice is rolled for
+    /// each candle which trades through the limit price.
+    pub queue_fill_probability: f64,
+    /// For how long is an offer valid. Offers which are not filled by then
+    /// expire and their purchases return to the seller.
+    pub expiry: Duration,
+}
+
+/// What happened to an offer during a candle.
+#[derive(Debug)]
+pub enum Fill {
+    /// The offer was sold at given rate, its limit.
+    Filled { offer: Offer, rate: BtcExchangeRate },
+    /// Nobody took the offer before its expiry.
+    Expired(Offer),
+}
+
+/// Keeps track of the offers which are waiting at the marketplace.
+pub struct FillSimulator<R> {
+    model: FillModel,
+    rng: R,
+    // Offers which have not been filled yet and when they were placed.
+    pending: Vec<(Offer, DateTime<Utc>)>,
+}
+
+impl Default for FillModel {
+    fn default() -> Self {
+        Self {
+            slippage: Percentage::new(1, 1),
+            queue_fill_probability: 0.5,
+            expiry: Duration::days(1),
+        }
+    }
+}
+
+impl<R: Rng> FillSimulator<R> {
+    pub fn new(model: FillModel, rng: R) -> Self {
+        Self {
+            model,
+            rng,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Puts a new offer to the marketplace at given time. The offer is only
+    /// matched against candles which start after this time, because the
+    /// candle we made the decision on has already happened.
+    pub fn place(&mut self, offer: Offer, placed_at: DateTime<Utc>) {
+        self.pending.push((offer, placed_at));
+    }
+
+    /// How many offers are waiting to be filled.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Matches all pending offers against given candle and returns those
+    /// which have been either filled or expired.
+    pub fn step(&mut self, candle: &Candle) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        let mut still_pending = Vec::with_capacity(self.pending.len());
+
+        for (offer, placed_at) in self.pending.drain(..) {
+            if candle.time <= placed_at {
+                still_pending.push((offer, placed_at));
+            } else if candle.time - placed_at > self.model.expiry {
+                fills.push(Fill::Expired(offer));
+            } else if candle.high >= markup(offer.rate, self.model.slippage)
+                && self.rng.gen_bool(self.model.queue_fill_probability)
+            {
+                let rate = offer.rate;
+                fills.push(Fill::Filled { offer, rate });
+            } else {
+                still_pending.push((offer, placed_at));
+            }
+        }
+
+        self.pending = still_pending;
+        fills
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {chrono::TimeZone, rand::rngs::mock::StepRng};
+
+    use super::*;
+
+    #[test]
+    fn should_fill_at_limit_only_when_high_trades_through() {
+        let model = FillModel {
+            slippage: Percentage::new(1, 0),
+            queue_fill_probability: 1.0,
+            expiry: Duration::hours(2),
+        };
+        let mut simulator = FillSimulator::new(model, StepRng::new(0, 0));
+        let at = |hour| Utc.with_ymd_and_hms(2020, 1, 1, hour, 0, 0).unwrap();
+        let candle = |hour, high| Candle {
+            time: at(hour),
+            open: Decimal::new(90, 0),
+            high: Decimal::new(high, 0),
+            low: Decimal::new(80, 0),
+            close: Decimal::new(90, 0),
+            volume: Decimal::new(1, 0),
+        };
+
+        simulator.place(Offer::new(Decimal::new(100, 0), vec![]), at(0));
+        simulator.place(Offer::new(Decimal::new(200, 0), vec![]), at(0));
+
+        // The candle the offers were placed in is never considered.
+        assert!(simulator.step(&candle(0, 300)).is_empty());
+        // Reaching the limit isn't enough, the market has to trade through
+        // it by the slippage.
+        assert!(simulator.step(&candle(1, 100)).is_empty());
+
+        match simulator.step(&candle(2, 101)).as_slice() {
+            [Fill::Filled { offer, rate }] => {
+                assert_eq!(Decimal::new(100, 0), offer.rate);
+                assert_eq!(Decimal::new(100, 0), *rate);
+            }
+            fills => panic!("Expected one fill, got {:?}", fills),
+        }
+
+        assert!(matches!(
+            simulator.step(&candle(3, 300)).as_slice(),
+            [Fill::Expired(_)]
+        ));
+        assert_eq!(0, simulator.pending());
+    }
+
+    #[test]
+    fn should_respect_queue_position() {
+        let model = FillModel {
+            queue_fill_probability: 0.0,
+            ..FillModel::default()
+        };
+        let mut simulator = FillSimulator::new(model, StepRng::new(0, 0));
+        let placed_at = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        simulator.place(Offer::new(Decimal::new(100, 0), vec![]), placed_at);
+
+        let candle = Candle::from_tick(
+            placed_at + Duration::minutes(5),
+            Decimal::new(150, 0),
+            Decimal::new(1, 0),
+        );
+        assert!(simulator.step(&candle).is_empty());
+        assert_eq!(1, simulator.pending());
+    }
+}
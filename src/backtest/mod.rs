@@ -0,0 +1,511 @@
+//! Replays historical candles against the seller to evaluate the strategy.
+//! The buyer bids on the dips of the trend as the
+//! [`DipPolicy`](crate::buyer::DipPolicy) decides, the offers the seller
+//! makes are filled according to a [`FillModel`].
+
+pub mod benchmark;
+pub mod fill;
+pub mod monte_carlo;
+pub mod report;
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    rand::{rngs::StdRng, Rng, SeedableRng},
+    std::{collections::BTreeMap, sync::Arc},
+    uuid::Uuid,
+};
+
+use crate::{
+    breaker::CircuitBreaker,
+    buyer::{self, DipPolicy},
+    clock::SimulatedClock,
+    history::Candle,
+    models::{Fee, Liquidation, Metadata, Purchase, Side},
+    prelude::*,
+    seller::{self, HoldingPeriod, Seller},
+    volatility,
+    wallet::{SharedWallet, Wallet},
+};
+
+pub use {
+    benchmark::{Baseline, Strategy},
+    fill::{Fill, FillModel, FillSimulator},
+};
+
+/// Parameters of a backtest run.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// The selling fee of the marketplace.
+    pub fee: Fee,
+    /// The minimum margin the seller is configured with.
+    pub min_margin: Percentage,
+    /// How long does the seller hold purchases at least.
+    pub holding_period: HoldingPeriod,
+    /// The retrace from the peak after which the seller sells in the
+    /// trailing mode, if enabled.
+    pub trailing: Option<Percentage>,
+    /// When does the seller give up on purchases.
+    pub liquidation: Liquidation,
+    /// Scales the min margin by the volatility of the trend, if set. The
+    /// outcome is then compared to the fixed min margin.
+    pub volatility: Option<volatility::Config>,
+    /// How much cash do we start with.
+    pub investment: Cash,
+    /// When does the buyer buy and for how much.
+    pub buyer: buyer::Config,
+    /// How are the seller's offers filled.
+    pub fill: FillModel,
+    /// How often does the dollar-cost averaging baseline buy.
+    pub dca_interval: Duration,
+}
+
+/// What did we end up with after the replay.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// How many bitcoins do we hold at the end, including those in offers
+    /// which haven't been filled yet.
+    pub btc: Btc,
+    /// How much cash do we have at the end.
+    pub cash: Cash,
+    /// How much money have we made each month, keyed by `YYYY-MM`. Every
+    /// month in the replayed data is present, even if we made nothing.
+    pub monthly_margin: BTreeMap<String, Cash>,
+    /// Value of our cash and bitcoins at the close of each candle.
+    pub equity: Vec<(DateTime<Utc>, Cash)>,
+    /// Every buy and sell in the order they happened.
+    pub trades: Vec<Trade>,
+    /// Purchases which were bought and sold again during the replay.
+    pub lots: Vec<RoundTrip>,
+    /// How did naive strategies do over the same candles and capital.
+    pub baselines: Vec<Baseline>,
+}
+
+/// A single buy of a purchase or a sell of a purchase.
+#[derive(Debug, Clone, Copy)]
+pub struct Trade {
+    pub time: DateTime<Utc>,
+    pub side: Side,
+    pub btc: Btc,
+    pub rate: BtcExchangeRate,
+}
+
+/// A purchase which was bought and later sold.
+#[derive(Debug, Clone, Copy)]
+pub struct RoundTrip {
+    pub purchase_id: Uuid,
+    pub btc: Btc,
+    pub bought_at: DateTime<Utc>,
+    /// The rate at which we bought, including the buying fee.
+    pub buy_rate: BtcExchangeRate,
+    pub sold_at: DateTime<Utc>,
+    /// The rate at which the offer was filled.
+    pub sell_rate: BtcExchangeRate,
+    /// Net profit after the selling fee.
+    pub margin: Cash,
+}
+
+impl Outcome {
+    /// Value of our cash and bitcoins at the close of the last candle.
+    pub fn final_equity(&self) -> Cash {
+        self.equity
+            .last()
+            .map(|(_, equity)| *equity)
+            .unwrap_or(self.cash)
+    }
+
+    /// The largest drop of equity from its running peak, in percent of the
+    /// peak.
+    pub fn max_drawdown(&self) -> Percentage {
+        let mut peak = Cash::new(0, 0);
+        let mut max_drawdown = Percentage::new(0, 0);
+        for (_, equity) in &self.equity {
+            peak = peak.max(*equity);
+            if peak > Cash::new(0, 0) {
+                let drawdown = (peak - equity) / peak * Decimal::new(100, 0);
+                max_drawdown = max_drawdown.max(drawdown);
+            }
+        }
+
+        max_drawdown
+    }
+}
+
+/// Replays given time sorted candles and returns how we did.
+///
+/// * The buyer is given the candle's close as the trend. Its bids are filled
+///   straight away at their rate, which is the close.
+/// * We don't buy if we don't have resources, which is enforced by the wallet
+///   shared with the seller.
+/// * The seller is given the candle's close as the current trend. The
+///   seller's clock is set to the time of the candle. The seller's circuit
+///   breaker uses the default thresholds.
+/// * The outcome is compared to buying and holding, dollar-cost averaging
+///   and holding cash. When the margin is adjusted to volatility, it's also
+///   compared to the fixed margin, replayed with the same purchases.
+/// * Offers are placed at the end of the candle they were made in, and are
+///   matched against the following candles. Expired offers return their
+///   purchases to the seller.
+pub fn run(
+    candles: &[Candle],
+    config: &Config,
+    rng: &mut impl Rng,
+) -> Result<Outcome> {
+    let (mut outcome, fixed) = if config.volatility.is_some() {
+        // Both replays roll the same dice, so that their offers are filled
+        // alike.
+        let seed = rng.gen();
+        let outcome =
+            replay(candles, config, &mut StdRng::seed_from_u64(seed))?;
+        let fixed = Config {
+            volatility: None,
+            ..*config
+        };
+        let fixed = replay(candles, &fixed, &mut StdRng::seed_from_u64(seed))?;
+        (outcome, Some(fixed))
+    } else {
+        (replay(candles, config, rng)?, None)
+    };
+
+    outcome.baselines = benchmark::compare(
+        candles,
+        config.investment,
+        config.dca_interval,
+        outcome.final_equity(),
+    );
+    if let Some(fixed) = fixed {
+        let broker_return =
+            benchmark::return_pct(config.investment, outcome.final_equity());
+        let return_pct =
+            benchmark::return_pct(config.investment, fixed.final_equity());
+        outcome.baselines.push(Baseline {
+            strategy: Strategy::FixedMargin,
+            equity: fixed.final_equity(),
+            return_pct,
+            alpha: broker_return - return_pct,
+        });
+    }
+
+    Ok(outcome)
+}
+
+// Replays the candles against the seller.
+fn replay(
+    candles: &[Candle],
+    config: &Config,
+    rng: &mut impl Rng,
+) -> Result<Outcome> {
+    let clock = match candles.first() {
+        Some(candle) => SimulatedClock::new(candle.time),
+        None => return Ok(Outcome::default()),
+    };
+    let wallet =
+        SharedWallet::new(Wallet::new(config.investment, Btc::new(0, 0)));
+    let seller_config = seller::Config {
+        fee: config.fee,
+        min_margin: config.min_margin,
+        holding_period: config.holding_period,
+        trailing: config.trailing,
+        liquidation: config.liquidation,
+        max_impact: None,
+        volatility: config.volatility,
+    };
+    let mut seller = Seller::new(
+        seller_config,
+        Arc::new(clock.clone()),
+        wallet.clone(),
+        CircuitBreaker::default(),
+        None,
+    );
+    let mut buyer = DipPolicy::new(config.buyer, wallet.clone());
+    let mut simulator =
+        FillSimulator::new(config.fill, StdRng::from_rng(&mut *rng)?);
+    let mut outcome = Outcome::default();
+
+    for candle in candles {
+        clock.set(candle.time);
+        let margin_this_month = outcome
+            .monthly_margin
+            .entry(candle.time.format("%Y-%m").to_string())
+            .or_default();
+
+        for fill in simulator.step(candle) {
+            match fill {
+                Fill::Filled { offer, rate } => {
+                    let mut received = Cash::new(0, 0);
+                    for purchase in &offer.purchases {
+                        let margin =
+                            purchase.margin_after_fee(rate, config.fee);
+                        received += purchase.buying_price() + margin;
+                        *margin_this_month += margin;
+
+                        outcome.trades.push(Trade {
+                            time: candle.time,
+                            side: Side::Sell,
+                            btc: purchase.btc,
+                            rate,
+                        });
+                        outcome.lots.push(RoundTrip {
+                            purchase_id: purchase.id,
+                            btc: purchase.btc,
+                            bought_at: purchase
+                                .metadata
+                                .bought_at
+                                .unwrap_or(candle.time),
+                            buy_rate: purchase.rate,
+                            sold_at: candle.time,
+                            sell_rate: rate,
+                            margin,
+                        });
+                    }
+                    wallet.lock().settle_sell(offer.id, offer.btc(), received);
+                }
+                Fill::Expired(offer) => {
+                    seller.handle(seller::Message::OfferExpired(offer))?;
+                }
+            }
+        }
+
+        // The buyer has already reserved the cash of its bid, which is
+        // filled straight away.
+        if let Some(bid) = buyer.handle(candle.close, candle.time) {
+            let btc = bid.cash / bid.rate;
+            wallet.lock().settle_buy(bid.id, bid.cash, btc);
+            buyer.settle(bid.id, bid.cash);
+            let metadata = Metadata {
+                bought_at: Some(candle.time),
+                ..Metadata::default()
+            };
+            let purchase = Purchase::with_metadata(btc, bid.rate, metadata);
+            seller.handle(seller::Message::NewPurchase(purchase))?;
+
+            outcome.trades.push(Trade {
+                time: candle.time,
+                side: Side::Buy,
+                btc,
+                rate: bid.rate,
+            });
+        }
+
+        let reading = seller::Message::TrendReading {
+            current_trend: candle.close,
+            observed_at: candle.time,
+        };
+        if let Some(offer) = seller.handle(reading)? {
+            simulator.place(offer, candle.time);
+        }
+
+        let wallet = wallet.lock();
+        let equity = wallet.cash() + wallet.btc() * candle.close;
+        outcome.equity.push((candle.time, equity));
+    }
+
+    outcome.cash = wallet.lock().cash();
+    outcome.btc = wallet.lock().btc();
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use {
+        super::*,
+        crate::history::{self, Interval},
+    };
+
+    // Path to a CSV file which contains historical data of btc/$ exchange
+    // rates in the Yahoo format.
+    const HISTORICAL_DATA_PATH: &str =
+        "tests/data/btc_usd_2019_02_01-2020_08_19.csv";
+
+    // A naive test which runs the seller for a year while the buyer buys the
+    // dips of 5 % below the weekly high.
+    //
+    // * initial investment of $2k
+    // * We always buy BTC for $250, at most twice a week.
+    // * Offers which are not filled within 3 days expire.
+    #[test]
+    fn seller_should_yield_profit_from_historical_data() -> Result<()> {
+        let config = Config {
+            fee: Fee::Percentage(Percentage::new(25, 2)),
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            volatility: None,
+            investment: Cash::new(2_000, 0),
+            buyer: buyer::Config {
+                reference: buyer::Reference::RecentHigh,
+                window: Duration::weeks(1),
+                sampling: Duration::days(1),
+                dip: Percentage::new(5, 0),
+                cooldown: Duration::days(2),
+                spending: Cash::new(250, 0),
+                weekly_budget: Cash::new(500, 0),
+            },
+            fill: FillModel {
+                expiry: Duration::days(3),
+                ..FillModel::default()
+            },
+            dca_interval: Duration::weeks(1),
+        };
+
+        let candles =
+            history::load(HISTORICAL_DATA_PATH, history::Format::Yahoo)?;
+        let outcome = run(&candles, &config, &mut StdRng::seed_from_u64(1))?;
+
+        let total_monthly_margin: Cash =
+            outcome.monthly_margin.values().copied().sum();
+        assert!(outcome.cash >= Cash::new(0, 0));
+        assert!(total_monthly_margin >= Cash::new(0, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_replay_intraday_candles() -> Result<()> {
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(1, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            volatility: None,
+            investment: Cash::new(100, 0),
+            buyer: buyer::Config {
+                reference: buyer::Reference::RecentHigh,
+                window: Duration::minutes(15),
+                sampling: Duration::minutes(5),
+                dip: Percentage::new(3, 0),
+                cooldown: Duration::minutes(30),
+                spending: Cash::new(100, 0),
+                weekly_budget: Cash::new(1_000, 0),
+            },
+            fill: FillModel {
+                slippage: Percentage::new(0, 0),
+                queue_fill_probability: 1.0,
+                expiry: Duration::hours(1),
+            },
+            dca_interval: Duration::hours(1),
+        };
+
+        // Trades every minute with the price rising by $1 each time, except
+        // it drops by $10 every half an hour.
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let ticks: Vec<_> = (0..120)
+            .map(|minute| {
+                Candle::from_tick(
+                    start + Duration::minutes(minute),
+                    Decimal::new(100 + minute - minute / 30 * 10, 0),
+                    Decimal::new(1, 0),
+                )
+            })
+            .collect();
+        let candles = history::resample(&ticks, Interval::FiveMinutes);
+        assert_eq!(24, candles.len());
+
+        let outcome = run(&candles, &config, &mut StdRng::seed_from_u64(0))?;
+
+        // We buy the drops whenever we have the cash and the price goes up
+        // again, so every purchase is sold with a margin a few candles later.
+        let buys = outcome.trades.iter().filter(|t| t.side == Side::Buy);
+        assert!(buys.count() > 1);
+        let total_margin: Cash = outcome.monthly_margin.values().copied().sum();
+        assert!(total_margin > Cash::new(0, 0));
+        assert_eq!(Some(&total_margin), outcome.monthly_margin.get("2020-01"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_compare_volatility_adjusted_margin_with_fixed() -> Result<()> {
+        let adjustment = volatility::Config {
+            window: 30,
+            sampling: Duration::days(1),
+            reference: Percentage::new(3, 0),
+            floor: Percentage::new(10, 0),
+            ceiling: Percentage::new(10, 0),
+        };
+        let mut config = Config {
+            fee: Fee::Percentage(Percentage::new(25, 2)),
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            volatility: Some(adjustment),
+            investment: Cash::new(2_000, 0),
+            buyer: buyer::Config {
+                reference: buyer::Reference::RecentHigh,
+                window: Duration::weeks(1),
+                sampling: Duration::days(1),
+                dip: Percentage::new(5, 0),
+                cooldown: Duration::days(2),
+                spending: Cash::new(250, 0),
+                weekly_budget: Cash::new(500, 0),
+            },
+            fill: FillModel {
+                expiry: Duration::days(3),
+                ..FillModel::default()
+            },
+            dca_interval: Duration::weeks(1),
+        };
+        let candles =
+            history::load(HISTORICAL_DATA_PATH, history::Format::Yahoo)?;
+        let fixed_margin = |outcome: &Outcome| {
+            outcome
+                .baselines
+                .iter()
+                .find(|baseline| baseline.strategy == Strategy::FixedMargin)
+                .copied()
+        };
+
+        // The floor and ceiling pin the margin to the fixed one, therefore
+        // both replays end up the same.
+        let mut rng = StdRng::seed_from_u64(42);
+        let outcome = run(&candles, &config, &mut rng)?;
+        let baseline = fixed_margin(&outcome).expect("Fixed margin replayed");
+        assert_eq!(outcome.final_equity(), baseline.equity);
+        assert_eq!(Percentage::new(0, 0), baseline.alpha);
+
+        config.volatility = Some(volatility::Config {
+            floor: Percentage::new(3, 0),
+            ceiling: Percentage::new(20, 0),
+            ..adjustment
+        });
+        let outcome = run(&candles, &config, &mut rng)?;
+        let baseline = fixed_margin(&outcome).expect("Fixed margin replayed");
+        assert_ne!(outcome.final_equity(), baseline.equity);
+        // Both start with the same purchases, but the adjusted margin sells
+        // them at other times. Later purchases differ as the cash in the
+        // wallet does.
+        let sells = |outcome: &Outcome| {
+            let sells = outcome.lots.iter().map(|lot| (lot.sold_at, lot.btc));
+            sells.collect::<Vec<_>>()
+        };
+        let fixed = Config {
+            volatility: None,
+            ..config
+        };
+        let fixed = replay(&candles, &fixed, &mut StdRng::seed_from_u64(7))?;
+        let adjusted =
+            replay(&candles, &config, &mut StdRng::seed_from_u64(7))?;
+        let buys = |outcome: &Outcome| {
+            let buys = outcome.trades.iter().filter(|t| t.side == Side::Buy);
+            buys.map(|trade| trade.time).collect::<Vec<_>>()
+        };
+        assert_eq!(buys(&fixed)[..5], buys(&adjusted)[..5]);
+        assert_ne!(sells(&fixed), sells(&adjusted));
+        println!(
+            "Volatility adjusted margin's alpha over the fixed one: {}%",
+            baseline.alpha.round_dp(2)
+        );
+
+        // Without the adjustment, there's nothing to compare against.
+        config.volatility = None;
+        let outcome = run(&candles, &config, &mut rng)?;
+        assert_eq!(None, fixed_margin(&outcome).map(|b| b.strategy));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+//! A historical series is only a single sample path of the market. To find
+//! out how robust the strategy is, we generate many synthetic price paths and
+//! backtest the seller on each of them. The outcomes are summarized into a
+//! distribution of returns and drawdowns.
+//!
+//! There are two generators:
+//! * block bootstrap which stitches together random blocks of historical
+//!   candles, preserving short term autocorrelation and volatility clusters;
+//! * geometric brownian motion with Poisson distributed jumps (Merton's
+//!   jump diffusion), which models crashes and rallies the history might not
+//!   contain.
+
+use {
+    chrono::Duration,
+    rand::{rngs::StdRng, Rng, SeedableRng},
+    rand_distr::{Distribution, Normal, Poisson},
+    rust_decimal::prelude::{FromPrimitive, ToPrimitive},
+};
+
+use super::{run, Config};
+use crate::{history::Candle, prelude::*};
+
+/// Parameters of the jump diffusion process. Rates are annualized.
+#[derive(Debug, Clone, Copy)]
+pub struct JumpDiffusion {
+    /// The expected log return per year without the jumps.
+    pub drift: f64,
+    /// Standard deviation of log returns per year.
+    pub volatility: f64,
+    /// How many jumps to expect per year.
+    pub jump_intensity: f64,
+    /// Mean of the log size of a jump.
+    pub jump_mean: f64,
+    /// Standard deviation of the log size of a jump.
+    pub jump_volatility: f64,
+}
+
+/// How to generate the synthetic price paths.
+#[derive(Debug, Clone)]
+pub enum Generator {
+    /// Resamples blocks of given length from historical candles. The
+    /// generated paths have the same number of candles as the history.
+    BlockBootstrap {
+        history: Vec<Candle>,
+        block_len: usize,
+    },
+    /// Simulates the jump diffusion starting at the first candle.
+    JumpDiffusion {
+        params: JumpDiffusion,
+        start: Candle,
+        interval: Duration,
+        len: usize,
+    },
+}
+
+/// Distribution of the backtest outcomes across all paths.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub paths: usize,
+    /// Returns in percent of the investment, sorted ascending.
+    pub returns: Vec<Percentage>,
+    /// Max drawdowns in percent, sorted ascending.
+    pub drawdowns: Vec<Percentage>,
+    /// Share of paths between 0 and 1 which ended with less than invested.
+    pub probability_of_loss: f64,
+    /// Average return of the worst 5 % of paths.
+    pub expected_shortfall: Percentage,
+}
+
+impl JumpDiffusion {
+    /// Estimates the parameters from historical candles. Log returns which
+    /// are more than three standard deviations away from the mean are
+    /// considered jumps.
+    pub fn estimate(history: &[Candle], interval: Duration) -> Self {
+        let returns = log_returns(history);
+        let (mean, std) = mean_and_std(&returns);
+        let (jumps, diffusion): (Vec<f64>, Vec<f64>) =
+            returns.iter().partition(|r| (*r - mean).abs() > 3.0 * std);
+        let (diffusion_mean, diffusion_std) = mean_and_std(&diffusion);
+        let (jump_mean, jump_volatility) = mean_and_std(&jumps);
+
+        let per_year = per_year(interval);
+        Self {
+            drift: diffusion_mean * per_year,
+            volatility: diffusion_std * per_year.sqrt(),
+            jump_intensity: jumps.len() as f64 / returns.len().max(1) as f64
+                * per_year,
+            jump_mean,
+            jump_volatility,
+        }
+    }
+}
+
+impl Generator {
+    /// Generates one synthetic path of candles.
+    pub fn generate(&self, rng: &mut impl Rng) -> Vec<Candle> {
+        match self {
+            Self::BlockBootstrap { history, block_len } => {
+                block_bootstrap(history, *block_len, rng)
+            }
+            Self::JumpDiffusion {
+                params,
+                start,
+                interval,
+                len,
+            } => jump_diffusion(params, start, *interval, *len, rng),
+        }
+    }
+}
+
+impl Summary {
+    /// Return at given percentile between 0 and 100.
+    pub fn return_percentile(&self, percentile: f64) -> Percentage {
+        at_percentile(&self.returns, percentile)
+    }
+
+    /// Max drawdown at given percentile between 0 and 100. The 95th
+    /// percentile is the drawdown which only 5 % of the paths exceeded.
+    pub fn drawdown_percentile(&self, percentile: f64) -> Percentage {
+        at_percentile(&self.drawdowns, percentile)
+    }
+}
+
+/// Backtests the seller on given number of generated paths. The random
+/// generator is used both to generate the paths and to run the backtests.
+pub fn simulate(
+    generator: &Generator,
+    config: &Config,
+    paths: usize,
+    rng: &mut impl Rng,
+) -> Result<Summary> {
+    let mut returns = Vec::with_capacity(paths);
+    let mut drawdowns = Vec::with_capacity(paths);
+
+    for _ in 0..paths {
+        let mut path_rng = StdRng::from_rng(&mut *rng)?;
+        let candles = generator.generate(&mut path_rng);
+        let outcome = run(&candles, config, &mut path_rng)?;
+
+        returns.push(super::benchmark::return_pct(
+            config.investment,
+            outcome.final_equity(),
+        ));
+        drawdowns.push(outcome.max_drawdown());
+    }
+
+    returns.sort();
+    drawdowns.sort();
+
+    let losses = returns.iter().filter(|r| r.is_sign_negative()).count();
+    let tail_len = (paths / 20).max(1).min(paths);
+    let expected_shortfall = if paths == 0 {
+        Percentage::new(0, 0)
+    } else {
+        returns[..tail_len].iter().copied().sum::<Decimal>()
+            / Decimal::from(tail_len)
+    };
+
+    Ok(Summary {
+        paths,
+        probability_of_loss: losses as f64 / paths.max(1) as f64,
+        expected_shortfall,
+        returns,
+        drawdowns,
+    })
+}
+
+/// Stitches together randomly picked blocks of consecutive candles. Each
+/// candle is expressed relative to the previous close, so that the blocks
+/// connect without gaps. The times of the history are kept.
+pub fn block_bootstrap(
+    history: &[Candle],
+    block_len: usize,
+    rng: &mut impl Rng,
+) -> Vec<Candle> {
+    if history.len() < 2 {
+        return history.to_vec();
+    }
+
+    // Ratios of each candle's prices to the close of the previous candle.
+    // Candles after a zero close, e.g. a gap in the data, are skipped.
+    let moves: Vec<_> = history
+        .windows(2)
+        .filter(|w| w[0].close > Decimal::new(0, 0))
+        .map(|w| {
+            let prev = w[0].close;
+            let ratios = [
+                w[1].open / prev,
+                w[1].high / prev,
+                w[1].low / prev,
+                w[1].close / prev,
+            ];
+            (ratios, w[1].volume)
+        })
+        .collect();
+    if moves.is_empty() {
+        return history.to_vec();
+    }
+    let block_len = block_len.max(1).min(moves.len());
+
+    let mut path = Vec::with_capacity(history.len());
+    path.push(history[0]);
+    while path.len() < history.len() {
+        let start = rng.gen_range(0, moves.len() - block_len + 1);
+        for ([open, high, low, close], volume) in
+            &moves[start..start + block_len]
+        {
+            let time = match history.get(path.len()) {
+                Some(candle) => candle.time,
+                None => break,
+            };
+            let prev = path[path.len() - 1].close;
+            path.push(Candle {
+                time,
+                open: (prev * open).round_dp(2),
+                high: (prev * high).round_dp(2),
+                low: (prev * low).round_dp(2),
+                close: (prev * close).round_dp(2),
+                volume: *volume,
+            });
+        }
+    }
+
+    path
+}
+
+/// Simulates the closes with the jump diffusion process. The high and low of
+/// each candle are drawn from the candle's own volatility around its open and
+/// close.
+pub fn jump_diffusion(
+    params: &JumpDiffusion,
+    start: &Candle,
+    interval: Duration,
+    len: usize,
+    rng: &mut impl Rng,
+) -> Vec<Candle> {
+    let dt = 1.0 / per_year(interval);
+    let step_volatility = params.volatility * dt.sqrt();
+    // The drift is already the mean of the log returns, which accounts for
+    // the volatility drag.
+    let drift = params.drift * dt;
+    // The distributions can only fail to be created with invalid parameters,
+    // in which case we fall back to no randomness.
+    let diffusion = Normal::new(0.0, step_volatility)
+        .unwrap_or_else(|_| Normal::new(0.0, 0.0).unwrap());
+    let jump = Normal::new(params.jump_mean, params.jump_volatility)
+        .unwrap_or_else(|_| Normal::new(0.0, 0.0).unwrap());
+    let jumps = Poisson::new(params.jump_intensity * dt).ok();
+
+    let mut path = Vec::with_capacity(len);
+    path.push(*start);
+    let mut close = to_f64(start.close);
+    for i in 1..len {
+        let jump_count: u64 = jumps.map(|j| j.sample(rng)).unwrap_or(0);
+        let jump_size: f64 = (0..jump_count).map(|_| jump.sample(rng)).sum();
+        let open = close;
+        close = open * (drift + diffusion.sample(rng) + jump_size).exp();
+
+        let wick_up = diffusion.sample(rng).abs() / 2.0;
+        let wick_down = diffusion.sample(rng).abs() / 2.0;
+        path.push(Candle {
+            time: start.time + interval * i as i32,
+            open: to_decimal(open),
+            high: to_decimal(open.max(close) * wick_up.exp()),
+            low: to_decimal(open.min(close) * (-wick_down).exp()),
+            close: to_decimal(close),
+            volume: start.volume,
+        });
+    }
+
+    path
+}
+
+// How many candles of a given interval there are in a year.
+fn per_year(interval: Duration) -> f64 {
+    Duration::days(365).num_seconds() as f64 / interval.num_seconds() as f64
+}
+
+fn log_returns(candles: &[Candle]) -> Vec<f64> {
+    candles
+        .windows(2)
+        .map(|w| (to_f64(w[1].close) / to_f64(w[0].close)).ln())
+        .filter(|r| r.is_finite())
+        .collect()
+}
+
+fn mean_and_std(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
+
+fn at_percentile(sorted: &[Percentage], percentile: f64) -> Percentage {
+    if sorted.is_empty() {
+        return Percentage::new(0, 0);
+    }
+
+    let index = (percentile / 100.0 * (sorted.len() - 1) as f64).round();
+    sorted[(index as usize).min(sorted.len() - 1)]
+}
+
+fn to_f64(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
+}
+
+fn to_decimal(value: f64) -> Decimal {
+    Decimal::from_f64(value).unwrap_or_default().round_dp(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use {chrono::TimeZone, chrono::Utc};
+
+    use super::*;
+    use crate::{
+        backtest::FillModel,
+        buyer, history,
+        models::{Fee, Liquidation},
+        seller::HoldingPeriod,
+    };
+
+    fn config() -> Config {
+        Config {
+            fee: Fee::Percentage(Percentage::new(25, 2)),
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            volatility: None,
+            investment: Cash::new(2_000, 0),
+            buyer: buyer::Config {
+                reference: buyer::Reference::RecentHigh,
+                window: Duration::weeks(1),
+                sampling: Duration::days(1),
+                dip: Percentage::new(5, 0),
+                cooldown: Duration::days(2),
+                spending: Cash::new(250, 0),
+                weekly_budget: Cash::new(500, 0),
+            },
+            fill: FillModel::default(),
+            dca_interval: Duration::weeks(1),
+        }
+    }
+
+    #[test]
+    fn should_bootstrap_paths_of_the_same_shape() -> Result<()> {
+        let history = history::load(
+            "tests/data/btc_usd_2019_02_01-2020_08_19.csv",
+            history::Format::Yahoo,
+        )?;
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let path = block_bootstrap(&history, 10, &mut rng);
+
+        assert_eq!(history.len(), path.len());
+        assert_eq!(history[0], path[0]);
+        for (synthetic, historical) in path.iter().zip(&history) {
+            assert_eq!(historical.time, synthetic.time);
+            assert!(synthetic.low <= synthetic.high);
+        }
+        assert_ne!(history, path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_summarize_outcomes_of_jump_diffusion_paths() -> Result<()> {
+        let start = Candle::from_tick(
+            Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+            Decimal::new(10_000, 0),
+            Decimal::new(1, 0),
+        );
+        let generator = Generator::JumpDiffusion {
+            params: JumpDiffusion {
+                drift: 0.0,
+                volatility: 0.8,
+                jump_intensity: 4.0,
+                jump_mean: -0.1,
+                jump_volatility: 0.2,
+            },
+            start,
+            interval: Duration::days(1),
+            len: 120,
+        };
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let path = generator.generate(&mut rng);
+        assert_eq!(120, path.len());
+        assert!(path.iter().all(|c| c.low <= c.open && c.open <= c.high));
+        assert!(path.iter().all(|c| c.low <= c.close && c.close <= c.high));
+
+        let summary = simulate(&generator, &config(), 20, &mut rng)?;
+        assert_eq!(20, summary.returns.len());
+        assert!(
+            summary.return_percentile(5.0) <= summary.return_percentile(95.0)
+        );
+        assert!(summary.drawdown_percentile(95.0) >= Percentage::new(0, 0));
+        assert!((0.0..=1.0).contains(&summary.probability_of_loss));
+        assert!(summary.expected_shortfall <= summary.return_percentile(50.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_generate_log_returns_with_the_estimated_drift() {
+        let start = Candle::from_tick(
+            Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+            Decimal::new(100_000_000, 0),
+            Decimal::new(1, 0),
+        );
+        let params = JumpDiffusion {
+            drift: 0.0,
+            volatility: 0.5,
+            jump_intensity: 0.0,
+            jump_mean: 0.0,
+            jump_volatility: 0.0,
+        };
+        let interval = Duration::weeks(1);
+
+        // Four centuries of weekly candles. Subtracting the volatility drag
+        // from the drift again would bias the mean by 0.125.
+        let mut rng = StdRng::seed_from_u64(11);
+        let path = jump_diffusion(&params, &start, interval, 20_800, &mut rng);
+        let (mean, _) = mean_and_std(&log_returns(&path));
+        assert!((mean * per_year(interval) - params.drift).abs() < 0.05);
+
+        let estimated = JumpDiffusion::estimate(&path, interval);
+        assert!((estimated.drift - params.drift).abs() < 0.05);
+    }
+
+    #[test]
+    fn should_skip_candles_after_zero_close() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let history: Vec<_> = [100, 0, 100, 110]
+            .iter()
+            .enumerate()
+            .map(|(day, price)| {
+                Candle::from_tick(
+                    start + Duration::days(day as i64),
+                    Decimal::new(*price, 0),
+                    Decimal::new(1, 0),
+                )
+            })
+            .collect();
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let path = block_bootstrap(&history, 1, &mut rng);
+
+        assert_eq!(history.len(), path.len());
+    }
+
+    #[test]
+    fn should_estimate_jump_diffusion_from_history() -> Result<()> {
+        let history = history::load(
+            "tests/data/btc_usd_2019_02_01-2020_08_19.csv",
+            history::Format::Yahoo,
+        )?;
+
+        let params = JumpDiffusion::estimate(&history, Duration::days(1));
+
+        // Bitcoin is famously volatile and crashed in March 2020.
+        assert!(params.volatility > 0.3);
+        assert!(params.jump_intensity > 0.0);
+        assert!(params.jump_mean.abs() > 0.0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+//! Renders the outcome of a backtest into a self-contained HTML page. The
+//! charts are inline SVG, so the report can be opened offline without any
+//! external scripts or stylesheets.
+
+use {
+    chrono::{DateTime, TimeZone, Utc},
+    rust_decimal::prelude::ToPrimitive,
+    std::{fmt::Write as _, fs, path::Path},
+};
+
+use super::Outcome;
+use crate::{history::Candle, models::Side, prelude::*};
+
+const WIDTH: f64 = 960.0;
+const HEIGHT: f64 = 240.0;
+// Space around the plot area for axis labels.
+const PADDING: f64 = 48.0;
+
+const GREEN: &str = "#2e7d32";
+const RED: &str = "#c62828";
+const BLUE: &str = "#1565c0";
+
+/// Maps time and value onto the pixels of a chart.
+struct Scale {
+    from: i64,
+    to: i64,
+    min: f64,
+    max: f64,
+}
+
+/// Renders the report and writes it into a file at given path.
+pub fn write(
+    path: impl AsRef<Path>,
+    title: &str,
+    candles: &[Candle],
+    outcome: &Outcome,
+) -> Result<()> {
+    fs::write(path, render(title, candles, outcome))?;
+    Ok(())
+}
+
+/// Renders the report with the price series and our trades, the equity curve,
+/// drawdown, monthly P&L and a table of all purchases which were sold.
+pub fn render(title: &str, candles: &[Candle], outcome: &Outcome) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>{title}</title>\n<style>\n\
+        body {{ font-family: sans-serif; margin: 2em; color: #212121; }}\n\
+        table {{ border-collapse: collapse; }}\n\
+        td, th {{ padding: 2px 8px; text-align: right; }}\n\
+        tr:nth-child(even) {{ background: #f5f5f5; }}\n\
+        svg {{ display: block; margin-bottom: 2em; }}\n\
+        </style>\n</head>\n<body>\n<h1>{title}</h1>\n",
+        title = escape(title),
+    );
+
+    let _ = write!(html, "{}", summary(outcome));
+    html.push_str(&baselines_table(outcome));
+
+    html.push_str("<h2>Price and trades</h2>\n");
+    html.push_str(&price_chart(candles, outcome));
+    html.push_str("<h2>Equity</h2>\n");
+    html.push_str(&line_chart(&to_points(&outcome.equity), BLUE, false));
+    html.push_str("<h2>Drawdown (%)</h2>\n");
+    html.push_str(&line_chart(&drawdown(&outcome.equity), RED, true));
+    html.push_str("<h2>Monthly P&amp;L</h2>\n");
+    html.push_str(&monthly_chart(outcome));
+    html.push_str("<h2>Round-trip lots</h2>\n");
+    html.push_str(&lots_table(outcome));
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn summary(outcome: &Outcome) -> String {
+    let start = outcome.equity.first().map(|(_, e)| *e).unwrap_or_default();
+    let end = outcome.equity.last().map(|(_, e)| *e).unwrap_or_default();
+    let realized: Cash = outcome.lots.iter().map(|lot| lot.margin).sum();
+
+    format!(
+        "<p>Equity ${} &rarr; ${}, realized margin ${} over {} lots, \
+        max drawdown {} %. Ended up with {} BTC and ${} cash.</p>\n",
+        start.round_dp(2),
+        end.round_dp(2),
+        realized.round_dp(2),
+        outcome.lots.len(),
+        outcome.max_drawdown().round_dp(2),
+        outcome.btc.round_dp(6),
+        outcome.cash.round_dp(2),
+    )
+}
+
+fn baselines_table(outcome: &Outcome) -> String {
+    let mut table = String::from(
+        "<table>\n<tr><th>Baseline</th><th>Equity</th><th>Return</th>\
+        <th>Broker's alpha</th></tr>\n",
+    );
+    for baseline in &outcome.baselines {
+        let _ = writeln!(
+            table,
+            "<tr><td>{:?}</td><td>${}</td><td>{} %</td><td>{} %</td></tr>",
+            baseline.strategy,
+            baseline.equity.round_dp(2),
+            baseline.return_pct.round_dp(2),
+            baseline.alpha.round_dp(2),
+        );
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+fn price_chart(candles: &[Candle], outcome: &Outcome) -> String {
+    let points: Vec<_> = candles
+        .iter()
+        .map(|c| (c.time.timestamp(), to_f64(c.close)))
+        .collect();
+    let scale = match Scale::fit(&points) {
+        Some(scale) => scale,
+        None => return empty_chart(),
+    };
+
+    let mut svg = open_svg();
+    svg.push_str(&scale.axes());
+    svg.push_str(&scale.polyline(&points, BLUE));
+    for trade in &outcome.trades {
+        let x = scale.x(trade.time.timestamp());
+        let y = scale.y(to_f64(trade.rate));
+        // Buys point up from below, sells point down from above.
+        let (color, dy) = match trade.side {
+            Side::Buy => (GREEN, 9.0),
+            Side::Sell => (RED, -9.0),
+        };
+        let points = format!(
+            "{},{} {},{} {},{}",
+            x,
+            y,
+            x - 5.0,
+            y + dy,
+            x + 5.0,
+            y + dy
+        );
+        let _ = writeln!(
+            svg,
+            "<polygon points=\"{}\" fill=\"{}\"><title>{:?} {} BTC at ${} \
+            on {}</title></polygon>",
+            points,
+            color,
+            trade.side,
+            trade.btc.round_dp(6),
+            trade.rate.round_dp(2),
+            format_date(trade.time),
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn line_chart(points: &[(i64, f64)], color: &str, fill: bool) -> String {
+    let scale = match Scale::fit(points) {
+        Some(scale) => scale,
+        None => return empty_chart(),
+    };
+
+    let mut svg = open_svg();
+    svg.push_str(&scale.axes());
+    if fill {
+        let baseline = scale.y(scale.min);
+        let mut area = format!("{},{}", scale.x(scale.from), baseline);
+        for (time, value) in points {
+            let _ = write!(area, " {},{}", scale.x(*time), scale.y(*value));
+        }
+        let _ = write!(area, " {},{}", scale.x(scale.to), baseline);
+        let _ = writeln!(
+            svg,
+            "<polygon points=\"{}\" fill=\"{}\" fill-opacity=\"0.3\"/>",
+            area, color
+        );
+    }
+    svg.push_str(&scale.polyline(points, color));
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn monthly_chart(outcome: &Outcome) -> String {
+    if outcome.monthly_margin.is_empty() {
+        return empty_chart();
+    }
+
+    let values: Vec<_> = outcome
+        .monthly_margin
+        .values()
+        .map(|margin| to_f64(*margin))
+        .collect();
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    let min = values.iter().cloned().fold(0.0, f64::min);
+    let range = if max - min > 0.0 { max - min } else { 1.0 };
+    let plot_height = HEIGHT - 2.0 * PADDING;
+    let zero = PADDING + plot_height * max / range;
+    let slot = (WIDTH - 2.0 * PADDING) / values.len() as f64;
+
+    let mut svg = open_svg();
+    let _ = writeln!(
+        svg,
+        "<line x1=\"{}\" y1=\"{z}\" x2=\"{}\" y2=\"{z}\" stroke=\"#9e9e9e\"/>",
+        PADDING,
+        WIDTH - PADDING,
+        z = zero,
+    );
+    for (i, (month, margin)) in outcome.monthly_margin.iter().enumerate() {
+        let value = values[i];
+        let height = plot_height * value.abs() / range;
+        let x = PADDING + slot * i as f64;
+        let y = if value >= 0.0 { zero - height } else { zero };
+        let color = if value >= 0.0 { GREEN } else { RED };
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" \
+            fill=\"{}\"><title>{}: ${}</title></rect>",
+            x + slot * 0.1,
+            y,
+            slot * 0.8,
+            height,
+            color,
+            month,
+            margin.round_dp(2),
+        );
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{}\" font-size=\"9\" text-anchor=\"end\" \
+            transform=\"rotate(-60 {:.1} {})\">{}</text>",
+            x + slot / 2.0,
+            HEIGHT - PADDING + 12.0,
+            x + slot / 2.0,
+            HEIGHT - PADDING + 12.0,
+            month,
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn lots_table(outcome: &Outcome) -> String {
+    let mut table = String::from(
+        "<table>\n<tr><th>Bought</th><th>Sold</th><th>Days held</th>\
+        <th>BTC</th><th>Buy rate</th><th>Sell rate</th><th>Margin</th></tr>\n",
+    );
+    for lot in &outcome.lots {
+        let color = if lot.margin >= Cash::new(0, 0) {
+            GREEN
+        } else {
+            RED
+        };
+        let _ = writeln!(
+            table,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>${}</td>\
+            <td>${}</td><td style=\"color: {}\">${}</td></tr>",
+            format_date(lot.bought_at),
+            format_date(lot.sold_at),
+            (lot.sold_at - lot.bought_at).num_days(),
+            lot.btc.round_dp(6),
+            lot.buy_rate.round_dp(2),
+            lot.sell_rate.round_dp(2),
+            color,
+            lot.margin.round_dp(2),
+        );
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+// How many percent is the equity below its running peak.
+fn drawdown(equity: &[(DateTime<Utc>, Cash)]) -> Vec<(i64, f64)> {
+    let mut peak = f64::MIN;
+    equity
+        .iter()
+        .map(|(time, value)| {
+            let value = to_f64(*value);
+            peak = peak.max(value);
+            let drawdown = if peak > 0.0 {
+                (peak - value) / peak * 100.0
+            } else {
+                0.0
+            };
+            (time.timestamp(), drawdown)
+        })
+        .collect()
+}
+
+impl Scale {
+    // Returns none if there are no points to plot.
+    fn fit(points: &[(i64, f64)]) -> Option<Self> {
+        let (first, last) = (points.first()?, points.last()?);
+        let min = points.iter().map(|(_, v)| *v).fold(f64::MAX, f64::min);
+        let max = points.iter().map(|(_, v)| *v).fold(f64::MIN, f64::max);
+        Some(Self {
+            from: first.0,
+            to: last.0,
+            min,
+            max,
+        })
+    }
+
+    fn x(&self, time: i64) -> f64 {
+        let span = (self.to - self.from).max(1) as f64;
+        let x = PADDING
+            + (time - self.from) as f64 / span * (WIDTH - 2.0 * PADDING);
+        (x * 10.0).round() / 10.0
+    }
+
+    fn y(&self, value: f64) -> f64 {
+        let span = if self.max > self.min {
+            self.max - self.min
+        } else {
+            1.0
+        };
+        let y = HEIGHT
+            - PADDING
+            - (value - self.min) / span * (HEIGHT - 2.0 * PADDING);
+        (y * 10.0).round() / 10.0
+    }
+
+    fn polyline(&self, points: &[(i64, f64)], color: &str) -> String {
+        let mut line = String::new();
+        for (time, value) in points {
+            let _ = write!(line, "{},{} ", self.x(*time), self.y(*value));
+        }
+        format!(
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" \
+            stroke-width=\"1.5\"/>\n",
+            line.trim_end(),
+            color
+        )
+    }
+
+    // Draws the frame of the plot with the min and max values and the first
+    // and last date.
+    fn axes(&self) -> String {
+        let to_date = |ts| {
+            Utc.timestamp_opt(ts, 0)
+                .single()
+                .map(format_date)
+                .unwrap_or_default()
+        };
+        format!(
+            "<rect x=\"{p}\" y=\"{p}\" width=\"{w}\" height=\"{h}\" \
+            fill=\"none\" stroke=\"#e0e0e0\"/>\n\
+            <text x=\"{l}\" y=\"{top}\" font-size=\"10\" \
+            text-anchor=\"end\">{max:.2}</text>\n\
+            <text x=\"{l}\" y=\"{bottom}\" font-size=\"10\" \
+            text-anchor=\"end\">{min:.2}</text>\n\
+            <text x=\"{p}\" y=\"{below}\" font-size=\"10\">{from}</text>\n\
+            <text x=\"{right}\" y=\"{below}\" font-size=\"10\" \
+            text-anchor=\"end\">{to}</text>\n",
+            p = PADDING,
+            w = WIDTH - 2.0 * PADDING,
+            h = HEIGHT - 2.0 * PADDING,
+            l = PADDING - 4.0,
+            top = PADDING + 4.0,
+            bottom = HEIGHT - PADDING,
+            below = HEIGHT - PADDING + 14.0,
+            right = WIDTH - PADDING,
+            max = self.max,
+            min = self.min,
+            from = to_date(self.from),
+            to = to_date(self.to),
+        )
+    }
+}
+
+fn open_svg() -> String {
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" \
+        height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n",
+        w = WIDTH,
+        h = HEIGHT,
+    )
+}
+
+fn empty_chart() -> String {
+    String::from("<p>No data.</p>\n")
+}
+
+fn to_points(series: &[(DateTime<Utc>, Cash)]) -> Vec<(i64, f64)> {
+    series
+        .iter()
+        .map(|(time, value)| (time.timestamp(), to_f64(*value)))
+        .collect()
+}
+
+fn to_f64(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
+}
+
+fn format_date(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::mock::StepRng;
+
+    use super::*;
+    use crate::{
+        backtest::{run, Config, FillModel},
+        buyer,
+        models::{Fee, Liquidation},
+        seller::HoldingPeriod,
+    };
+
+    #[test]
+    fn should_render_charts_and_lots_of_a_backtest() -> Result<()> {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let prices = [100, 110, 120, 90, 80, 130, 140];
+        let candles: Vec<_> = prices
+            .iter()
+            .enumerate()
+            .map(|(day, price)| {
+                Candle::from_tick(
+                    start + chrono::Duration::days(day as i64 * 20),
+                    Decimal::new(*price, 0),
+                    Decimal::new(1, 0),
+                )
+            })
+            .collect();
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(5, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            volatility: None,
+            investment: Cash::new(100, 0),
+            buyer: buyer::Config {
+                reference: buyer::Reference::RecentHigh,
+                window: chrono::Duration::days(40),
+                sampling: chrono::Duration::days(1),
+                dip: Percentage::new(10, 0),
+                cooldown: chrono::Duration::days(1),
+                spending: Cash::new(100, 0),
+                weekly_budget: Cash::new(100, 0),
+            },
+            fill: FillModel {
+                slippage: Percentage::new(0, 0),
+                queue_fill_probability: 1.0,
+                expiry: chrono::Duration::days(30),
+            },
+            dca_interval: chrono::Duration::days(30),
+        };
+        let outcome = run(&candles, &config, &mut StepRng::new(0, 0))?;
+        assert!(!outcome.lots.is_empty());
+
+        let html = render("Rally <test>", &candles, &outcome);
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>Rally &lt;test&gt;</title>"));
+        // Price, equity, drawdown and monthly P&L.
+        assert_eq!(4, html.matches("<svg").count());
+        assert!(html.contains(&format!("fill=\"{}\"><title>Buy", GREEN)));
+        assert!(html.contains(&format!("fill=\"{}\"><title>Sell", RED)));
+        // Header and row for each baseline, header and row for each lot.
+        assert_eq!(
+            outcome.baselines.len() + outcome.lots.len() + 2,
+            html.matches("<tr>").count()
+        );
+        assert!(html.contains("<td>BuyAndHold</td>"));
+        assert!(html.contains("<title>2020-02: $"));
+
+        Ok(())
+    }
+}
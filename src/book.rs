@@ -0,0 +1,383 @@
+//! The trend tells us at what rate bitcoins trade, but not how many we can
+//! sell at that rate. The book actor keeps a local copy of Kraken's L2 order
+//! book of BTC/USD, built from the snapshot and the updates of the `book`
+//! channel. Each update carries a checksum of the top of the book, and when
+//! our copy disagrees with it, we subscribe again for a new snapshot.
+//!
+//! The book is shared with the seller, which sizes its offers so that they
+//! don't move the market too much.
+
+use {
+    rust_decimal::RoundingStrategy,
+    serde_json::{json, Value},
+    std::{
+        collections::BTreeMap,
+        str::FromStr,
+        sync::{Arc, Mutex, MutexGuard},
+        thread, time,
+    },
+    tungstenite::Message as WsMessage,
+};
+
+use crate::{models::Side, prelude::*, pricing::TopOfBook};
+
+/// Kraken's public WebSocket endpoint.
+pub const URL: &str = "wss://ws.kraken.com";
+
+// How long do we wait before reconnecting.
+const RECONNECT_AFTER: time::Duration = time::Duration::from_secs(5);
+
+// Kraken computes the checksum from this many levels of each side.
+const CHECKSUM_LEVELS: usize = 10;
+
+/// The volume at each price level of both sides of the book.
+#[derive(Debug, Clone, Default)]
+pub struct OrderBook {
+    // How many levels of each side do we keep.
+    depth: usize,
+    bids: BTreeMap<BtcExchangeRate, Btc>,
+    asks: BTreeMap<BtcExchangeRate, Btc>,
+}
+
+/// A handle to the book which the book actor keeps up to date.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBook(Arc<Mutex<OrderBook>>);
+
+/// Spawns a new thread which keeps the book up to date with Kraken. The book
+/// is empty while we're not connected.
+pub fn spawn(url: String, book: SharedBook) {
+    thread::spawn(move || loop {
+        if let Err(e) = stream(&url, &book) {
+            log::warn!("The Kraken book feed failed due to: {}", e);
+        }
+        book.lock().clear();
+        thread::sleep(RECONNECT_AFTER);
+    });
+}
+
+// Subscribes to the book and applies the messages until the connection
+// fails or the book diverges.
+fn stream(url: &str, book: &SharedBook) -> Result<()> {
+    let (mut socket, _) = tungstenite::connect(url)?;
+    let subscription = book.lock().subscription();
+    socket.write_message(WsMessage::Text(subscription))?;
+    log::info!("Subscribed to the Kraken book at {}", url);
+
+    loop {
+        match socket.read_message()? {
+            WsMessage::Text(text) => book.lock().apply(&text)?,
+            WsMessage::Close(_) => {
+                return Err(Box::new(Error::invalid_data("Connection closed")))
+            }
+            _ => continue,
+        };
+    }
+}
+
+impl OrderBook {
+    /// Creates an empty book which keeps given number of levels of each
+    /// side. Kraken supports the depths of 10, 25, 100, 500 and 1000.
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            ..Self::default()
+        }
+    }
+
+    /// The message which subscribes to the BTC/USD book of our depth.
+    pub fn subscription(&self) -> String {
+        json!({
+            "event": "subscribe",
+            "pair": ["XBT/USD"],
+            "subscription": { "name": "book", "depth": self.depth },
+        })
+        .to_string()
+    }
+
+    /// Applies a snapshot or an update of the book. Messages which don't
+    /// carry the book, such as heartbeats, are ignored. Fails if the book
+    /// disagrees with the checksum of the update, after which it must be
+    /// cleared and subscribed to again.
+    //
+    // [channel_id, {"as": [[price, volume, time], ...], "bs": [...]},
+    // "book-10", "XBT/USD"] or [channel_id, {"a": [...]}, {"b": [...],
+    // "c": checksum}, "book-10", "XBT/USD"]
+    pub fn apply(&mut self, message: &str) -> Result<()> {
+        let message: Value = serde_json::from_str(message)?;
+        let parts = match message.as_array() {
+            Some(parts) if parts.len() >= 4 => &parts[1..parts.len() - 2],
+            _ => return Ok(()),
+        };
+        let channel = message[parts.len() + 1].as_str().unwrap_or_default();
+        if !channel.starts_with("book") {
+            return Ok(());
+        }
+
+        let mut checksum = None;
+        for part in parts {
+            if part.get("as").is_some() || part.get("bs").is_some() {
+                self.clear();
+            }
+            if let Some(levels) = part.get("as").or_else(|| part.get("a")) {
+                update(&mut self.asks, levels)?;
+                truncate(&mut self.asks, self.depth, true);
+            }
+            if let Some(levels) = part.get("bs").or_else(|| part.get("b")) {
+                update(&mut self.bids, levels)?;
+                truncate(&mut self.bids, self.depth, false);
+            }
+            if let Some(c) = part.get("c") {
+                checksum = Some(u32::from_str(string(Some(c))?)?);
+            }
+        }
+
+        match checksum {
+            Some(expected) if expected != self.checksum() => {
+                Err(Box::new(Error::invalid_data(format!(
+                    "The book's checksum {} differs from Kraken's {}",
+                    self.checksum(),
+                    expected
+                ))))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes all levels, e.g. when the book cannot be trusted anymore.
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    /// The highest rate someone is willing to buy at.
+    pub fn best_bid(&self) -> Option<BtcExchangeRate> {
+        self.bids.keys().next_back().copied()
+    }
+
+    /// The lowest rate someone is willing to sell at.
+    pub fn best_ask(&self) -> Option<BtcExchangeRate> {
+        self.asks.keys().next().copied()
+    }
+
+    /// The difference between the best ask and the best bid.
+    pub fn spread(&self) -> Option<Cash> {
+        Some(self.best_ask()? - self.best_bid()?)
+    }
+
+    /// The best bid and ask, if both sides have any levels.
+    pub fn top(&self) -> Option<TopOfBook> {
+        Some(TopOfBook {
+            bid: self.best_bid()?,
+            ask: self.best_ask()?,
+        })
+    }
+
+    /// The average rate we would trade given bitcoins at if we took the
+    /// levels of the book one by one, best first. Selling takes the bids and
+    /// buying the asks. None if the book isn't deep enough.
+    pub fn expected_price(
+        &self,
+        side: Side,
+        btc: Btc,
+    ) -> Option<BtcExchangeRate> {
+        if btc <= Btc::new(0, 0) {
+            return None;
+        }
+        let levels: Box<dyn Iterator<Item = (&BtcExchangeRate, &Btc)>> =
+            match side {
+                Side::Sell => Box::new(self.bids.iter().rev()),
+                Side::Buy => Box::new(self.asks.iter()),
+            };
+
+        let mut remaining = btc;
+        let mut cash = Cash::new(0, 0);
+        for (rate, volume) in levels {
+            let taken = remaining.min(*volume);
+            cash += taken * rate;
+            remaining -= taken;
+            if remaining <= Btc::new(0, 0) {
+                return Some(cash / btc);
+            }
+        }
+
+        None
+    }
+
+    /// How many bitcoins we can trade with the average rate no worse than
+    /// given limit, taking the levels of the book one by one, best first.
+    /// The level which crosses the limit is taken only in part.
+    pub fn absorbs(&self, side: Side, limit: BtcExchangeRate) -> Btc {
+        let levels: Box<dyn Iterator<Item = (&BtcExchangeRate, &Btc)>> =
+            match side {
+                Side::Sell => Box::new(self.bids.iter().rev()),
+                Side::Buy => Box::new(self.asks.iter()),
+            };
+
+        let mut btc = Btc::new(0, 0);
+        let mut cash = Cash::new(0, 0);
+        for (rate, volume) in levels {
+            let better = match side {
+                Side::Sell => *rate >= limit,
+                Side::Buy => *rate <= limit,
+            };
+            // A worse level drags the average towards its rate, we take just
+            // enough of it for the average to reach the limit.
+            let taken = if better {
+                *volume
+            } else {
+                ((cash - limit * btc) / (limit - rate))
+                    .round_dp_with_strategy(8, RoundingStrategy::RoundDown)
+                    .min(*volume)
+            };
+            btc += taken;
+            cash += taken * rate;
+            if taken < *volume {
+                break;
+            }
+        }
+
+        btc
+    }
+
+    // CRC32 of the top levels, asks from the lowest and bids from the
+    // highest. Each level is its price and volume as Kraken formats them,
+    // without the decimal point and leading zeros.
+    fn checksum(&self) -> u32 {
+        let asks = self.asks.iter().take(CHECKSUM_LEVELS);
+        let bids = self.bids.iter().rev().take(CHECKSUM_LEVELS);
+        let mut hasher = crc32fast::Hasher::new();
+        for (rate, volume) in asks.chain(bids) {
+            for number in &[rate, volume] {
+                let digits = number.to_string().replace('.', "");
+                hasher.update(digits.trim_start_matches('0').as_bytes());
+            }
+        }
+
+        hasher.finalize()
+    }
+}
+
+impl SharedBook {
+    pub fn new(book: OrderBook) -> Self {
+        Self(Arc::new(Mutex::new(book)))
+    }
+
+    /// Locks the book for the duration of the returned guard.
+    pub fn lock(&self) -> MutexGuard<'_, OrderBook> {
+        // A book left half way through an update fails the checksum of the
+        // next one, so it's cleared and fetched again.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Sets the volume of each level, a zero volume removes the level. Republished
+// levels, which have an extra flag, are applied the same way.
+fn update(
+    side: &mut BTreeMap<BtcExchangeRate, Btc>,
+    levels: &Value,
+) -> Result<()> {
+    let levels = levels
+        .as_array()
+        .ok_or_else(|| invalid("Kraken book levels are not a list"))?;
+    for level in levels {
+        let rate = decimal(level.get(0))?;
+        let volume = decimal(level.get(1))?;
+        if volume <= Btc::new(0, 0) {
+            side.remove(&rate);
+        } else {
+            // The rate is replaced too, as it's formatted the way Kraken
+            // includes it in the checksum.
+            side.remove(&rate);
+            side.insert(rate, volume);
+        }
+    }
+
+    Ok(())
+}
+
+// Drops the levels beyond the depth, furthest from the top first. The top of
+// the asks is the lowest rate, of the bids the highest.
+fn truncate(
+    side: &mut BTreeMap<BtcExchangeRate, Btc>,
+    depth: usize,
+    asks: bool,
+) {
+    while side.len() > depth {
+        let furthest = if asks {
+            side.keys().next_back()
+        } else {
+            side.keys().next()
+        };
+        // It's safe to unwrap because there are more levels than the depth.
+        let rate = *furthest.unwrap();
+        side.remove(&rate);
+    }
+}
+
+fn string(value: Option<&Value>) -> Result<&str> {
+    value
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("Expected a string field"))
+}
+
+// Kraken sends numbers as strings to avoid losing precision.
+fn decimal(value: Option<&Value>) -> Result<Decimal> {
+    Ok(Decimal::from_str(string(value)?)?)
+}
+
+fn invalid(reason: &'static str) -> Box<dyn std::error::Error> {
+    Box::new(Error::invalid_data(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn should_maintain_book_and_validate_checksums() -> Result<()> {
+        let messages = fs::read_to_string("tests/data/ws/kraken_book.jsonl")?;
+        let mut messages = messages.lines();
+        let mut book = OrderBook::new(10);
+        // The last update has a wrong checksum.
+        for message in messages.by_ref().take(7) {
+            book.apply(message)?;
+        }
+
+        // 9_191 was pushed out of the depth by the new best bid.
+        assert_eq!(Some(BtcExchangeRate::new(92005, 1)), book.best_bid());
+        assert_eq!(Some(BtcExchangeRate::new(9201, 0)), book.best_ask());
+        assert_eq!(Some(Cash::new(5, 1)), book.spread());
+        assert_eq!(10, book.bids.len());
+        assert_eq!(None, book.bids.get(&BtcExchangeRate::new(9191, 0)));
+        assert_eq!(None, book.asks.get(&BtcExchangeRate::new(9202, 0)));
+
+        // Selling 1 BTC takes 0.3 BTC at 9_200.5 and 0.7 BTC at 9_200, buying
+        // 0.5 BTC takes 0.2 BTC at 9_201 and 0.3 BTC at 9_203.
+        assert_eq!(
+            Some(BtcExchangeRate::new(920015, 2)),
+            book.expected_price(Side::Sell, Btc::new(1, 0))
+        );
+        assert_eq!(
+            Some(BtcExchangeRate::new(92022, 1)),
+            book.expected_price(Side::Buy, Btc::new(5, 1))
+        );
+        assert_eq!(None, book.expected_price(Side::Sell, Btc::new(100, 0)));
+
+        // Selling 0.6 BTC averages 9_200.25, buying 0.4 BTC averages 9_202.
+        assert_eq!(
+            Btc::new(6, 1),
+            book.absorbs(Side::Sell, BtcExchangeRate::new(920025, 2))
+        );
+        assert_eq!(
+            Btc::new(4, 1),
+            book.absorbs(Side::Buy, BtcExchangeRate::new(9202, 0))
+        );
+
+        let diverged = book.apply(messages.next().unwrap());
+        assert!(diverged.is_err());
+
+        Ok(())
+    }
+}
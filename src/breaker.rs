@@ -0,0 +1,366 @@
+//! The circuit breaker halts trading when the market or our data about it
+//! behaves abnormally: the trend changes too fast, it is too volatile, or the
+//! sources we read it from disagree. It is shared by the actors, which don't
+//! trade while it is tripped.
+//!
+//! Trading resumes on its own once the readings have been normal for a while.
+//! The breaker also has a manual kill switch which halts trading until it is
+//! explicitly re-armed, and can be paused by other components, such as the
+//! watchdog, until they resume it.
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    rust_decimal::prelude::{FromPrimitive, ToPrimitive},
+    std::{
+        collections::{HashMap, VecDeque},
+        fmt,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+};
+
+use crate::prelude::*;
+
+/// When is the breaker tripped.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// How much can the trend change within the window, in percent.
+    pub max_price_change: Percentage,
+    /// How large can the standard deviation of the changes between
+    /// consecutive readings within the window be, in percent.
+    pub max_volatility: Percentage,
+    /// How far apart can the sources be, in percent of the lowest reading.
+    pub max_disagreement: Percentage,
+    /// How far into the past do we look when evaluating the trend. Source
+    /// readings older than this are ignored.
+    pub window: Duration,
+    /// How long does trading stay halted after the last abnormal reading.
+    pub cooldown: Duration,
+}
+
+/// Why is trading halted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trip {
+    PriceChange(Percentage),
+    Volatility(Percentage),
+    Disagreement(Percentage),
+    /// None of the sources have sent a reading for given duration.
+    StaleFeed(Duration),
+    KillSwitch,
+}
+
+/// A circuit breaker which can be shared between actors.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    thresholds: Thresholds,
+    // The trend readings within the window, oldest first.
+    trend: VecDeque<(DateTime<Utc>, BtcExchangeRate)>,
+    // The latest reading of each source.
+    sources: HashMap<String, (DateTime<Utc>, BtcExchangeRate)>,
+    // The last automatic trip and when it happened.
+    tripped: Option<(Trip, DateTime<Utc>)>,
+    // Whether the kill switch was pulled.
+    killed: bool,
+    // Why is trading paused, if it is.
+    paused: Option<Trip>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_price_change: Percentage::new(10, 0),
+            max_volatility: Percentage::new(3, 0),
+            max_disagreement: Percentage::new(2, 0),
+            window: Duration::minutes(5),
+            cooldown: Duration::minutes(30),
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(Thresholds::default())
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Self(Arc::new(Mutex::new(State {
+            thresholds,
+            trend: VecDeque::new(),
+            sources: HashMap::new(),
+            tripped: None,
+            killed: false,
+            paused: None,
+        })))
+    }
+
+    /// Records a reading of the trend and checks how much and how wildly it
+    /// changed within the window. Returns the trip if the reading is
+    /// abnormal.
+    pub fn observe_trend(
+        &self,
+        rate: BtcExchangeRate,
+        observed_at: DateTime<Utc>,
+    ) -> Option<Trip> {
+        let mut state = self.lock();
+        let window = state.thresholds.window;
+        while let Some((time, _)) = state.trend.front() {
+            if observed_at - *time <= window {
+                break;
+            }
+            state.trend.pop_front();
+        }
+        state.trend.push_back((observed_at, rate));
+
+        let max_change = state
+            .trend
+            .iter()
+            .map(|(_, past)| change(*past, rate).abs())
+            .max()
+            .unwrap_or_default();
+        let trip = if max_change > state.thresholds.max_price_change {
+            Some(Trip::PriceChange(max_change))
+        } else {
+            let volatility = volatility(&state.trend);
+            if volatility > state.thresholds.max_volatility {
+                Some(Trip::Volatility(volatility))
+            } else {
+                None
+            }
+        };
+
+        state.trip(trip, observed_at)
+    }
+
+    /// Records a reading of a single source the trend is derived from and
+    /// checks whether the recent readings of all sources agree. Returns the
+    /// trip if they don't.
+    pub fn observe_source(
+        &self,
+        source: &str,
+        rate: BtcExchangeRate,
+        observed_at: DateTime<Utc>,
+    ) -> Option<Trip> {
+        let mut state = self.lock();
+        state
+            .sources
+            .insert(source.to_string(), (observed_at, rate));
+
+        let window = state.thresholds.window;
+        let recent = state
+            .sources
+            .values()
+            .filter(|(time, _)| observed_at - *time <= window)
+            .map(|(_, rate)| *rate);
+        let (low, high) = recent.fold((None, None), |(low, high), rate| {
+            (
+                Some(low.map_or(rate, |low: Decimal| low.min(rate))),
+                Some(high.map_or(rate, |high: Decimal| high.max(rate))),
+            )
+        });
+        let trip = match (low, high) {
+            (Some(low), Some(high)) => {
+                let disagreement = change(low, high);
+                if disagreement > state.thresholds.max_disagreement {
+                    Some(Trip::Disagreement(disagreement))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        state.trip(trip, observed_at)
+    }
+
+    /// Tells whether we can trade at given time, and if not, why.
+    pub fn check(&self, now: DateTime<Utc>) -> std::result::Result<(), Trip> {
+        let state = self.lock();
+        if state.killed {
+            return Err(Trip::KillSwitch);
+        }
+        if let Some(trip) = state.paused {
+            return Err(trip);
+        }
+
+        match state.tripped {
+            Some((trip, at)) if now - at < state.thresholds.cooldown => {
+                Err(trip)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Halts trading until the breaker is re-armed. Cancelling the open
+    /// orders is up to the caller, see the risk manager.
+    pub fn kill(&self) {
+        log::warn!("Kill switch pulled, trading is halted until re-armed");
+        self.lock().killed = true;
+    }
+
+    /// Halts trading until [`CircuitBreaker::resume`] is called.
+    pub fn pause(&self, trip: Trip) {
+        self.lock().paused = Some(trip);
+    }
+
+    /// Lifts the pause. Trading stays halted if the breaker is tripped for
+    /// other reasons.
+    pub fn resume(&self) {
+        self.lock().paused = None;
+    }
+
+    /// Resumes trading after the kill switch was pulled. Automatic trips are
+    /// cleared as well, but a pause is not.
+    pub fn rearm(&self) {
+        log::info!("Circuit breaker re-armed, trading resumes");
+        let mut state = self.lock();
+        state.killed = false;
+        state.tripped = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state is updated by simple assignments which cannot panic half
+        // way through, so it's consistent even if the lock is poisoned.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn trip(&mut self, trip: Option<Trip>, at: DateTime<Utc>) -> Option<Trip> {
+        if let Some(trip) = trip {
+            log::warn!("Circuit breaker tripped at {}: {}", at, trip);
+            self.tripped = Some((trip, at));
+        }
+
+        trip
+    }
+}
+
+impl fmt::Display for Trip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PriceChange(change) => {
+                write!(f, "trend changed by {}%", change.round_dp(2))
+            }
+            Self::Volatility(volatility) => {
+                write!(f, "trend volatility is {}%", volatility.round_dp(2))
+            }
+            Self::Disagreement(spread) => {
+                write!(f, "sources disagree by {}%", spread.round_dp(2))
+            }
+            Self::StaleFeed(silence) => {
+                write!(f, "feed is silent for {}s", silence.num_seconds())
+            }
+            Self::KillSwitch => write!(f, "kill switch was pulled"),
+        }
+    }
+}
+
+// By how many percent did the rate change.
+fn change(from: BtcExchangeRate, to: BtcExchangeRate) -> Percentage {
+    if from == Decimal::new(0, 0) {
+        Percentage::new(0, 0)
+    } else {
+        (to - from) / from * Decimal::new(100, 0)
+    }
+}
+
+// Standard deviation of the changes between consecutive readings, in
+// percent. Needs at least two changes to say anything.
+fn volatility(
+    trend: &VecDeque<(DateTime<Utc>, BtcExchangeRate)>,
+) -> Percentage {
+    let changes: Vec<f64> = trend
+        .iter()
+        .zip(trend.iter().skip(1))
+        .filter_map(|((_, from), (_, to))| change(*from, *to).to_f64())
+        .collect();
+    if changes.len() < 2 {
+        return Percentage::new(0, 0);
+    }
+
+    let n = changes.len() as f64;
+    let mean = changes.iter().sum::<f64>() / n;
+    let variance =
+        changes.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    Percentage::from_f64(variance.sqrt()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn should_halt_on_abnormal_trend_until_cooldown_passes() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let breaker = CircuitBreaker::default();
+        let minute = |m: i64| start + Duration::minutes(m);
+
+        for (m, rate) in [100, 101, 100, 102].iter().enumerate() {
+            let rate = BtcExchangeRate::new(*rate, 0);
+            assert_eq!(None, breaker.observe_trend(rate, minute(m as i64)));
+        }
+        assert!(breaker.check(minute(3)).is_ok());
+
+        // A flash crash by 30 % within a minute.
+        let trip =
+            breaker.observe_trend(BtcExchangeRate::new(714, 1), minute(4));
+        assert_eq!(Some(Trip::PriceChange(Percentage::new(30, 0))), trip);
+        assert!(breaker.check(minute(5)).is_err());
+
+        // The crash is out of the window, but the trend keeps jumping.
+        for (m, rate) in [80, 75, 80, 75, 80, 75].iter().enumerate() {
+            breaker.observe_trend(
+                BtcExchangeRate::new(*rate, 0),
+                minute(10 + m as i64),
+            );
+        }
+        assert!(matches!(
+            breaker.check(minute(16)),
+            Err(Trip::Volatility(_))
+        ));
+
+        assert!(breaker.check(minute(15 + 30)).is_ok());
+    }
+
+    #[test]
+    fn should_halt_when_sources_disagree() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let breaker = CircuitBreaker::default();
+
+        let rate = BtcExchangeRate::new(100, 0);
+        assert_eq!(None, breaker.observe_source("kraken", rate, start));
+        let rate = BtcExchangeRate::new(101, 0);
+        assert_eq!(None, breaker.observe_source("bitstamp", rate, start));
+        let rate = BtcExchangeRate::new(105, 0);
+        assert_eq!(
+            Some(Trip::Disagreement(Percentage::new(5, 0))),
+            breaker.observe_source("coinbase", rate, start)
+        );
+
+        // Readings outside of the window don't count.
+        let later = start + Duration::minutes(10);
+        let rate = BtcExchangeRate::new(105, 0);
+        assert_eq!(None, breaker.observe_source("coinbase", rate, later));
+    }
+
+    #[test]
+    fn should_stay_halted_until_rearmed() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let breaker = CircuitBreaker::default();
+
+        breaker.kill();
+        assert_eq!(
+            Err(Trip::KillSwitch),
+            breaker.check(start + Duration::weeks(1))
+        );
+
+        breaker.rearm();
+        assert!(breaker.check(start).is_ok());
+    }
+}
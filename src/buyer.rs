@@ -0,0 +1,336 @@
+//! The buyer decides when to acquire bitcoins. Buying at any rate leaves the
+//! seller with purchases that are hard to sell at a margin, so the buyer only
+//! bids when the trend dips a configured percent below a reference, either the
+//! moving average or the recent high of the trend. Consecutive buys are kept
+//! apart by a cooldown, and the cash spent within a week is capped by a
+//! budget.
+
+use {
+    chrono::{DateTime, Datelike, Duration, IsoWeek, Utc},
+    std::collections::{HashMap, VecDeque},
+    uuid::Uuid,
+};
+
+use crate::{models::Bid, prelude::*, wallet::SharedWallet};
+
+/// Parameters of the dip detection.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// What the trend is compared against.
+    pub reference: Reference,
+    /// How far back does the reference look. We don't buy until we've
+    /// observed the trend for this long.
+    pub window: Duration,
+    /// How far apart are the samples of the trend the reference is computed
+    /// from.
+    pub sampling: Duration,
+    /// How many percent below the reference must the trend be to buy.
+    pub dip: Percentage,
+    /// How long do we wait after a buy before buying again.
+    pub cooldown: Duration,
+    /// How much cash do we bid on a single buy.
+    pub spending: Cash,
+    /// How much cash can we bid within a calendar week, starting on Monday.
+    pub weekly_budget: Cash,
+}
+
+/// The rate a dip is measured from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reference {
+    /// The mean of the samples within the window.
+    MovingAverage,
+    /// The highest sample within the window.
+    RecentHigh,
+}
+
+/// What the buyer hears back about its bids.
+#[derive(Debug)]
+pub enum Message {
+    /// The bid breached a risk limit and was not placed.
+    BidRejected(Bid),
+}
+
+/// Samples the trend and decides whether to buy at it.
+#[derive(Debug)]
+pub struct DipPolicy {
+    config: Config,
+    // The cash of our bids is reserved in the wallet.
+    wallet: SharedWallet,
+    // The samples within the window, oldest first.
+    samples: VecDeque<(DateTime<Utc>, BtcExchangeRate)>,
+    // When did we first observe the trend.
+    since: Option<DateTime<Utc>>,
+    last_buy: Option<DateTime<Utc>>,
+    // The cash we've bid in the current week.
+    week: Option<(IsoWeek, Cash)>,
+    // The bids which haven't been settled yet, with the week they were made
+    // in, so that their unspent cash returns to that week's budget.
+    pending: HashMap<Uuid, (IsoWeek, Cash)>,
+}
+
+impl DipPolicy {
+    pub fn new(config: Config, wallet: SharedWallet) -> Self {
+        Self {
+            config,
+            wallet,
+            samples: VecDeque::new(),
+            since: None,
+            last_buy: None,
+            week: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Samples the trend and returns a bid at it if the trend dipped enough
+    /// below the reference, the cooldown has passed and there's budget left
+    /// for the week. The bid is capped by what's left of the budget and its
+    /// cash is reserved in the wallet. Until the bid is settled, its cash
+    /// counts towards the budget.
+    pub fn handle(
+        &mut self,
+        trend: BtcExchangeRate,
+        observed_at: DateTime<Utc>,
+    ) -> Option<Bid> {
+        self.observe(trend, observed_at);
+
+        let since = self.since?;
+        if observed_at - since < self.config.window {
+            return None;
+        }
+        let in_cooldown = self
+            .last_buy
+            .is_some_and(|last| observed_at - last < self.config.cooldown);
+        if in_cooldown {
+            return None;
+        }
+
+        let reference = self.reference()?;
+        let threshold =
+            reference - reference / Decimal::new(100, 0) * self.config.dip;
+        if trend > threshold {
+            return None;
+        }
+
+        let week = observed_at.iso_week();
+        let spent = match self.week {
+            Some((current, spent)) if current == week => spent,
+            _ => Cash::new(0, 0),
+        };
+        let cash = self.config.spending.min(self.config.weekly_budget - spent);
+        if cash <= Cash::new(0, 0) {
+            log::debug!(
+                "Trend {} dipped below {} but the weekly budget is spent",
+                trend,
+                reference
+            );
+            return None;
+        }
+
+        let bid = Bid::new(cash, trend);
+        if let Err(e) = self.wallet.lock().reserve_cash(bid.id, bid.cash) {
+            log::debug!("Trend {} dipped below {}: {}", trend, reference, e);
+            return None;
+        }
+
+        log::info!(
+            "Trend {} dipped below {}, bidding {} cash",
+            trend,
+            reference,
+            cash
+        );
+        self.week = Some((week, spent + cash));
+        self.pending.insert(bid.id, (week, cash));
+        self.last_buy = Some(observed_at);
+        Some(bid)
+    }
+
+    /// The bid was filled for given cash, or released if nothing was spent.
+    /// Only the spent cash is charged to the budget of the week the bid was
+    /// made in, the rest is given back. The wallet is settled by whoever
+    /// filled or released the bid.
+    pub fn settle(&mut self, bid: Uuid, spent: Cash) {
+        let (week, cash) = if let Some(pending) = self.pending.remove(&bid) {
+            pending
+        } else {
+            return;
+        };
+        if let Some((current, charged)) = self.week.as_mut() {
+            if *current == week {
+                *charged -= (cash - spent).max(Cash::new(0, 0));
+            }
+        }
+    }
+
+    /// The bid was never placed, e.g. the risk manager rejected it. Its cash
+    /// is released in the wallet and all of it returns to the budget. The
+    /// cooldown still applies, so that we don't bid again right away.
+    pub fn reject(&mut self, bid: Uuid) {
+        self.wallet.lock().release(bid);
+        self.settle(bid, Cash::new(0, 0));
+    }
+
+    /// The rate the dips are measured from, once we have any samples.
+    pub fn reference(&self) -> Option<BtcExchangeRate> {
+        let rates = self.samples.iter().map(|(_, rate)| *rate);
+        match self.config.reference {
+            Reference::RecentHigh => rates.max(),
+            Reference::MovingAverage if self.samples.is_empty() => None,
+            Reference::MovingAverage => Some(
+                rates.sum::<BtcExchangeRate>()
+                    / Decimal::from(self.samples.len() as u64),
+            ),
+        }
+    }
+
+    // Takes a sample of the trend if the sampling interval has passed since
+    // the last one, and drops the samples which fell out of the window.
+    fn observe(&mut self, rate: BtcExchangeRate, at: DateTime<Utc>) {
+        self.since.get_or_insert(at);
+        let due = self
+            .samples
+            .back()
+            .is_none_or(|(last, _)| at - *last >= self.config.sampling);
+        if due {
+            self.samples.push_back((at, rate));
+        }
+        while self
+            .samples
+            .front()
+            .is_some_and(|(time, _)| at - *time > self.config.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{models::Balances, wallet::Wallet};
+
+    fn wallet(cash: i64) -> SharedWallet {
+        SharedWallet::new(Wallet::new(Cash::new(cash, 0), Btc::new(0, 0)))
+    }
+
+    #[test]
+    fn should_buy_dips_within_cooldown_and_budget() {
+        let config = Config {
+            reference: Reference::RecentHigh,
+            window: Duration::days(1),
+            sampling: Duration::hours(1),
+            dip: Percentage::new(5, 0),
+            cooldown: Duration::days(1),
+            spending: Cash::new(100, 0),
+            weekly_budget: Cash::new(250, 0),
+        };
+        let mut policy = DipPolicy::new(config, wallet(10_000));
+        // A Monday.
+        let start = Utc.with_ymd_and_hms(2020, 6, 1, 0, 0, 0).unwrap();
+        let mut bid = |hours, rate| {
+            let at = start + Duration::hours(hours);
+            policy
+                .handle(BtcExchangeRate::new(rate, 0), at)
+                .map(|bid| (bid.cash, bid.rate))
+        };
+        let buy = |cash, rate| {
+            Some((Cash::new(cash, 0), BtcExchangeRate::new(rate, 0)))
+        };
+
+        // The dip doesn't count until we've watched the trend for a day.
+        assert_eq!(None, bid(0, 10_000));
+        assert_eq!(None, bid(12, 9_000));
+        // 6 % below the high of 10_000.
+        assert_eq!(buy(100, 9_400), bid(24, 9_400));
+        // Still in the cooldown.
+        assert_eq!(None, bid(25, 8_000));
+        // The high is still 9_400, so 9_000 isn't deep enough.
+        assert_eq!(None, bid(48, 9_000));
+        // The 9_400 fell out of the window and the high is now 9_000.
+        assert_eq!(buy(100, 8_500), bid(49, 8_500));
+        // Only 50 cash is left of the budget.
+        assert_eq!(buy(50, 8_000), bid(73, 8_000));
+        assert_eq!(None, bid(97, 7_500));
+        // A new week has a new budget.
+        assert_eq!(None, bid(150, 8_000));
+        assert_eq!(buy(100, 7_500), bid(168, 7_500));
+    }
+
+    #[test]
+    fn should_measure_dips_from_moving_average() {
+        let config = Config {
+            reference: Reference::MovingAverage,
+            window: Duration::hours(2),
+            sampling: Duration::hours(1),
+            dip: Percentage::new(5, 0),
+            cooldown: Duration::days(1),
+            spending: Cash::new(100, 0),
+            weekly_budget: Cash::new(1_000, 0),
+        };
+        let mut policy = DipPolicy::new(config, wallet(10_000));
+        let start = Utc.with_ymd_and_hms(2020, 6, 1, 0, 0, 0).unwrap();
+        let rate = |rate| BtcExchangeRate::new(rate, 0);
+
+        assert!(policy.handle(rate(100), start).is_none());
+        // Not sampled, as it's within the sampling interval.
+        assert!(policy
+            .handle(rate(200), start + Duration::minutes(30))
+            .is_none());
+        assert!(policy
+            .handle(rate(110), start + Duration::hours(1))
+            .is_none());
+        assert_eq!(Some(rate(105)), policy.reference());
+
+        // The average of 100, 110 and 95 is 101.67, which 95 is 6.6 % below.
+        let bid = policy.handle(rate(95), start + Duration::hours(2));
+        assert_eq!(Some(rate(95)), bid.map(|bid| bid.rate));
+    }
+
+    #[test]
+    fn should_reserve_cash_and_charge_budget_for_spent_cash() {
+        let config = Config {
+            reference: Reference::RecentHigh,
+            window: Duration::days(1),
+            sampling: Duration::hours(1),
+            dip: Percentage::new(5, 0),
+            cooldown: Duration::hours(1),
+            spending: Cash::new(100, 0),
+            weekly_budget: Cash::new(150, 0),
+        };
+        let wallet = wallet(140);
+        let mut policy = DipPolicy::new(config, wallet.clone());
+        let start = Utc.with_ymd_and_hms(2020, 6, 1, 0, 0, 0).unwrap();
+        let bid = |policy: &mut DipPolicy, hours, rate| {
+            let at = start + Duration::hours(hours);
+            policy.handle(BtcExchangeRate::new(rate, 0), at)
+        };
+
+        assert!(bid(&mut policy, 0, 10_000).is_none());
+        assert!(bid(&mut policy, 12, 10_000).is_none());
+        let rejected = bid(&mut policy, 24, 9_000).expect("Trend dipped");
+        assert_eq!(Cash::new(40, 0), wallet.lock().available_cash());
+
+        // The rejected bid releases its cash and gives it back to the budget.
+        policy.reject(rejected.id);
+        assert_eq!(Cash::new(140, 0), wallet.lock().available_cash());
+        let filled =
+            bid(&mut policy, 25, 9_000).expect("Budget was given back");
+        assert_eq!(Cash::new(100, 0), filled.cash);
+
+        // Only the 80 cash the bid spent is charged, 70 is left of the
+        // budget but the wallet holds only 60.
+        wallet
+            .lock()
+            .settle_buy(filled.id, Cash::new(80, 0), Btc::new(1, 2));
+        policy.settle(filled.id, Cash::new(80, 0));
+        assert!(bid(&mut policy, 26, 9_000).is_none());
+        assert_eq!(Cash::new(60, 0), wallet.lock().available_cash());
+        wallet.lock().sync(&Balances {
+            cash: Cash::new(1_000, 0),
+            btc: Btc::new(1, 2),
+        });
+        let bid = bid(&mut policy, 27, 9_000).expect("Wallet has the cash");
+        assert_eq!(Cash::new(70, 0), bid.cash);
+    }
+}
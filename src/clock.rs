@@ -0,0 +1,78 @@
+//! Time-dependent logic asks a [`Clock`] for the current time instead of the
+//! system directly. This way the actors behave the same way when we replay
+//! historical data as they do when trading live.
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    std::sync::{Arc, Mutex},
+};
+
+/// Provides the current time to the actors.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Reads the time from the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+/// A clock which only moves when told to. Clones share the same time, so that
+/// the replay can advance the clock which the actors hold.
+#[derive(Debug, Clone)]
+pub struct SimulatedClock(Arc<Mutex<DateTime<Utc>>>);
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+impl SimulatedClock {
+    /// Creates a new clock which starts at given time.
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self(Arc::new(Mutex::new(start)))
+    }
+
+    /// Moves the clock to given time. The time is allowed to go backwards,
+    /// which is useful for starting a new replay with the same clock.
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.lock() = time;
+    }
+
+    /// Moves the clock forward by given duration.
+    pub fn advance(&self, by: Duration) {
+        *self.lock() += by;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DateTime<Utc>> {
+        // The lock is only held while the time is read or written, and that
+        // cannot leave the time in an invalid state.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn should_share_time_between_clones() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let clock = SimulatedClock::new(start);
+        let actors_clock: Arc<dyn Clock> = Arc::new(clock.clone());
+
+        clock.advance(Duration::minutes(5));
+        assert_eq!(start + Duration::minutes(5), actors_clock.now());
+
+        clock.set(start);
+        assert_eq!(start, actors_clock.now());
+    }
+}
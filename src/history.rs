@@ -0,0 +1,468 @@
+//! Loads historical exchange rate data which is used to replay the market in
+//! backtests. Every supported export format is normalized into a list of
+//! [`Candle`]s sorted by time. Candles are only used by the backtester. The
+//! trend actor doesn't read historical data, and it keeps live trades as
+//! [`Tick`](crate::trend::Tick)s, as it needs to know which exchange each
+//! trade comes from.
+//!
+//! Files which start with the gzip magic bytes are decompressed on the fly,
+//! therefore `.csv` and `.csv.gz` exports can be used interchangeably.
+
+use {
+    chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc},
+    flate2::read::GzDecoder,
+    serde::Deserialize,
+    std::{
+        fs::File,
+        io::{BufRead, BufReader, Read},
+        path::Path,
+    },
+};
+
+use crate::prelude::*;
+
+// First two bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Describes how the exchange rate evolved over a period of time which starts
+/// at `time`. A single trade is represented as a candle whose open, high, low
+/// and close are all equal to the price of the trade.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candle {
+    /// When does the period which the candle describes start.
+    pub time: DateTime<Utc>,
+    pub open: BtcExchangeRate,
+    pub high: BtcExchangeRate,
+    pub low: BtcExchangeRate,
+    pub close: BtcExchangeRate,
+    /// How many bitcoins were traded during the period.
+    pub volume: Btc,
+}
+
+/// Lists the export formats we know how to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Daily rows with header `Date,Open,High,Low,Close,Adj Close,Volume`.
+    /// The adjusted close is ignored, so older exports which name it
+    /// `AdjClose` can be read too. The volume is in hard currency, not
+    /// bitcoins.
+    Yahoo,
+    /// Kraken OHLCVT export without header. The columns are
+    /// `timestamp,open,high,low,close,volume,trades`.
+    KrakenOhlc,
+    /// Kraken trades export without header. The columns are
+    /// `timestamp,price,volume`.
+    KrakenTrades,
+    /// Bitstamp candles as exported by CryptoDataDownload, with header
+    /// `unix,date,symbol,open,high,low,close,Volume BTC,Volume USD`. The line
+    /// with the URL of the source which precedes the header is skipped.
+    Bitstamp,
+    /// Coinbase candles with header `time,low,high,open,close,volume`.
+    Coinbase,
+}
+
+/// Widths of candles the market can be replayed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl Candle {
+    /// Creates a candle which represents a single trade.
+    pub fn from_tick(
+        time: DateTime<Utc>,
+        price: BtcExchangeRate,
+        volume: Btc,
+    ) -> Self {
+        Self {
+            time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume,
+        }
+    }
+
+    /// Average between the candle's high and low.
+    pub fn mid(&self) -> BtcExchangeRate {
+        (self.high + self.low) / Decimal::new(2, 0)
+    }
+}
+
+impl Interval {
+    /// How much time does one candle of this width span.
+    pub fn duration(self) -> Duration {
+        match self {
+            Self::OneMinute => Duration::minutes(1),
+            Self::FiveMinutes => Duration::minutes(5),
+            Self::OneHour => Duration::hours(1),
+            Self::OneDay => Duration::days(1),
+        }
+    }
+}
+
+/// Merges time sorted candles or ticks into candles of given width. Each
+/// resulting candle starts at a multiple of the interval since epoch. Periods
+/// without any trades are left out.
+///
+/// Resampling into an interval narrower than the input's is a no-op apart
+/// from aligning the candle times.
+pub fn resample(candles: &[Candle], interval: Interval) -> Vec<Candle> {
+    let width = interval.duration().num_seconds();
+    let mut resampled: Vec<Candle> = Vec::new();
+
+    for candle in candles {
+        let start = candle.time.timestamp() - candle.time.timestamp() % width;
+        match resampled.last_mut() {
+            Some(last) if last.time.timestamp() == start => {
+                last.high = last.high.max(candle.high);
+                last.low = last.low.min(candle.low);
+                last.close = candle.close;
+                last.volume += candle.volume;
+            }
+            _ => resampled.push(Candle {
+                // It's safe to unwrap because the start is always less than
+                // the timestamp of an existing candle.
+                time: Utc.timestamp_opt(start, 0).unwrap(),
+                ..*candle
+            }),
+        }
+    }
+
+    resampled
+}
+
+/// Reads the file at given path in given format. Gzip compressed files are
+/// detected and decompressed.
+pub fn load(path: impl AsRef<Path>, format: Format) -> Result<Vec<Candle>> {
+    let file = File::open(path)?;
+    read(file, format)
+}
+
+/// Reads candles in given format from any reader. The candles are returned
+/// sorted by time.
+pub fn read(reader: impl Read, format: Format) -> Result<Vec<Candle>> {
+    let mut reader = BufReader::new(reader);
+    let is_gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+
+    let mut candles = if is_gzip {
+        read_csv(BufReader::new(GzDecoder::new(reader)), format)?
+    } else {
+        read_csv(reader, format)?
+    };
+
+    candles.sort_by_key(|candle| candle.time);
+    Ok(candles)
+}
+
+fn read_csv(mut reader: impl BufRead, format: Format) -> Result<Vec<Candle>> {
+    if format == Format::Bitstamp {
+        skip_source_line(&mut reader)?;
+    }
+
+    let has_headers = match format {
+        Format::KrakenOhlc | Format::KrakenTrades => false,
+        Format::Yahoo | Format::Bitstamp | Format::Coinbase => true,
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(has_headers)
+        .flexible(true)
+        .from_reader(reader);
+
+    match format {
+        Format::Yahoo => collect::<YahooRow, _>(&mut rdr),
+        Format::KrakenOhlc => collect::<KrakenOhlcRow, _>(&mut rdr),
+        Format::KrakenTrades => collect::<KrakenTradeRow, _>(&mut rdr),
+        Format::Bitstamp => collect::<BitstampRow, _>(&mut rdr),
+        Format::Coinbase => collect::<CoinbaseRow, _>(&mut rdr),
+    }
+}
+
+// CryptoDataDownload prepends a line with their URL before the header. We
+// only skip the line if it doesn't look like the header itself.
+fn skip_source_line(reader: &mut impl BufRead) -> Result<()> {
+    if !reader.fill_buf()?.starts_with(b"unix") {
+        reader.read_line(&mut String::new())?;
+    }
+
+    Ok(())
+}
+
+fn collect<T, R>(rdr: &mut csv::Reader<R>) -> Result<Vec<Candle>>
+where
+    T: for<'de> Deserialize<'de> + IntoCandle,
+    R: Read,
+{
+    rdr.deserialize::<T>()
+        .map(|row| row?.into_candle())
+        .collect()
+}
+
+trait IntoCandle {
+    fn into_candle(self) -> Result<Candle>;
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+struct YahooRow {
+    date: String,
+    open: BtcExchangeRate,
+    high: BtcExchangeRate,
+    low: BtcExchangeRate,
+    close: BtcExchangeRate,
+    volume: Cash,
+}
+
+impl IntoCandle for YahooRow {
+    fn into_candle(self) -> Result<Candle> {
+        let date = NaiveDate::parse_from_str(&self.date, "%Y-%m-%d")?;
+        // Yahoo reports the volume in dollars, we convert it to bitcoins with
+        // the close rate.
+        let volume = if self.close == Decimal::new(0, 0) {
+            Btc::new(0, 0)
+        } else {
+            self.volume / self.close
+        };
+
+        Ok(Candle {
+            time: Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()),
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            close: self.close,
+            volume,
+        })
+    }
+}
+
+// The trailing number of trades is ignored.
+#[derive(Deserialize)]
+struct KrakenOhlcRow(
+    String,
+    BtcExchangeRate,
+    BtcExchangeRate,
+    BtcExchangeRate,
+    BtcExchangeRate,
+    Btc,
+);
+
+impl IntoCandle for KrakenOhlcRow {
+    fn into_candle(self) -> Result<Candle> {
+        let Self(time, open, high, low, close, volume) = self;
+        Ok(Candle {
+            time: parse_unix_time(&time)?,
+            open,
+            high,
+            low,
+            close,
+            volume,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct KrakenTradeRow(String, BtcExchangeRate, Btc);
+
+impl IntoCandle for KrakenTradeRow {
+    fn into_candle(self) -> Result<Candle> {
+        let Self(time, price, volume) = self;
+        Ok(Candle::from_tick(parse_unix_time(&time)?, price, volume))
+    }
+}
+
+#[derive(Deserialize)]
+struct BitstampRow {
+    unix: String,
+    open: BtcExchangeRate,
+    high: BtcExchangeRate,
+    low: BtcExchangeRate,
+    close: BtcExchangeRate,
+    #[serde(rename = "Volume BTC")]
+    volume: Btc,
+}
+
+impl IntoCandle for BitstampRow {
+    fn into_candle(self) -> Result<Candle> {
+        Ok(Candle {
+            time: parse_unix_time(&self.unix)?,
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            close: self.close,
+            volume: self.volume,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct CoinbaseRow {
+    time: String,
+    low: BtcExchangeRate,
+    high: BtcExchangeRate,
+    open: BtcExchangeRate,
+    close: BtcExchangeRate,
+    volume: Btc,
+}
+
+impl IntoCandle for CoinbaseRow {
+    fn into_candle(self) -> Result<Candle> {
+        Ok(Candle {
+            time: parse_unix_time(&self.time)?,
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            close: self.close,
+            volume: self.volume,
+        })
+    }
+}
+
+// Exchanges export the time as seconds since epoch, sometimes with fractions
+// of a second and sometimes in milliseconds. Anything with more than 11
+// digits before the decimal point is considered to be in milliseconds.
+pub(crate) fn parse_unix_time(value: &str) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+    let whole = value.split('.').next().unwrap_or(value);
+    let (secs, millis) = if whole.len() > 11 {
+        let millis: i64 = whole.parse()?;
+        (millis / 1000, millis % 1000)
+    } else {
+        let secs: i64 = whole.parse()?;
+        let fraction = value.get(whole.len() + 1..).unwrap_or("");
+        let millis = format!("{:0<3}", fraction)[..3].parse().unwrap_or(0);
+        (secs, millis)
+    };
+
+    Utc.timestamp_opt(secs, millis as u32 * 1_000_000)
+        .single()
+        .ok_or_else(|| Error::invalid_data("Unix time out of range").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use {flate2::write::GzEncoder, std::io::Write};
+
+    use super::*;
+
+    #[test]
+    fn should_load_yahoo_daily_rows() -> Result<()> {
+        let candles = load(
+            "tests/data/btc_usd_2019_02_01-2020_08_19.csv",
+            Format::Yahoo,
+        )?;
+
+        assert_eq!(930, candles.len());
+        let first = &candles[0];
+        assert_eq!(
+            Utc.with_ymd_and_hms(2018, 2, 1, 0, 0, 0).unwrap(),
+            first.time
+        );
+        assert_eq!(Decimal::new(10288_799805, 6), first.high);
+        assert_eq!(Decimal::new(8812_280273, 6), first.low);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_normalize_all_formats_into_candles() -> Result<()> {
+        let time = Utc.timestamp_opt(1_577_836_800, 0).unwrap();
+        let expected = Candle {
+            time,
+            open: Decimal::new(7200, 0),
+            high: Decimal::new(7300, 0),
+            low: Decimal::new(7100, 0),
+            close: Decimal::new(7250, 0),
+            volume: Decimal::new(15, 1),
+        };
+
+        let kraken_ohlc = "1577836800,7200,7300,7100,7250,1.5,42\n";
+        let bitstamp = "https://www.CryptoDataDownload.com\n\
+            unix,date,symbol,open,high,low,close,Volume BTC,Volume USD\n\
+            1577836800000,2020-01-01 00:00:00,BTC/USD,\
+            7200,7300,7100,7250,1.5,10875\n";
+        let coinbase = "time,low,high,open,close,volume\n\
+            1577836800,7100,7300,7200,7250,1.5\n";
+        let yahoo = "Date,Open,High,Low,Close,Adj Close,Volume\n\
+            2020-01-01,7200,7300,7100,7250,7250,10875\n";
+
+        for (data, format) in &[
+            (kraken_ohlc, Format::KrakenOhlc),
+            (bitstamp, Format::Bitstamp),
+            (coinbase, Format::Coinbase),
+            (yahoo, Format::Yahoo),
+        ] {
+            let candles = read(data.as_bytes(), *format)?;
+            assert_eq!(vec![expected], candles, "{:?}", format);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_gzipped_kraken_trades_as_ticks() -> Result<()> {
+        let trades = "1577836860.5,7250.1,0.25\n1577836800,7200.0,0.1\n";
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(trades.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        let candles = read(compressed.as_slice(), Format::KrakenTrades)?;
+
+        assert_eq!(
+            vec![
+                Candle::from_tick(
+                    Utc.timestamp_opt(1_577_836_800, 0).unwrap(),
+                    Decimal::new(72000, 1),
+                    Decimal::new(1, 1),
+                ),
+                Candle::from_tick(
+                    Utc.timestamp_opt(1_577_836_860, 500_000_000).unwrap(),
+                    Decimal::new(72501, 1),
+                    Decimal::new(25, 2),
+                ),
+            ],
+            candles
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_resample_ticks_into_candles() {
+        let at =
+            |secs: i64| Utc.timestamp_opt(1_577_836_800 + secs, 0).unwrap();
+        let ticks = vec![
+            Candle::from_tick(at(0), Decimal::new(100, 0), Decimal::new(1, 0)),
+            Candle::from_tick(at(70), Decimal::new(120, 0), Decimal::new(2, 0)),
+            Candle::from_tick(at(200), Decimal::new(90, 0), Decimal::new(1, 0)),
+            Candle::from_tick(at(299), Decimal::new(95, 0), Decimal::new(1, 0)),
+            Candle::from_tick(at(300), Decimal::new(99, 0), Decimal::new(3, 0)),
+        ];
+
+        let candles = resample(&ticks, Interval::FiveMinutes);
+        assert_eq!(
+            vec![
+                Candle {
+                    time: at(0),
+                    open: Decimal::new(100, 0),
+                    high: Decimal::new(120, 0),
+                    low: Decimal::new(90, 0),
+                    close: Decimal::new(95, 0),
+                    volume: Decimal::new(5, 0),
+                },
+                Candle::from_tick(
+                    at(300),
+                    Decimal::new(99, 0),
+                    Decimal::new(3, 0)
+                ),
+            ],
+            candles
+        );
+
+        assert_eq!(5, resample(&ticks, Interval::OneMinute).len());
+        assert_eq!(1, resample(&ticks, Interval::OneHour).len());
+    }
+}
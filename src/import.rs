@@ -0,0 +1,395 @@
+//! Imports purchases made before we adopted the broker from the trade history
+//! exports of the exchanges, so that the seller can start managing them. Only
+//! bitcoin bought for dollars is imported, every other row is skipped.
+//!
+//! The rate of each purchase includes the fee paid for it, as the seller
+//! expects. The time, the ids and the fee of the original trade are kept in
+//! the purchase's metadata, which is tagged as imported.
+
+use {
+    chrono::{DateTime, NaiveDateTime, Utc},
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        fs::File,
+        io::{BufRead, BufReader, Read},
+        path::Path,
+        str::FromStr,
+    },
+};
+
+use crate::{
+    models::{Metadata, Purchase},
+    prelude::*,
+};
+
+/// Lists the export formats we know how to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Kraken trades export with header `txid,ordertxid,pair,time,type,
+    /// ordertype,price,cost,fee,vol,margin,misc,ledgers`. The fee is in
+    /// dollars.
+    KrakenTrades,
+    /// Kraken ledgers export with header `txid,refid,time,type,subtype,
+    /// aclass,asset,amount,fee,balance`. A trade is recorded as two entries
+    /// with the same refid, one for each asset, and each pays its own fee.
+    KrakenLedgers,
+    /// Coinbase transaction history report. The header `Timestamp,
+    /// Transaction Type,Asset,Quantity Transacted,Spot Price Currency,...,
+    /// Total (inclusive of fees and/or spread),...` is preceded by a few lines
+    /// about the user which are skipped.
+    Coinbase,
+}
+
+// Every imported purchase is tagged with this.
+const TAG: &str = "imported";
+
+/// Reads the file at given path in given format.
+pub fn load(path: impl AsRef<Path>, format: Format) -> Result<Vec<Purchase>> {
+    let file = File::open(path)?;
+    read(file, format)
+}
+
+/// Reads purchases in given format from any reader. The purchases are
+/// returned sorted by the time they were bought.
+pub fn read(reader: impl Read, format: Format) -> Result<Vec<Purchase>> {
+    let mut reader = BufReader::new(reader);
+    if format == Format::Coinbase {
+        skip_to_header(&mut reader, "Timestamp")?;
+    }
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+
+    let mut imported = match format {
+        Format::KrakenTrades => kraken_trades(&mut rdr)?,
+        Format::KrakenLedgers => kraken_ledgers(&mut rdr)?,
+        Format::Coinbase => coinbase(&mut rdr)?,
+    };
+
+    imported.sort_by_key(|purchase| purchase.metadata.bought_at);
+    Ok(imported)
+}
+
+// Coinbase puts a title and the details of the user before the header.
+fn skip_to_header(reader: &mut impl BufRead, header: &str) -> Result<()> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() || buf.starts_with(header.as_bytes()) {
+            return Ok(());
+        }
+        reader.read_line(&mut String::new())?;
+    }
+}
+
+#[derive(Deserialize)]
+struct KrakenTradeRow {
+    txid: String,
+    ordertxid: String,
+    pair: String,
+    time: String,
+    #[serde(rename = "type")]
+    kind: String,
+    cost: Cash,
+    fee: Cash,
+    vol: Btc,
+}
+
+fn kraken_trades(rdr: &mut csv::Reader<impl Read>) -> Result<Vec<Purchase>> {
+    let mut imported = Vec::new();
+    for row in rdr.deserialize::<KrakenTradeRow>() {
+        let row = row?;
+        if row.kind != "buy" || !is_kraken_btc_usd(&row.pair) {
+            continue;
+        }
+
+        let metadata = Metadata {
+            bought_at: Some(kraken_time(&row.time)?),
+            venue: Some("kraken".to_string()),
+            order_id: Some(row.ordertxid),
+            trade_id: Some(row.txid),
+            fee: row.fee,
+            tags: vec![TAG.to_string()],
+            liquidation: None,
+        };
+        imported.push(purchase(row.vol, row.cost + row.fee, metadata)?);
+    }
+
+    Ok(imported)
+}
+
+#[derive(Deserialize)]
+struct KrakenLedgerRow {
+    refid: String,
+    time: String,
+    #[serde(rename = "type")]
+    kind: String,
+    asset: String,
+    amount: Decimal,
+    fee: Decimal,
+}
+
+// Both sides of a trade in the ledger, matched by refid.
+#[derive(Default)]
+struct KrakenLedgerTrade {
+    time: Option<DateTime<Utc>>,
+    // Bitcoins we received after the fee.
+    btc: Btc,
+    // Dollars we paid including the fee.
+    spent: Cash,
+    // The fee paid in dollars and in bitcoins.
+    fee: (Cash, Btc),
+    // Whether an asset other than bitcoin and dollars was traded.
+    other_asset: bool,
+}
+
+fn kraken_ledgers(rdr: &mut csv::Reader<impl Read>) -> Result<Vec<Purchase>> {
+    let mut trades: HashMap<String, KrakenLedgerTrade> = HashMap::new();
+    for row in rdr.deserialize::<KrakenLedgerRow>() {
+        let row = row?;
+        // Purchases with the buy button are recorded as spend and receive.
+        if !["trade", "spend", "receive"].contains(&row.kind.as_str()) {
+            continue;
+        }
+
+        let trade = trades.entry(row.refid).or_default();
+        match row.asset.as_str() {
+            "XXBT" | "XBT" => {
+                trade.time = Some(kraken_time(&row.time)?);
+                trade.btc += row.amount - row.fee;
+                trade.fee.1 += row.fee;
+            }
+            "ZUSD" | "USD" => {
+                trade.spent -= row.amount - row.fee;
+                trade.fee.0 += row.fee;
+            }
+            _ => trade.other_asset = true,
+        }
+    }
+
+    let mut imported = Vec::new();
+    for (refid, trade) in trades {
+        // Sells and trades of other assets are skipped.
+        let bought = trade.btc > Btc::new(0, 0) && !trade.other_asset;
+        if !bought {
+            continue;
+        }
+
+        // The fee paid in bitcoins is valued at the rate of the trade.
+        let (fee_cash, fee_btc) = trade.fee;
+        let rate = trade.spent / (trade.btc + fee_btc);
+        let metadata = Metadata {
+            bought_at: trade.time,
+            venue: Some("kraken".to_string()),
+            order_id: None,
+            trade_id: Some(refid),
+            fee: fee_cash + fee_btc * rate,
+            tags: vec![TAG.to_string()],
+            liquidation: None,
+        };
+        imported.push(purchase(trade.btc, trade.spent, metadata)?);
+    }
+
+    Ok(imported)
+}
+
+#[derive(Deserialize)]
+struct CoinbaseRow {
+    // Only newer reports have ids.
+    #[serde(rename = "ID", default)]
+    id: Option<String>,
+    #[serde(rename = "Timestamp")]
+    timestamp: String,
+    #[serde(rename = "Transaction Type")]
+    kind: String,
+    #[serde(rename = "Asset")]
+    asset: String,
+    #[serde(rename = "Quantity Transacted")]
+    quantity: String,
+    #[serde(rename = "Spot Price Currency")]
+    currency: String,
+    #[serde(rename = "Total (inclusive of fees and/or spread)")]
+    total: String,
+    #[serde(rename = "Fees and/or Spread")]
+    fee: String,
+}
+
+fn coinbase(rdr: &mut csv::Reader<impl Read>) -> Result<Vec<Purchase>> {
+    let mut imported = Vec::new();
+    for row in rdr.deserialize::<CoinbaseRow>() {
+        let row = row?;
+        let is_buy = row.kind == "Buy" || row.kind == "Advanced Trade Buy";
+        if !is_buy || row.asset != "BTC" || row.currency != "USD" {
+            continue;
+        }
+
+        let metadata = Metadata {
+            bought_at: Some(coinbase_time(&row.timestamp)?),
+            venue: Some("coinbase".to_string()),
+            order_id: None,
+            trade_id: row.id,
+            fee: amount(&row.fee)?,
+            tags: vec![TAG.to_string()],
+            liquidation: None,
+        };
+        let btc = amount(&row.quantity)?;
+        imported.push(purchase(btc, amount(&row.total)?, metadata)?);
+    }
+
+    Ok(imported)
+}
+
+// The rate of the purchase includes the fee, that is all we spent.
+fn purchase(btc: Btc, spent: Cash, metadata: Metadata) -> Result<Purchase> {
+    if btc <= Btc::new(0, 0) {
+        return Err(Box::new(Error::invalid_data("Purchase of no bitcoins")));
+    }
+
+    Ok(Purchase::with_metadata(btc, spent / btc, metadata))
+}
+
+// Kraken calls bitcoin XBT and prefixes the older asset codes.
+fn is_kraken_btc_usd(pair: &str) -> bool {
+    pair == "XXBTZUSD" || pair == "XBTUSD" || pair == "XBT/USD"
+}
+
+// Kraken exports the time in UTC, e.g. "2020-05-20 18:45:10.1234".
+fn kraken_time(value: &str) -> Result<DateTime<Utc>> {
+    let time = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")?;
+    Ok(DateTime::from_naive_utc_and_offset(time, Utc))
+}
+
+// Older reports use RFC 3339, newer ones e.g. "2021-01-05 12:00:00 UTC".
+fn coinbase_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let time = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S UTC")?;
+    Ok(DateTime::from_naive_utc_and_offset(time, Utc))
+}
+
+// Newer Coinbase reports format dollars as e.g. "$1,002.50".
+fn amount(value: &str) -> Result<Decimal> {
+    let value: String =
+        value.chars().filter(|c| *c != '$' && *c != ',').collect();
+    Ok(Decimal::from_str(value.trim())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn should_import_purchases_with_fees_from_all_formats() -> Result<()> {
+        let kraken_trades = "\
+            \"txid\",\"ordertxid\",\"pair\",\"time\",\"type\",\"ordertype\",\
+            \"price\",\"cost\",\"fee\",\"vol\",\"margin\",\"misc\",\"ledgers\"\n\
+            \"T1\",\"O1\",\"XXBTZUSD\",\"2020-03-12 10:00:00.0000\",\"buy\",\
+            \"limit\",\"5000.0\",\"1000.00000\",\"2.60000\",\"0.20000000\",\
+            \"0.00000\",\"\",\"L1,L2\"\n\
+            \"T2\",\"O2\",\"XXBTZUSD\",\"2020-05-20 18:45:10.1234\",\"sell\",\
+            \"limit\",\"9000.0\",\"900.00000\",\"2.34000\",\"0.10000000\",\
+            \"0.00000\",\"\",\"L3,L4\"\n\
+            \"T3\",\"O3\",\"XETHZUSD\",\"2020-01-01 00:00:00.0000\",\"buy\",\
+            \"limit\",\"130.0\",\"130.00000\",\"0.34000\",\"1.00000000\",\
+            \"0.00000\",\"\",\"L5,L6\"\n\
+            \"T4\",\"O4\",\"XXBTZUSD\",\"2019-12-24 08:30:15.5000\",\"buy\",\
+            \"market\",\"7500.0\",\"3750.00000\",\"9.75000\",\"0.50000000\",\
+            \"0.00000\",\"\",\"L7,L8\"\n";
+
+        let kraken_ledgers = "\
+            \"txid\",\"refid\",\"time\",\"type\",\"subtype\",\"aclass\",\
+            \"asset\",\"amount\",\"fee\",\"balance\"\n\
+            \"L0\",\"D1\",\"2019-12-20 12:00:00\",\"deposit\",\"\",\"currency\",\
+            \"ZUSD\",\"5000.0000\",\"0.0000\",\"5000.0000\"\n\
+            \"L7\",\"T4\",\"2019-12-24 08:30:15.5\",\"trade\",\"\",\"currency\",\
+            \"ZUSD\",\"-3750.0000\",\"9.7500\",\"1240.2500\"\n\
+            \"L8\",\"T4\",\"2019-12-24 08:30:15.5\",\"trade\",\"\",\"currency\",\
+            \"XXBT\",\"0.5000000000\",\"0.0000000000\",\"0.5000000000\"\n\
+            \"L1\",\"T1\",\"2020-03-12 10:00:00\",\"spend\",\"\",\"currency\",\
+            \"ZUSD\",\"-1000.0000\",\"2.6000\",\"237.6500\"\n\
+            \"L2\",\"T1\",\"2020-03-12 10:00:00\",\"receive\",\"\",\"currency\",\
+            \"XXBT\",\"0.2000000000\",\"0.0000000000\",\"0.7000000000\"\n\
+            \"L3\",\"T2\",\"2020-05-20 18:45:10.1234\",\"trade\",\"\",\"currency\",\
+            \"XXBT\",\"-0.1000000000\",\"0.0000000000\",\"0.6000000000\"\n\
+            \"L4\",\"T2\",\"2020-05-20 18:45:10.1234\",\"trade\",\"\",\"currency\",\
+            \"ZUSD\",\"900.0000\",\"2.3400\",\"1135.3100\"\n";
+
+        let coinbase = "\
+            Transactions\n\
+            User,someone@example.com,abc123\n\
+            \n\
+            Timestamp,Transaction Type,Asset,Quantity Transacted,\
+            Spot Price Currency,Spot Price at Transaction,Subtotal,\
+            Total (inclusive of fees and/or spread),Fees and/or Spread,Notes\n\
+            2019-12-24T08:30:15Z,Buy,BTC,0.5,USD,7500.00,3750.00,3759.75,9.75,\
+            Bought 0.5 BTC\n\
+            2020-01-01 00:00:00 UTC,Buy,ETH,1,USD,$130.00,$130.00,$130.34,\
+            $0.34,Bought 1 ETH\n\
+            2020-03-12 10:00:00 UTC,Advanced Trade Buy,BTC,0.2,USD,\
+            \"$5,000.00\",\"$1,000.00\",\"$1,002.60\",$2.60,\n\
+            2020-05-20 18:45:10 UTC,Sell,BTC,0.1,USD,$9000.00,$900.00,\
+            $897.66,$2.34,Sold 0.1 BTC\n";
+
+        for (data, format, first_time) in &[
+            (
+                kraken_trades,
+                Format::KrakenTrades,
+                Utc.timestamp_opt(1_577_176_215, 500_000_000).unwrap(),
+            ),
+            (
+                kraken_ledgers,
+                Format::KrakenLedgers,
+                Utc.timestamp_opt(1_577_176_215, 500_000_000).unwrap(),
+            ),
+            (
+                coinbase,
+                Format::Coinbase,
+                Utc.timestamp_opt(1_577_176_215, 0).unwrap(),
+            ),
+        ] {
+            let imported = read(data.as_bytes(), *format)?;
+            let lots: Vec<_> = imported
+                .iter()
+                .map(|p| (p.metadata.bought_at, p.btc, p.rate, p.metadata.fee))
+                .collect();
+            assert_eq!(
+                vec![
+                    (
+                        Some(*first_time),
+                        Btc::new(5, 1),
+                        BtcExchangeRate::new(75195, 1),
+                        Cash::new(975, 2),
+                    ),
+                    (
+                        Utc.with_ymd_and_hms(2020, 3, 12, 10, 0, 0).single(),
+                        Btc::new(2, 1),
+                        BtcExchangeRate::new(5013, 0),
+                        Cash::new(26, 1),
+                    ),
+                ],
+                lots,
+                "{:?}",
+                format
+            );
+        }
+
+        let imported = read(kraken_trades.as_bytes(), Format::KrakenTrades)?;
+        assert_eq!(
+            Metadata {
+                bought_at: Utc
+                    .timestamp_opt(1_577_176_215, 500_000_000)
+                    .single(),
+                venue: Some("kraken".to_string()),
+                order_id: Some("O4".to_string()),
+                trade_id: Some("T4".to_string()),
+                fee: Cash::new(975, 2),
+                tags: vec!["imported".to_string()],
+                liquidation: None,
+            },
+            imported[0].metadata
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! # Bitcoin broker
+//! The broker app trades bitcoin over public APIs. See the design doc for more
+//! information about the algorithm.
+//!
+//! The high level organization of the code are actors. Each relevant part is
+//! wrapped into a thread that runs it. A communication between the threads is
+//! achieved with channels.
+//!
+//! ## Flow of information
+//!
+//! ```text
+//!    Kraken, Bitstamp, Coinbase
+//!
+//!   ||
+//!   ||  trades over WebSockets
+//!   \/
+//!
+//! +------  Trend ---------------------+
+//! | Computes the consensus rate of    |
+//! | the sources which agree and are   |
+//! | alive. Reports every reading to   |
+//! | the watchdog and circuit breaker. |
+//! +-----------------------------------+
+//!
+//!   ||
+//!   ||
+//!   \/
+//!
+//! +------  Seller --------------------+
+//! | Responsible for deciding which    |
+//! | purchases to sell under what      |
+//! | condition. Receives trend updates |
+//! | and settings from control agent.  |
+//! +-----------------------------------+
+//!
+//!   ||     /\
+//!   ||     ||  rejected offers
+//!   \/     ||
+//!
+//! +------  Risk manager --------------+
+//! | Checks each order against limits  |
+//! | on exposure, order size, order    |
+//! | rate and daily loss. Rejects all  |
+//! | orders while the circuit breaker  |
+//! | is tripped.                       |
+//! +-----------------------------------+
+//!
+//!   ||
+//!   ||
+//!   \/
+//!
+//! +------  Router --------------------+
+//! | Places each order at the exchange |
+//! | with the best rate after its fee  |
+//! | which holds the bitcoins. Tracks  |
+//! | which purchases each exchange     |
+//! | holds and periodically reconciles |
+//! | the wallet with the exchanges.    |
+//! +-----------------------------------+
+//!
+//!   ||
+//!   ||
+//!   \/
+//!
+//!    Bitstamp, Coinbase
+//! ```
+
+pub mod backtest;
+pub mod book;
+pub mod breaker;
+pub mod buyer;
+pub mod clock;
+pub mod history;
+pub mod import;
+pub mod marketplaces;
+pub mod models;
+pub mod prelude;
+pub mod pricing;
+pub mod reconcile;
+pub mod risk;
+pub mod router;
+pub mod seller;
+pub mod trend;
+pub mod volatility;
+pub mod wallet;
+pub mod watchdog;
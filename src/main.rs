@@ -1,41 +1,30 @@
-//! # Bitcoin broker
-//! The broker app trades bitcoin over public APIs. See the design doc for more
-//! information about the algorithm.
-//!
-//! The high level organization of the code are actors. Each relevant part is
-//! wrapped into a thread that runs it. A communication between the threads is
-//! achieved with channels.
-//!
-//! ## Flow of information
-//!
-//! ```text
-//!      ...
-//!
-//!   ||     /\
-//!   ||     ||
-//!   \/     ||
-//!
-//! +------  Seller --------------------+
-//! | Responsible for deciding which    |
-//! | purchases to sell under what      |
-//! | condition. Receives trend updates |
-//! | and settings from control agent.  |
-//! +-----------------------------------+
-//!
-//!   ||     /\
-//!   ||     ||
-//!   \/     ||
-//!
-//!     ...
-//! ```
+//! Runs the broker. See the library documentation for an overview of the
+//! actors and how they communicate.
 
-pub mod models;
-pub mod prelude;
-pub mod seller;
+use {
+    crossbeam_channel::unbounded,
+    std::{collections::HashSet, env, sync::Arc, thread, time::Duration},
+};
 
-use {crossbeam_channel::unbounded, std::thread};
-
-use {models::Fee, prelude::*};
+use broker::{
+    book::{self, OrderBook, SharedBook},
+    breaker::CircuitBreaker,
+    clock::SystemClock,
+    import::{self, Format},
+    marketplaces::{
+        bitstamp::Bitstamp, coinbase::Coinbase, Credentials, Marketplace,
+    },
+    models::{Fee, Liquidation},
+    prelude::*,
+    pricing::{self, Anchor, Expiry},
+    reconcile::{self, Reconciler},
+    risk::{self, Limits, RiskManager},
+    router::{self, Router, Venue},
+    seller::{self, HoldingPeriod},
+    trend::{self, Aggregator, Consensus, Source},
+    wallet::SharedWallet,
+    watchdog::{self, Watchdog},
+};
 
 fn main() {
     dotenv::dotenv().ok();
@@ -43,169 +32,250 @@ fn main() {
 
     // The input (receiver) into the seller actor sends updates of current trend
     // or threshold for minimum_margin.
-    let (_, seller_input) = unbounded();
+    let (seller_feedback, seller_input) = unbounded();
 
     // The output of the seller (sender) actor is an order to sell certain
-    // purchases.
-    let (seller_output, _) = unbounded();
-    let fee = Fee::Percentage(Percentage::new(25, 2));
-    let min_margin = Percentage::new(5, 0);
-    seller::spawn(seller_input, seller_output, fee, min_margin);
+    // purchases. It goes through the risk manager.
+    let (seller_output, risk_input) = unbounded();
 
-    loop {
-        thread::park();
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use {
-        crossbeam_channel::bounded,
-        rand::{thread_rng, Rng},
-        serde::Deserialize,
-        std::{collections::HashMap, time::Instant},
+    // Orders which pass the risk checks are routed to the marketplaces.
+    let (risk_output, router_input) = unbounded();
+    // Purchases are sold as soon as they make the margin.
+    let config = seller::Config {
+        fee: Fee::Percentage(Percentage::new(25, 2)),
+        min_margin: Percentage::new(5, 0),
+        holding_period: HoldingPeriod::default(),
+        trailing: None,
+        liquidation: Liquidation::default(),
+        max_impact: Some(Percentage::new(5, 1)),
+        volatility: None,
     };
+    let clock = Arc::new(SystemClock);
+    // The wallet is shared by all actors which place orders.
+    let wallet = SharedWallet::default();
+    // Halts trading of all actors when the market behaves abnormally.
+    let breaker = CircuitBreaker::default();
 
-    use {super::*, models::Purchase};
-
-    // Path to a CSV file which contains historical data of btc/$ exchange
-    // rates.
-    // The header of the file is following:
-    // `Date,Open,High,Low,Close,Adj Close,Volume`
-    const HISTORICAL_DATA_PATH: &str =
-        "tests/data/btc_usd_2019_02_01-2020_08_19.csv";
-
-    #[derive(Debug, Deserialize)]
-    #[serde(rename_all(deserialize = "PascalCase"))]
-    struct HistoricalRow {
-        date: String,
-        open: BtcExchangeRate,
-        high: BtcExchangeRate,
-        low: BtcExchangeRate,
-        close: BtcExchangeRate,
-        adj_close: BtcExchangeRate,
-        volume: usize,
-    }
+    // The trend actor reads trades of several exchanges and sends their
+    // consensus to the seller.
+    let sources = [Source::Kraken, Source::Bitstamp, Source::Coinbase];
+    let aggregator = Aggregator::new(trend::Config {
+        consensus: Consensus::VolumeWeighted,
+        max_divergence: Percentage::new(1, 0),
+        max_age: chrono::Duration::minutes(2),
+    });
 
-    // A naive test which runs the seller for a year while buyer buys bitcoin
-    // randomly every ~ 2 days.
-    //
-    // * We conservatively set the buying price closer to daily maximum and
-    // the selling price closer to daily minimum.
-    // * initial investment of $2k
-    // * We always buy BTC for $100.
-    // * We don't buy if we don't have resources.
-    // * Every second offer we place is not fulfilled.
-    // * We sell for market's average calculated with (high - low) / 2.
-    #[test]
-    fn seller_should_yield_profit_from_historical_data() -> Result<()> {
-        let fee = Fee::Percentage(Percentage::new(25, 2));
-        let min_margin = Percentage::new(10, 0);
-        let spending_per_purchase = Cash::new(250, 0);
-        // Everyday we roll a dice whether we buy or not.
-        let likelihood_of_purchase = 1.0 / 2.0;
-        // Some fraction of our offers won't be accepted straight away
-        let likelihood_of_offer_rejection = 1.0 / 2.0;
-        let investment = Cash::new(2_000, 0);
+    // The watchdog is told about every reading and pauses trading when the
+    // feed goes silent.
+    let (watchdog_input, watchdog_receiver) = unbounded();
+    let names: Vec<_> = sources.iter().map(|s| s.to_string()).collect();
+    let names: Vec<_> = names.iter().map(String::as_str).collect();
+    let watchdog = Watchdog::new(
+        &names,
+        chrono::Duration::minutes(2),
+        clock.clone(),
+        breaker.clone(),
+    );
+    watchdog::spawn(watchdog_receiver, watchdog, Duration::from_secs(10));
+    trend::spawn(
+        sources.iter().map(|s| (*s, s.url().to_string())).collect(),
+        seller_feedback.clone(),
+        watchdog_input,
+        risk_output.clone(),
+        breaker.clone(),
+        aggregator,
+        clock.clone(),
+    );
 
-        // env_logger::init();
-        let mut rng = thread_rng();
-        let (channel_in, seller_input) = bounded(0);
-        let (seller_output, channel_out) = bounded(5);
-        seller::spawn(seller_input, seller_output, fee, min_margin);
-
-        let mut btc = Btc::new(0, 0);
-        let mut cash = investment;
-
-        // How much money have we made each month.
-        let mut monthly_margin: HashMap<&str, Cash> =
-            HashMap::with_capacity(24);
-
-        let historical_data = load_historical_data();
-        for row in &historical_data {
-            let HistoricalRow {
-                date, high, low, ..
-            } = row;
-            let avg = high + low / Decimal::new(2, 0);
-
-            // Updates the current trend. We sell for value slightly below
-            // market average to be conservative.
-            channel_in.send(seller::Message::TrendReading {
-                current_trend: avg - (avg - low) / Decimal::new(2, 0),
-                observed_at: Instant::now(),
-            })?;
-
-            // Every now and then we buy some bitcoins without thinking for
-            // a random price between daily average and daily high.
-            if rng.gen_bool(likelihood_of_purchase) {
-                let rate = avg + (high - avg) / Decimal::new(2, 0);
-                let btc_to_buy = spending_per_purchase / rate;
-                let purchase = Purchase::new(btc_to_buy, rate);
-                channel_in.send(seller::Message::NewPurchase(purchase))?;
+    let limits = Limits {
+        max_exposure: Btc::new(1, 0),
+        max_order_notional: Cash::new(5_000, 0),
+        max_orders_per_hour: 10,
+        daily_loss_cap: Cash::new(200, 0),
+    };
+    let manager = RiskManager::new(
+        limits,
+        clock.clone(),
+        wallet.clone(),
+        breaker.clone(),
+    );
+    // Deposits of imported purchases go straight to the router.
+    let deposits = risk_output.clone();
+    // No buyer runs yet, the risk manager releases the cash of rejected bids.
+    risk::spawn(
+        risk_input,
+        risk_output,
+        seller_feedback.clone(),
+        None,
+        manager,
+    );
+    // Touching the kill file cancels all orders and halts trading, removing
+    // it resumes trading.
+    let kill_file =
+        env::var("KILL_FILE").unwrap_or_else(|_| "broker.kill".to_string());
+    risk::watch(
+        kill_file.into(),
+        seller_output.clone(),
+        breaker.clone(),
+        Duration::from_secs(1),
+    );
 
-                btc += btc_to_buy;
-                cash -= spending_per_purchase;
+    // We trade at the exchanges we have credentials for.
+    let venues = vec![
+        venue("bitstamp", Percentage::new(5, 1), |credentials| {
+            Box::new(Bitstamp::new(Bitstamp::URL, credentials))
+        }),
+        venue("coinbase", Percentage::new(6, 1), |credentials| {
+            Box::new(Coinbase::new(Coinbase::URL, credentials))
+        }),
+    ];
+    // Offers ask a little over the trend, so that we pay the maker fee. Those
+    // not filled within ten minutes are placed again at the current price.
+    let pricing = pricing::Config {
+        anchor: Anchor::Trend,
+        premium: Percentage::new(1, 1),
+        timeout: Some(chrono::Duration::minutes(10)),
+        expiry: Expiry::Reprice,
+        ladder: Vec::new(),
+    };
+    if let Err(e) = pricing.validate() {
+        log::error!("Invalid pricing: {}", e);
+        return;
+    }
+    let mut router = Router::new(
+        venues.into_iter().flatten().collect(),
+        pricing,
+        clock.clone(),
+        wallet.clone(),
+    );
+    // The wallet is compared with the exchanges every hour. Trades we don't
+    // know of are only flagged, they need to be checked by hand.
+    let reconciler = Reconciler::new(
+        reconcile::Config {
+            interval: chrono::Duration::hours(1),
+            tolerance: Decimal::new(1, 4),
+            import: false,
+        },
+        clock.clone(),
+        wallet.clone(),
+    );
+    // The wallet starts with what the exchanges hold, otherwise nothing could
+    // be reserved for the orders.
+    let seeded = match reconciler.seed(&mut router) {
+        Ok(balances) => {
+            log::info!(
+                "Starting with ${} and {} BTC",
+                balances.cash,
+                balances.btc
+            );
+            true
+        }
+        Err(e) => {
+            log::error!("Cannot seed the wallet: {}", e);
+            false
+        }
+    };
+    router::spawn(
+        router_input,
+        seller_feedback.clone(),
+        seller_output.clone(),
+        router,
+        reconciler,
+    );
+    // Purchases made before we adopted the broker are imported from the trade
+    // history exports given in the environment, e.g. KRAKEN_TRADES_CSV.
+    // The same trade can be in several exports, e.g. both Kraken's trades and
+    // ledgers, and is imported only once.
+    let mut trade_ids = HashSet::new();
+    for (format, var) in &[
+        (Format::KrakenTrades, "KRAKEN_TRADES_CSV"),
+        (Format::KrakenLedgers, "KRAKEN_LEDGERS_CSV"),
+        (Format::Coinbase, "COINBASE_REPORT_CSV"),
+    ] {
+        let path = if let Ok(path) = env::var(var) {
+            path
+        } else {
+            continue;
+        };
+        let imported = match import::load(&path, *format) {
+            Ok(imported) => imported,
+            Err(e) => {
+                log::error!("Cannot import {}: {}", path, e);
+                continue;
             }
-
-            // We do this here to make sure every month is represented in the
-            // hash map. This prevents incorrect average monhtly margin
-            // calculation.
-            let yyyy_mm_len = "2020-01".len();
-            let margin_this_month =
-                monthly_margin.entry(&date[0..yyyy_mm_len]).or_default();
-
-            // Evaluates attempts to sell the bitcoins.
-            while let Ok(offer) = channel_out.try_recv() {
-                // Every second offer on the marketplace expires and is never
-                // fulfilled.
-                if rng.gen_bool(likelihood_of_offer_rejection) {
-                    for purchase in offer.purchases {
-                        cash += purchase.btc * offer.rate;
-                        btc -= purchase.btc;
-
-                        *margin_this_month +=
-                            purchase.margin_after_fee(offer.rate, fee);
-                    }
-                } else {
-                    for purchase in offer.purchases {
-                        channel_in
-                            .send(seller::Message::NewPurchase(purchase))?;
-                    }
+        };
+        let imported: Vec<_> = imported
+            .into_iter()
+            .filter(|purchase| match &purchase.metadata.trade_id {
+                Some(trade_id) => trade_ids.insert(trade_id.clone()),
+                None => true,
+            })
+            .collect();
+        log::info!("Imported {} purchases from {}", imported.len(), path);
+        for purchase in imported {
+            // The balances the wallet was seeded with already include the
+            // bitcoins we still hold, the cash was spent before.
+            if !seeded {
+                wallet.lock().settle_buy(
+                    purchase.id,
+                    Cash::new(0, 0),
+                    purchase.btc,
+                );
+            }
+            // The router learns where the bitcoins are held, so that it
+            // sells them there.
+            if let Some(venue) = purchase.metadata.venue.clone() {
+                let deposit = router::Message::Deposit {
+                    venue,
+                    purchase: purchase.id,
+                    btc: purchase.btc,
+                };
+                if deposits.send(deposit).is_err() {
+                    log::error!("The router's input channel died");
                 }
             }
+            let purchase = seller::Message::NewPurchase(purchase);
+            // We still hold the seller's input, so the channel cannot be
+            // disconnected.
+            seller_feedback.send(purchase).unwrap();
         }
+    }
+    // The seller follows Kraken's book and doesn't offer more bitcoins than
+    // its bids absorb within the max impact. We don't trade at Kraken, its
+    // book is the deepest and stands in for those of Bitstamp and Coinbase.
+    let book = SharedBook::new(OrderBook::new(25));
+    book::spawn(book::URL.to_string(), book.clone());
+    seller::spawn(
+        seller_input,
+        seller_output,
+        config,
+        clock,
+        wallet,
+        breaker,
+        Some(book),
+    );
 
-        let total_monthly_margin = monthly_margin
-            .iter()
-            .fold(Cash::new(0, 0), |cash, (_, margin)| cash + margin);
-        let avg_monthly_margin =
-            total_monthly_margin / Cash::from(monthly_margin.len());
-        let monthly_margin: Vec<_> = {
-            let mut v: Vec<_> = monthly_margin.into_iter().collect();
-            v.sort_by(|(date, _), (date2, _)| date.cmp(date2));
-            v.into_iter()
-                .map(|(date, margin)| {
-                    format!("[{}] ${}", date, margin.round_dp(2))
-                })
-                .collect()
-        };
-        println!(
-            "Monthly margin: \n{:#?} \n(total ${}, avg ${})",
-            monthly_margin,
-            total_monthly_margin.round_dp(2),
-            avg_monthly_margin.round_dp(2),
-        );
-        println!(
-            "Ended up with {} BTC and net ${}.",
-            btc.round_dp(6),
-            (cash - investment).round_dp(2)
-        );
-
-        Ok(())
+    loop {
+        thread::park();
     }
+}
 
-    fn load_historical_data() -> Vec<HistoricalRow> {
-        let mut rdr = csv::Reader::from_path(HISTORICAL_DATA_PATH).unwrap();
-        rdr.deserialize().map(|r| r.unwrap()).collect()
+// Connects to the exchange if its key and secret are in the environment, e.g.
+// BITSTAMP_KEY and BITSTAMP_SECRET.
+fn venue(
+    name: &str,
+    fee: Percentage,
+    marketplace: impl FnOnce(Credentials) -> Box<dyn Marketplace>,
+) -> Option<Venue> {
+    let prefix = name.to_uppercase();
+    let key = env::var(format!("{}_KEY", prefix)).ok()?;
+    let secret = env::var(format!("{}_SECRET", prefix)).ok()?;
+    let marketplace = marketplace(Credentials { key, secret });
+    match Venue::new(name, marketplace, Fee::Percentage(fee)) {
+        Ok(venue) => Some(venue),
+        Err(e) => {
+            log::error!("Cannot trade at {}: {}", name, e);
+            None
+        }
     }
 }
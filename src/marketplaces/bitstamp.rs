@@ -0,0 +1,358 @@
+//! Bindings to the Bitstamp v2 API. All private endpoints are POST requests
+//! with a form encoded body, signed with the v2 authentication headers. The
+//! public ticker is a plain GET request.
+
+use {
+    attohttpc::Method,
+    chrono::{DateTime, NaiveDateTime, Utc},
+    serde_json::Value,
+    uuid::Uuid,
+};
+
+use super::{
+    decimal, hmac_sha256, invalid, rejected, string, Credentials, Execution,
+    Marketplace, OrderStatus,
+};
+use crate::{
+    models::{Balances, Bid, Offer, Side},
+    prelude::*,
+    pricing::TopOfBook,
+};
+
+const PAIR: &str = "btcusd";
+const CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+// The type of user transactions which are trades.
+const MARKET_TRADE: &str = "2";
+// The most transactions Bitstamp returns at once.
+const PAGE_LIMIT: usize = 1000;
+
+/// Authenticated client of the v2 API.
+pub struct Bitstamp {
+    base_url: String,
+    credentials: Credentials,
+}
+
+impl Bitstamp {
+    /// The production API.
+    pub const URL: &'static str = "https://www.bitstamp.net";
+
+    pub fn new(base_url: impl Into<String>, credentials: Credentials) -> Self {
+        Self {
+            base_url: base_url.into(),
+            credentials,
+        }
+    }
+
+    // Sends a signed POST request with given form fields and parses the JSON
+    // response. Bitstamp reports some errors with a success status, those are
+    // turned into errors as well.
+    fn request(&self, path: &str, form: &[(&str, String)]) -> Result<Value> {
+        let path = format!("/api/v2{}", path);
+        let body = form
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        // The content type is only signed when there is a body.
+        let content_type = if body.is_empty() { "" } else { CONTENT_TYPE };
+        let nonce = Uuid::new_v4().to_string();
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let host = self
+            .base_url
+            .splitn(2, "://")
+            .last()
+            .unwrap_or(&self.base_url);
+        // The query would follow the path, but we send none.
+        let message = format!(
+            "BITSTAMP {}{}{}{}{}{}{}v2{}",
+            self.credentials.key,
+            Method::POST,
+            host,
+            path,
+            content_type,
+            nonce,
+            timestamp,
+            body
+        );
+        let signature = hex::encode(hmac_sha256(
+            self.credentials.secret.as_bytes(),
+            message.as_bytes(),
+        ));
+
+        let request = attohttpc::post(format!("{}{}", self.base_url, path))
+            .header("X-Auth", format!("BITSTAMP {}", self.credentials.key))
+            .header("X-Auth-Signature", signature)
+            .header("X-Auth-Nonce", nonce)
+            .header("X-Auth-Timestamp", timestamp)
+            .header("X-Auth-Version", "v2");
+        let response = if body.is_empty() {
+            request.send()?
+        } else {
+            request
+                .header("Content-Type", content_type)
+                .text(body)
+                .send()?
+        };
+        let status = response.status();
+        let text = response.text()?;
+        if !status.is_success() {
+            return Err(rejected("Bitstamp", format!("{} {}", status, text)));
+        }
+
+        let response: Value = serde_json::from_str(&text)?;
+        if response["status"].as_str() == Some("error") {
+            return Err(rejected("Bitstamp", &response["reason"]));
+        }
+
+        Ok(response)
+    }
+
+    fn place(
+        &self,
+        side: &str,
+        client_order_id: Uuid,
+        btc: Btc,
+        rate: BtcExchangeRate,
+    ) -> Result<String> {
+        let path = format!("/{}/{}/", side, PAIR);
+        let form = [
+            ("amount", btc.round_dp(8).to_string()),
+            ("price", rate.round_dp(2).to_string()),
+            ("client_order_id", client_order_id.to_string()),
+        ];
+        let response = self.request(&path, &form)?;
+        id(&response["id"])
+    }
+}
+
+impl Marketplace for Bitstamp {
+    fn balances(&mut self) -> Result<Balances> {
+        let response = self.request(&format!("/balance/{}/", PAIR), &[])?;
+        Ok(Balances {
+            cash: decimal(&response["usd_balance"])?,
+            btc: decimal(&response["btc_balance"])?,
+        })
+    }
+
+    fn place_offer(&mut self, offer: &Offer) -> Result<String> {
+        self.place("sell", offer.id, offer.btc(), offer.rate)
+    }
+
+    fn place_bid(&mut self, bid: &Bid, btc: Btc) -> Result<String> {
+        self.place("buy", bid.id, btc, bid.rate)
+    }
+
+    fn cancel(&mut self, order_id: &str) -> Result<()> {
+        self.request("/cancel_order/", &[("id", order_id.to_string())])?;
+        Ok(())
+    }
+
+    fn order_status(&mut self, order_id: &str) -> Result<OrderStatus> {
+        let response =
+            self.request("/order_status/", &[("id", order_id.to_string())])?;
+        let transactions = response["transactions"].as_array();
+        let filled = transactions
+            .into_iter()
+            .flatten()
+            .map(|transaction| decimal(&transaction["btc"]))
+            .sum::<Result<Btc>>()?;
+
+        // Maps the Bitstamp order states into our lifecycle.
+        match string(&response["status"])? {
+            "Open" => Ok(OrderStatus::Open { filled }),
+            "Finished" => Ok(OrderStatus::Filled),
+            "Canceled" | "Expired" => Ok(OrderStatus::Cancelled { filled }),
+            status => Err(Box::new(Error::invalid_data(format!(
+                "Unknown order status {}",
+                status
+            )))),
+        }
+    }
+
+    fn fills(&mut self, since: DateTime<Utc>) -> Result<Vec<Execution>> {
+        let path = format!("/user_transactions/{}/", PAIR);
+        let mut executions = Vec::new();
+        // The transactions come in pages, we read them until one isn't full.
+        for page in 0.. {
+            let form = [
+                ("since_timestamp", since.timestamp().to_string()),
+                ("sort", "asc".to_string()),
+                ("limit", PAGE_LIMIT.to_string()),
+                ("offset", (page * PAGE_LIMIT).to_string()),
+            ];
+            let response = self.request(&path, &form)?;
+            let transactions = response
+                .as_array()
+                .ok_or_else(|| invalid("Expected a list of transactions"))?;
+
+            for transaction in transactions {
+                // Deposits, withdrawals and transfers are not our trades.
+                if transaction["type"].as_str() != Some(MARKET_TRADE) {
+                    continue;
+                }
+
+                // Sells are recorded with negative bitcoins.
+                let btc = decimal(&transaction["btc"])?;
+                executions.push(Execution {
+                    trade_id: id(&transaction["id"])?,
+                    order_id: id(&transaction["order_id"])?,
+                    side: if btc < Btc::new(0, 0) {
+                        Side::Sell
+                    } else {
+                        Side::Buy
+                    },
+                    btc: btc.abs(),
+                    rate: decimal(&transaction["btc_usd"])?,
+                    fee: decimal(&transaction["fee"])?,
+                    time: time(&transaction["datetime"])?,
+                });
+            }
+
+            if transactions.len() < PAGE_LIMIT {
+                break;
+            }
+        }
+        executions.sort_by_key(|execution| execution.time);
+
+        Ok(executions)
+    }
+
+    fn top_of_book(&mut self) -> Result<TopOfBook> {
+        let url = format!("{}/api/v2/ticker/{}/", self.base_url, PAIR);
+        let response = attohttpc::get(url).send()?;
+        let status = response.status();
+        let text = response.text()?;
+        if !status.is_success() {
+            return Err(rejected("Bitstamp", format!("{} {}", status, text)));
+        }
+
+        let ticker: Value = serde_json::from_str(&text)?;
+        Ok(TopOfBook {
+            bid: decimal(&ticker["bid"])?,
+            ask: decimal(&ticker["ask"])?,
+        })
+    }
+}
+
+// Ids are sent as numbers by some endpoints and as strings by others.
+fn id(value: &Value) -> Result<String> {
+    match value {
+        Value::Number(id) => Ok(id.to_string()),
+        Value::String(id) => Ok(id.clone()),
+        _ => Err(invalid("Expected an id")),
+    }
+}
+
+// Times are in UTC without a timezone, e.g. "2020-05-20 18:45:10.000000".
+fn time(value: &Value) -> Result<DateTime<Utc>> {
+    let time =
+        NaiveDateTime::parse_from_str(string(value)?, "%Y-%m-%d %H:%M:%S%.f")?;
+    Ok(DateTime::from_naive_utc_and_offset(time, Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{marketplaces::mock::Server, models::Purchase};
+
+    fn server() -> Server {
+        Server::start(&[
+            ("POST /api/v2/balance/btcusd/", "bitstamp/balance.json"),
+            ("POST /api/v2/sell/btcusd/", "bitstamp/sell.json"),
+            ("POST /api/v2/buy/btcusd/", "bitstamp/buy_error.json"),
+            ("POST /api/v2/order_status/", "bitstamp/order_status.json"),
+            ("POST /api/v2/cancel_order/", "bitstamp/cancel_order.json"),
+            (
+                "POST /api/v2/user_transactions/btcusd/",
+                "bitstamp/user_transactions.json",
+            ),
+            ("GET /api/v2/ticker/btcusd/", "bitstamp/ticker.json"),
+        ])
+    }
+
+    #[test]
+    fn should_trade_against_mock() -> Result<()> {
+        let server = server();
+        let credentials = Credentials {
+            key: "key".to_string(),
+            secret: "secret".to_string(),
+        };
+        let mut bitstamp = Bitstamp::new(server.url.as_str(), credentials);
+
+        assert_eq!(
+            Balances {
+                cash: Cash::new(150050, 2),
+                btc: Btc::new(125, 2),
+            },
+            bitstamp.balances()?
+        );
+
+        let purchase =
+            Purchase::new(Btc::new(5, 1), BtcExchangeRate::new(9000, 0));
+        let offer = Offer::new(BtcExchangeRate::new(10000, 0), vec![purchase]);
+        let order_id = bitstamp.place_offer(&offer)?;
+        assert_eq!("1234567890", order_id);
+
+        let bid = Bid::new(Cash::new(100, 0), BtcExchangeRate::new(9000, 0));
+        let error = bitstamp.place_bid(&bid, Btc::new(1, 2)).unwrap_err();
+        assert!(error.to_string().contains("You need 100.00 USD"));
+
+        assert_eq!(
+            OrderStatus::Open {
+                filled: Btc::new(2, 1)
+            },
+            bitstamp.order_status(&order_id)?
+        );
+        bitstamp.cancel(&order_id)?;
+
+        let since = Utc.with_ymd_and_hms(2020, 5, 1, 0, 0, 0).unwrap();
+        let fills = bitstamp.fills(since)?;
+        assert_eq!(
+            vec![Execution {
+                trade_id: "98765".to_string(),
+                order_id: "1234567890".to_string(),
+                side: Side::Sell,
+                btc: Btc::new(2, 1),
+                rate: BtcExchangeRate::new(10000, 0),
+                fee: Cash::new(5, 0),
+                time: Utc.with_ymd_and_hms(2020, 5, 20, 18, 45, 10).unwrap(),
+            }],
+            fills
+        );
+
+        let requests = server.requests();
+        let sell = &requests[1];
+        assert_eq!(
+            format!("amount=0.5&price=10000&client_order_id={}", offer.id),
+            sell.body
+        );
+        let message = format!(
+            "BITSTAMP keyPOST{}/api/v2/sell/btcusd/{}{}{}v2{}",
+            server.url.trim_start_matches("http://"),
+            CONTENT_TYPE,
+            sell.headers["x-auth-nonce"],
+            sell.headers["x-auth-timestamp"],
+            sell.body
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(b"secret", message.as_bytes())),
+            sell.headers["x-auth-signature"]
+        );
+        // Requests without a body don't sign the content type.
+        assert!(!requests[0].headers.contains_key("content-type"));
+        // The transactions fit a single page.
+        assert!(requests[5].body.ends_with("&limit=1000&offset=0"));
+
+        assert_eq!(
+            TopOfBook {
+                bid: BtcExchangeRate::new(920005, 2),
+                ask: BtcExchangeRate::new(9201, 0),
+            },
+            bitstamp.top_of_book()?
+        );
+
+        Ok(())
+    }
+}
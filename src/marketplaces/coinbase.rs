@@ -0,0 +1,521 @@
+//! Bindings to the Coinbase Advanced Trade API. Orders, balances and fills go
+//! over the authenticated REST API, the ticker and the updates of our orders
+//! can be followed over the WebSocket feed. The broker doesn't follow the
+//! feed yet: the trend reads Coinbase's trades on its own and the router
+//! polls the status of our orders.
+
+use {
+    attohttpc::Method,
+    chrono::{DateTime, SecondsFormat, Utc},
+    serde_json::{json, Value},
+    tungstenite::{client::AutoStream, Message as WsMessage, WebSocket},
+};
+
+use super::{
+    decimal, hmac_sha256, invalid, rejected, string, Credentials, Execution,
+    Marketplace, OrderStatus,
+};
+use crate::{
+    models::{Balances, Bid, Offer, Side},
+    prelude::*,
+    pricing::TopOfBook,
+};
+
+const PRODUCT_ID: &str = "BTC-USD";
+const API: &str = "/api/v3/brokerage";
+
+/// Authenticated client of the REST API.
+pub struct Coinbase {
+    base_url: String,
+    credentials: Credentials,
+}
+
+/// An update pushed over the WebSocket feed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    /// The last trade of BTC-USD.
+    Ticker {
+        rate: BtcExchangeRate,
+        observed_at: DateTime<Utc>,
+    },
+    /// One of our orders changed.
+    Order {
+        order_id: String,
+        /// The id we gave the order, which is the offer's or bid's id.
+        client_order_id: String,
+        status: OrderStatus,
+    },
+}
+
+/// The WebSocket feed of the ticker and our orders.
+pub struct Feed {
+    socket: WebSocket<AutoStream>,
+}
+
+impl Coinbase {
+    /// The production REST API.
+    pub const URL: &'static str = "https://api.coinbase.com";
+
+    pub fn new(base_url: impl Into<String>, credentials: Credentials) -> Self {
+        Self {
+            base_url: base_url.into(),
+            credentials,
+        }
+    }
+
+    // Sends a request signed with the CB-ACCESS headers and parses the JSON
+    // response.
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value> {
+        let path = format!("{}{}", API, path);
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(
+            &self.credentials.secret,
+            &format!("{}{}{}{}", timestamp, method, path, body),
+        );
+
+        let response = attohttpc::RequestBuilder::new(
+            method,
+            format!("{}{}", self.base_url, path),
+        )
+        .header("CB-ACCESS-KEY", self.credentials.key.as_str())
+        .header("CB-ACCESS-SIGN", signature)
+        .header("CB-ACCESS-TIMESTAMP", timestamp)
+        .header("Content-Type", "application/json")
+        .text(body)
+        .send()?;
+        let status = response.status();
+        let text = response.text()?;
+        if !status.is_success() {
+            return Err(rejected("Coinbase", format!("{} {}", status, text)));
+        }
+
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    // Sells are post only, so that Coinbase rejects them rather than let them
+    // take liquidity at the taker fee. Bids may cross the spread.
+    fn place(
+        &self,
+        client_order_id: String,
+        side: &str,
+        btc: Btc,
+        rate: BtcExchangeRate,
+    ) -> Result<String> {
+        let order = json!({
+            "client_order_id": client_order_id,
+            "product_id": PRODUCT_ID,
+            "side": side,
+            "order_configuration": {
+                "limit_limit_gtc": {
+                    "base_size": btc.round_dp(8).to_string(),
+                    "limit_price": rate.round_dp(2).to_string(),
+                    "post_only": side == "SELL",
+                }
+            }
+        });
+        let response = self.request(Method::POST, "/orders", Some(order))?;
+        if response["success"].as_bool() != Some(true) {
+            let error = &response["error_response"];
+            return Err(rejected("Coinbase", &error["message"]));
+        }
+
+        Ok(string(&response["success_response"]["order_id"])?.to_string())
+    }
+}
+
+impl Marketplace for Coinbase {
+    fn balances(&mut self) -> Result<Balances> {
+        let response = self.request(Method::GET, "/accounts", None)?;
+        let accounts = response["accounts"]
+            .as_array()
+            .ok_or_else(|| invalid("No accounts in response"))?;
+
+        let mut balances = Balances::default();
+        for account in accounts {
+            let total = decimal(&account["available_balance"]["value"])?
+                + decimal(&account["hold"]["value"])?;
+            match account["currency"].as_str() {
+                Some("USD") => balances.cash += total,
+                Some("BTC") => balances.btc += total,
+                _ => (),
+            }
+        }
+
+        Ok(balances)
+    }
+
+    fn place_offer(&mut self, offer: &Offer) -> Result<String> {
+        self.place(offer.id.to_string(), "SELL", offer.btc(), offer.rate)
+    }
+
+    fn place_bid(&mut self, bid: &Bid, btc: Btc) -> Result<String> {
+        self.place(bid.id.to_string(), "BUY", btc, bid.rate)
+    }
+
+    fn cancel(&mut self, order_id: &str) -> Result<()> {
+        let body = json!({ "order_ids": [order_id] });
+        let response =
+            self.request(Method::POST, "/orders/batch_cancel", Some(body))?;
+        let result = &response["results"][0];
+        if result["success"].as_bool() != Some(true) {
+            return Err(rejected("Coinbase", &result["failure_reason"]));
+        }
+
+        Ok(())
+    }
+
+    fn order_status(&mut self, order_id: &str) -> Result<OrderStatus> {
+        let path = format!("/orders/historical/{}", order_id);
+        let response = self.request(Method::GET, &path, None)?;
+        let order = &response["order"];
+        status(string(&order["status"])?, decimal(&order["filled_size"])?)
+    }
+
+    fn fills(&mut self, since: DateTime<Utc>) -> Result<Vec<Execution>> {
+        let mut executions = Vec::new();
+        // The fills come in pages, each tells the cursor of the next one.
+        let mut cursor = String::new();
+        loop {
+            let mut path = format!(
+                "/orders/historical/fills?product_id={}&start_sequence_timestamp={}",
+                PRODUCT_ID,
+                since.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+            if !cursor.is_empty() {
+                path.push_str(&format!("&cursor={}", cursor));
+            }
+            let response = self.request(Method::GET, &path, None)?;
+            let fills = response["fills"]
+                .as_array()
+                .ok_or_else(|| invalid("No fills in response"))?;
+            for fill in fills {
+                executions.push(Execution {
+                    trade_id: string(&fill["trade_id"])?.to_string(),
+                    order_id: string(&fill["order_id"])?.to_string(),
+                    side: match string(&fill["side"])? {
+                        "BUY" => Side::Buy,
+                        _ => Side::Sell,
+                    },
+                    btc: decimal(&fill["size"])?,
+                    rate: decimal(&fill["price"])?,
+                    fee: decimal(&fill["commission"])?,
+                    time: time(&fill["trade_time"])?,
+                });
+            }
+
+            cursor = response["cursor"].as_str().unwrap_or_default().into();
+            if cursor.is_empty() || fills.is_empty() {
+                break;
+            }
+        }
+        executions.sort_by_key(|execution| execution.time);
+
+        Ok(executions)
+    }
+
+    fn top_of_book(&mut self) -> Result<TopOfBook> {
+        let path = format!("/best_bid_ask?product_ids={}", PRODUCT_ID);
+        let response = self.request(Method::GET, &path, None)?;
+        let book = response["pricebooks"]
+            .as_array()
+            .and_then(|books| books.first())
+            .ok_or_else(|| invalid("Expected the book of BTC-USD"))?;
+        let best = |side: &str| -> Result<BtcExchangeRate> {
+            let level = book[side]
+                .as_array()
+                .and_then(|levels| levels.first())
+                .ok_or_else(|| invalid("Expected a level of the book"))?;
+            decimal(&level["price"])
+        };
+        Ok(TopOfBook {
+            bid: best("bids")?,
+            ask: best("asks")?,
+        })
+    }
+}
+
+impl Feed {
+    /// The production WebSocket feed.
+    pub const URL: &'static str = "wss://advanced-trade-ws.coinbase.com";
+
+    /// Connects to the feed and subscribes to the BTC-USD ticker and to the
+    /// updates of our orders.
+    pub fn connect(url: &str, credentials: &Credentials) -> Result<Self> {
+        let (mut socket, _) = tungstenite::connect(url)?;
+        for channel in &["ticker", "user"] {
+            let timestamp = Utc::now().timestamp().to_string();
+            let signature = sign(
+                &credentials.secret,
+                &format!("{}{}{}", timestamp, channel, PRODUCT_ID),
+            );
+            let subscription = json!({
+                "type": "subscribe",
+                "product_ids": [PRODUCT_ID],
+                "channel": channel,
+                "api_key": credentials.key,
+                "timestamp": timestamp,
+                "signature": signature,
+            });
+            socket.write_message(WsMessage::Text(subscription.to_string()))?;
+        }
+
+        Ok(Self { socket })
+    }
+
+    /// Blocks until the next message arrives and returns its updates.
+    pub fn read(&mut self) -> Result<Vec<Update>> {
+        loop {
+            match self.socket.read_message()? {
+                WsMessage::Text(text) => return parse(&text),
+                WsMessage::Close(_) => {
+                    return Err(invalid("Coinbase closed the feed"));
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Parses a message of the WebSocket feed. Messages of other channels, such
+/// as heartbeats or subscription confirmations, yield no updates.
+pub fn parse(message: &str) -> Result<Vec<Update>> {
+    let message: Value = serde_json::from_str(message)?;
+    let events = message["events"].as_array().cloned().unwrap_or_default();
+
+    let mut updates = Vec::new();
+    match message["channel"].as_str() {
+        Some("ticker") => {
+            let observed_at = time(&message["timestamp"])?;
+            for event in &events {
+                let tickers = event["tickers"].as_array();
+                for ticker in tickers.into_iter().flatten() {
+                    updates.push(Update::Ticker {
+                        rate: decimal(&ticker["price"])?,
+                        observed_at,
+                    });
+                }
+            }
+        }
+        Some("user") => {
+            for event in &events {
+                let orders = event["orders"].as_array();
+                for order in orders.into_iter().flatten() {
+                    let filled = decimal(&order["cumulative_quantity"])?;
+                    updates.push(Update::Order {
+                        order_id: string(&order["order_id"])?.to_string(),
+                        client_order_id: string(&order["client_order_id"])?
+                            .to_string(),
+                        status: status(string(&order["status"])?, filled)?,
+                    });
+                }
+            }
+        }
+        _ => (),
+    }
+
+    Ok(updates)
+}
+
+// Maps the Coinbase order states into our lifecycle.
+fn status(status: &str, filled: Btc) -> Result<OrderStatus> {
+    match status {
+        "PENDING" | "QUEUED" | "OPEN" | "CANCEL_QUEUED" => {
+            Ok(OrderStatus::Open { filled })
+        }
+        "FILLED" => Ok(OrderStatus::Filled),
+        "CANCELLED" | "EXPIRED" | "FAILED" => {
+            Ok(OrderStatus::Cancelled { filled })
+        }
+        _ => Err(Box::new(Error::invalid_data(format!(
+            "Unknown order status {}",
+            status
+        )))),
+    }
+}
+
+// Coinbase signs with the hex encoded HMAC of the timestamp and the request.
+fn sign(secret: &str, message: &str) -> String {
+    hex::encode(hmac_sha256(secret.as_bytes(), message.as_bytes()))
+}
+
+fn time(value: &Value) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(string(value)?)?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        chrono::TimeZone,
+        std::{fs, net::TcpListener, thread},
+    };
+
+    use super::*;
+    use crate::{marketplaces::mock::Server, models::Purchase};
+
+    fn credentials() -> Credentials {
+        Credentials {
+            key: "key".to_string(),
+            secret: "secret".to_string(),
+        }
+    }
+
+    fn server() -> Server {
+        Server::start(&[
+            ("GET /api/v3/brokerage/accounts", "coinbase/accounts.json"),
+            (
+                "POST /api/v3/brokerage/orders/batch_cancel",
+                "coinbase/cancel.json",
+            ),
+            ("POST /api/v3/brokerage/orders", "coinbase/order.json"),
+            (
+                "GET /api/v3/brokerage/orders/historical/fills?product_id=\
+                 BTC-USD&start_sequence_timestamp=2020-05-01T00:00:00Z&\
+                 cursor=789100",
+                "coinbase/fills_next.json",
+            ),
+            (
+                "GET /api/v3/brokerage/orders/historical/fills",
+                "coinbase/fills.json",
+            ),
+            (
+                "GET /api/v3/brokerage/orders/historical/",
+                "coinbase/order_status.json",
+            ),
+            (
+                "GET /api/v3/brokerage/best_bid_ask",
+                "coinbase/best_bid_ask.json",
+            ),
+        ])
+    }
+
+    #[test]
+    fn should_trade_against_recorded_responses() -> Result<()> {
+        let server = server();
+        let mut coinbase = Coinbase::new(server.url.as_str(), credentials());
+
+        assert_eq!(
+            Balances {
+                cash: Cash::new(150050, 2),
+                btc: Btc::new(125, 2),
+            },
+            coinbase.balances()?
+        );
+
+        let purchase =
+            Purchase::new(Btc::new(5, 1), BtcExchangeRate::new(9000, 0));
+        let offer = Offer::new(BtcExchangeRate::new(10000, 0), vec![purchase]);
+        let order_id = coinbase.place_offer(&offer)?;
+        assert_eq!("11111-00000-000000", order_id);
+
+        assert_eq!(
+            OrderStatus::Open {
+                filled: Btc::new(2, 1)
+            },
+            coinbase.order_status(&order_id)?
+        );
+        coinbase.cancel(&order_id)?;
+
+        let since = Utc.with_ymd_and_hms(2020, 5, 1, 0, 0, 0).unwrap();
+        // The second fill is on the next page.
+        let fills = coinbase.fills(since)?;
+        assert_eq!(2, fills.len());
+        assert_eq!(Side::Buy, fills[0].side);
+        assert_eq!(Cash::new(225, 2), fills[0].fee);
+        assert!(fills[0].time < fills[1].time);
+
+        let requests = server.requests();
+        let order = &requests[1];
+        let body: Value = serde_json::from_str(&order.body)?;
+        assert_eq!(offer.id.to_string(), body["client_order_id"]);
+        assert_eq!("SELL", body["side"]);
+        let limit = &body["order_configuration"]["limit_limit_gtc"];
+        assert_eq!("0.5", limit["base_size"]);
+        assert_eq!(true, limit["post_only"]);
+        let timestamp = &order.headers["cb-access-timestamp"];
+        assert_eq!(
+            &sign(
+                "secret",
+                &format!(
+                    "{}POST/api/v3/brokerage/orders{}",
+                    timestamp, order.body
+                )
+            ),
+            &order.headers["cb-access-sign"]
+        );
+        assert!(requests[4]
+            .path
+            .ends_with("start_sequence_timestamp=2020-05-01T00:00:00Z"));
+
+        assert_eq!(
+            TopOfBook {
+                bid: BtcExchangeRate::new(920005, 2),
+                ask: BtcExchangeRate::new(9201, 0),
+            },
+            coinbase.top_of_book()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_ticker_and_order_updates_from_feed() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            for _ in 0..2 {
+                socket.read_message().unwrap();
+            }
+            let feed = fs::read_to_string("tests/data/coinbase/feed.jsonl");
+            for line in feed.unwrap().lines() {
+                socket.write_message(WsMessage::Text(line.into())).unwrap();
+            }
+            while socket.read_message().is_ok() {}
+        });
+
+        let mut feed = Feed::connect(&url, &credentials())?;
+        let mut updates = Vec::new();
+        while updates.len() < 3 {
+            updates.extend(feed.read()?);
+        }
+
+        assert_eq!(
+            Update::Ticker {
+                rate: BtcExchangeRate::new(932150, 2),
+                observed_at: Utc
+                    .timestamp_opt(1590000000, 167_359_596)
+                    .unwrap(),
+            },
+            updates[0]
+        );
+        assert_eq!(
+            Update::Order {
+                order_id: "11111-00000-000000".to_string(),
+                client_order_id: "a3b1c2d4-0000-4000-8000-000000000001"
+                    .to_string(),
+                status: OrderStatus::Open {
+                    filled: Btc::new(0, 0)
+                },
+            },
+            updates[1]
+        );
+        assert_eq!(
+            Update::Order {
+                order_id: "11111-00000-000000".to_string(),
+                client_order_id: "a3b1c2d4-0000-4000-8000-000000000001"
+                    .to_string(),
+                status: OrderStatus::Filled,
+            },
+            updates[2]
+        );
+
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
 //! Contains bindings to kraken API which the actors seller and buyer.
 
+// The bindings are yet to be written, trades are only read from Kraken's
+// WebSocket feeds for now.
+#[allow(dead_code)]
 struct KrakenClient;
 
+#[allow(dead_code)]
 struct KrakenState {
     client: KrakenClient,
 }
-
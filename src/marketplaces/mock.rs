@@ -0,0 +1,118 @@
+//! A local HTTP server which answers requests with responses recorded from
+//! the exchanges. The requests it receives are kept so that tests can check
+//! how they were signed.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// The path including the query.
+    pub path: String,
+    /// Header names are lower case.
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// The server keeps running in the background until the test ends.
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    /// Starts a server which answers requests whose "METHOD /path" starts
+    /// with a route's prefix with the contents of the route's file in
+    /// tests/data. Other requests get 404.
+    pub fn start(routes: &[(&str, &str)]) -> Self {
+        let routes: Vec<(String, String)> = routes
+            .iter()
+            .map(|(route, file)| {
+                let path = format!("tests/data/{}", file);
+                let body = fs::read_to_string(&path)
+                    .unwrap_or_else(|_| panic!("Missing fixture {}", path));
+                (route.to_string(), body)
+            })
+            .collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = read_request(&mut BufReader::new(&stream));
+                let line = format!("{} {}", request.method, request.path);
+                let response = routes
+                    .iter()
+                    .find(|(route, _)| line.starts_with(route.as_str()));
+                let (status, body) = match response {
+                    Some((_, body)) => ("200 OK", body.as_str()),
+                    None => ("404 Not Found", "{}"),
+                };
+                received.lock().unwrap().push(request);
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Request {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+
+    Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8(body).unwrap(),
+    }
+}
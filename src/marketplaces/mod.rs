@@ -1 +1,120 @@
+//! Bindings to the APIs of the exchanges we trade at. Each exchange implements
+//! the [`Marketplace`] trait, so that the actors don't need to know where
+//! their orders end up.
+
+pub mod bitstamp;
+pub mod coinbase;
 pub mod kraken;
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+use {
+    chrono::{DateTime, Utc},
+    hmac::{Hmac, Mac},
+    serde_json::Value,
+    sha2::Sha256,
+    std::str::FromStr,
+};
+
+use crate::{
+    models::{Balances, Bid, Offer, Side},
+    prelude::*,
+    pricing::TopOfBook,
+};
+
+/// An exchange we can place orders at.
+pub trait Marketplace: Send {
+    /// How much cash and bitcoin do we hold at the exchange, including the
+    /// funds in open orders.
+    fn balances(&mut self) -> Result<Balances>;
+
+    /// Places a limit order to sell the offer's bitcoins at its rate. Returns
+    /// the id the exchange assigned to the order.
+    fn place_offer(&mut self, offer: &Offer) -> Result<String>;
+
+    /// Places a limit order to buy given bitcoins at the bid's rate. The
+    /// router sizes the order, so that the bitcoins and the fee fit into the
+    /// bid's cash. Returns the id the exchange assigned to the order.
+    fn place_bid(&mut self, bid: &Bid, btc: Btc) -> Result<String>;
+
+    /// Cancels an open order.
+    fn cancel(&mut self, order_id: &str) -> Result<()>;
+
+    /// Where in its lifecycle is the order.
+    fn order_status(&mut self, order_id: &str) -> Result<OrderStatus>;
+
+    /// Our trades since given time, oldest first.
+    fn fills(&mut self, since: DateTime<Utc>) -> Result<Vec<Execution>>;
+
+    /// The best bid and ask in the exchange's book of BTC/USD.
+    fn top_of_book(&mut self) -> Result<TopOfBook>;
+}
+
+/// API key and the secret to sign requests with.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub key: String,
+    pub secret: String,
+}
+
+/// The lifecycle of an order at an exchange. Whether an order is accepted by
+/// the exchange is told by the result of placing it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderStatus {
+    /// The order is in the book, possibly partially filled.
+    Open { filled: Btc },
+    /// The order was filled completely.
+    Filled,
+    /// The order was cancelled or expired before it was filled completely.
+    /// The bitcoins which were not filled return to us.
+    Cancelled { filled: Btc },
+}
+
+/// A trade of ours at an exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    pub trade_id: String,
+    pub order_id: String,
+    pub side: Side,
+    pub btc: Btc,
+    pub rate: BtcExchangeRate,
+    /// The fee we paid for the trade.
+    pub fee: Cash,
+    pub time: DateTime<Utc>,
+}
+
+// Exchanges sign requests with HMAC SHA256 of the request details.
+fn hmac_sha256(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length, this cannot fail.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Exchanges respond with errors in their own format, which we pass on.
+fn rejected(
+    exchange: &str,
+    reason: impl std::fmt::Display,
+) -> Box<dyn std::error::Error> {
+    Box::new(Error::invalid_data(format!(
+        "{} rejected the request: {}",
+        exchange, reason
+    )))
+}
+
+fn string(value: &Value) -> Result<&str> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid("Expected a string field"))
+}
+
+// Numbers are sent as strings to avoid losing precision.
+fn decimal(value: &Value) -> Result<Decimal> {
+    Ok(Decimal::from_str(string(value)?)?)
+}
+
+fn invalid(reason: &'static str) -> Box<dyn std::error::Error> {
+    Box::new(Error::invalid_data(reason))
+}
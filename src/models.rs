@@ -1,4 +1,5 @@
 use {
+    chrono::{DateTime, Utc},
     std::{cmp::Ordering, collections::BinaryHeap},
     uuid::Uuid,
 };
@@ -22,10 +23,44 @@ pub struct Purchase {
     /// account the fees paid to buy the bitcoins, so that the exact price we
     /// paid for this purchase can be calculated with btc * rate.
     pub rate: BtcExchangeRate,
+    /// Details about the transaction which don't affect the price.
+    pub metadata: Metadata,
+}
+
+/// Details about the transaction in which a purchase was made. They're used
+/// for holding period rules, tax reporting and reconciliation with the
+/// exchange. Purchases made in simulations might have none of them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// When were the bitcoins bought.
+    pub bought_at: Option<DateTime<Utc>>,
+    /// The exchange the bitcoins were bought at.
+    pub venue: Option<String>,
+    /// The id the exchange assigned to our order.
+    pub order_id: Option<String>,
+    /// The id the exchange assigned to the trade which filled the order.
+    pub trade_id: Option<String>,
+    /// How much cash did we pay in fees. The fee is already included in the
+    /// rate of the purchase, this is only for the record.
+    pub fee: Cash,
+    /// Free-form labels, e.g. where the purchase was imported from.
+    pub tags: Vec<String>,
+    /// Overrides the seller's liquidation policy for this purchase.
+    pub liquidation: Option<Liquidation>,
+}
+
+/// When do we give up on a purchase and sell it even though it doesn't make
+/// the margin, so that the cash can be used again. Either rule can be off.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Liquidation {
+    /// Sell when the trend falls this many percent below the rate we paid.
+    pub stop_loss: Option<Percentage>,
+    /// Sell when we've held the purchase for this long.
+    pub max_age: Option<chrono::Duration>,
 }
 
 /// The provider will take a cut from the transaction.
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum Fee {
     Percentage(Percentage),
     None,
@@ -45,6 +80,45 @@ pub struct Offer {
     pub rate: BtcExchangeRate,
     // What purchases are calculated in for the offer.
     pub purchases: Vec<Purchase>,
+    // Whether the offer liquidates purchases, likely at a loss. Its rate is
+    // not a floor worth waiting for, the offer goes back to the seller once
+    // it expires so that it follows the trend down.
+    pub liquidation: bool,
+}
+
+/// Represents an order on the marketplace to buy bitcoins for given amount of
+/// cash at given rate or lower.
+#[derive(Debug, Clone, Copy)]
+pub struct Bid {
+    pub id: Uuid,
+    // How much cash are we willing to spend, including the buying fee.
+    pub cash: Cash,
+    // The highest rate we are willing to pay.
+    pub rate: BtcExchangeRate,
+}
+
+/// Whether we bought or sold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// An order an actor wants to place at the marketplace.
+#[derive(Debug)]
+pub enum Order {
+    Sell(Offer),
+    Buy(Bid),
+    /// Cancels all our open orders at the marketplace.
+    CancelAll,
+}
+
+/// How much cash and bitcoin do we hold at the marketplace, including the
+/// funds in open orders.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Balances {
+    pub cash: Cash,
+    pub btc: Btc,
 }
 
 /// Each Purchase is evaluated primarily based on what was the exchange rate we
@@ -76,10 +150,20 @@ impl PartialEq for Purchase {
 impl Purchase {
     /// Creates a new purchase from given data.
     pub fn new(btc: Btc, rate: BtcExchangeRate) -> Self {
+        Self::with_metadata(btc, rate, Metadata::default())
+    }
+
+    /// Creates a new purchase with details about the transaction.
+    pub fn with_metadata(
+        btc: Btc,
+        rate: BtcExchangeRate,
+        metadata: Metadata,
+    ) -> Self {
         Self {
             id: Uuid::new_v4(),
             btc,
             rate,
+            metadata,
         }
     }
 
@@ -106,10 +190,28 @@ impl Purchase {
         self.btc * current_trend - self.buying_price()
     }
 
+    /// How long have we held the purchase, if we know when it was bought.
+    pub fn held_for(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        self.metadata.bought_at.map(|bought_at| now - bought_at)
+    }
+
     /// How much have we paid in total for this offer, including fees.
     pub fn buying_price(&self) -> Cash {
         self.btc * self.rate
     }
+
+    /// The part of the purchase of given bitcoins, e.g. sold at one venue. It
+    /// keeps the id of the purchase, so that the parts can be told apart only
+    /// by where they're sold. Parts which return to the seller are merged
+    /// back into one purchase.
+    pub fn part(&self, btc: Btc) -> Self {
+        Self {
+            id: self.id,
+            btc,
+            rate: self.rate,
+            metadata: self.metadata.clone(),
+        }
+    }
 }
 
 impl Offer {
@@ -118,6 +220,33 @@ impl Offer {
             id: Uuid::new_v4(),
             rate,
             purchases,
+            liquidation: false,
+        }
+    }
+
+    /// How many bitcoins are we selling with this offer.
+    pub fn btc(&self) -> Btc {
+        self.purchases.iter().map(|purchase| purchase.btc).sum()
+    }
+}
+
+impl Order {
+    /// How much cash is the order worth at its rate.
+    pub fn notional(&self) -> Cash {
+        match self {
+            Self::Sell(offer) => offer.btc() * offer.rate,
+            Self::Buy(bid) => bid.cash,
+            Self::CancelAll => Cash::new(0, 0),
+        }
+    }
+}
+
+impl Bid {
+    pub fn new(cash: Cash, rate: BtcExchangeRate) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            cash,
+            rate,
         }
     }
 }
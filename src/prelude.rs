@@ -22,10 +22,18 @@ impl Error {
     pub fn outdated_message() -> Self {
         Self(Cow::Borrowed("Received an outdated message"))
     }
+
+    pub fn insufficient_funds(reason: impl Into<Cow<'static, str>>) -> Self {
+        Self(reason.into())
+    }
+
+    pub fn invalid_data(reason: impl Into<Cow<'static, str>>) -> Self {
+        Self(reason.into())
+    }
 }
 impl std::error::Error for Error {}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "{}", self.0)
     }
 }
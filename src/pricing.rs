@@ -0,0 +1,297 @@
+//! Decides the limit rate our sell orders are placed at. An offer at exactly
+//! the trend mostly crosses the spread, so we pay the taker fee. Instead, the
+//! router asks for a premium over either the trend or the best ask in the
+//! order book of the exchange, so that the order rests in the book and we get
+//! the maker fee. Offers which aren't filled within a timeout are repriced or
+//! cancelled.
+//!
+//! Instead of selling all bitcoins of an offer at one rate, they can be split
+//! into a ladder of offers at increasing rates, e.g. 40 % at the price, 30 %
+//! at 2 % more and 30 % at 4 % more. Each rung is placed as an offer of its
+//! own.
+
+use chrono::Duration;
+
+use crate::{models::Purchase, prelude::*};
+
+/// Parameters of the pricing.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// What the premium is added to.
+    pub anchor: Anchor,
+    /// How many percent above the anchor do we ask.
+    pub premium: Percentage,
+    /// How long can an offer rest in the book before it expires. Offers
+    /// never expire if not set.
+    pub timeout: Option<Duration>,
+    /// What happens to offers which expire.
+    pub expiry: Expiry,
+    /// How are the bitcoins of an offer split by rate, cheapest rung first.
+    /// All bitcoins are offered at the price if empty.
+    pub ladder: Vec<Rung>,
+}
+
+/// The rate the premium is added to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    /// The higher of the trend and the rate the exchange last traded at.
+    Trend,
+    /// The lowest ask in the order book of the exchange, as the router last
+    /// read it. Falls back to the trend while we don't know the book.
+    BestAsk,
+}
+
+/// What happens to the part of an offer which wasn't filled in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expiry {
+    /// It's placed again at the current price.
+    Reprice,
+    /// It's returned to the seller, who decides again whether to sell it.
+    Cancel,
+}
+
+/// A part of the ladder of offers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rung {
+    /// How much of the bitcoins are offered at this rung, relative to the
+    /// shares of the other rungs, e.g. in percent.
+    pub share: Percentage,
+    /// How many percent above the price do we ask.
+    pub markup: Percentage,
+}
+
+/// The best rates in the order book of an exchange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopOfBook {
+    pub bid: BtcExchangeRate,
+    pub ask: BtcExchangeRate,
+}
+
+/// Sells at the trend, or higher if the exchange trades higher, and offers
+/// never expire.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            anchor: Anchor::Trend,
+            premium: Percentage::new(0, 0),
+            timeout: None,
+            expiry: Expiry::Cancel,
+            ladder: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The rate we ask at an exchange which last traded at given rate, if
+    /// any, and whose book we may know. We never ask less than the floor,
+    /// the rate the seller decided to sell at. When we know the book, we
+    /// never ask the best bid or less, as the order would take liquidity.
+    pub fn price(
+        &self,
+        floor: BtcExchangeRate,
+        last_trade: Option<BtcExchangeRate>,
+        book: Option<TopOfBook>,
+    ) -> BtcExchangeRate {
+        let anchor = match (self.anchor, book) {
+            (Anchor::BestAsk, Some(book)) => book.ask,
+            _ => last_trade.map_or(floor, |rate| rate.max(floor)),
+        };
+        let rate = markup(anchor, self.premium).max(floor);
+
+        match book {
+            Some(book) if rate <= book.bid => book.ask,
+            _ => rate,
+        }
+    }
+
+    /// Checks that the bitcoins can be split by the shares of the ladder. No
+    /// share may be negative and an empty ladder aside, they must add up to
+    /// more than nothing.
+    pub fn validate(&self) -> Result<()> {
+        let zero = Percentage::new(0, 0);
+        if self.ladder.iter().any(|rung| rung.share < zero) {
+            return Err(Box::new(Error::invalid_data(
+                "The ladder has a negative share",
+            )));
+        }
+        let shares: Percentage =
+            self.ladder.iter().map(|rung| rung.share).sum();
+        if !self.ladder.is_empty() && shares <= zero {
+            return Err(Box::new(Error::invalid_data(
+                "The shares of the ladder add up to nothing",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Splits the purchases into the rungs of the ladder by their shares of
+    /// the bitcoins, with the markup of each rung. A purchase is split into
+    /// parts if it falls into several rungs. Rungs without bitcoins are left
+    /// out. If the ladder isn't valid, all bitcoins are offered at the price.
+    pub fn ladder(
+        &self,
+        purchases: Vec<Purchase>,
+    ) -> Vec<(Percentage, Vec<Purchase>)> {
+        if self.ladder.is_empty() || self.validate().is_err() {
+            return vec![(Percentage::new(0, 0), purchases)];
+        }
+
+        let total: Btc = purchases.iter().map(|purchase| purchase.btc).sum();
+        let shares: Percentage =
+            self.ladder.iter().map(|rung| rung.share).sum();
+        let mut purchases = purchases.into_iter();
+        let mut current = purchases.next();
+        let mut rungs = Vec::new();
+        for (index, rung) in self.ladder.iter().enumerate() {
+            // The last rung takes whatever is left, so that no bitcoins are
+            // lost to rounding. Others are rounded to satoshis.
+            let mut remaining = if index + 1 == self.ladder.len() {
+                total
+            } else {
+                (total / shares * rung.share).round_dp(8)
+            };
+            let mut parts = Vec::new();
+            while remaining > Btc::new(0, 0) {
+                let purchase = if let Some(purchase) = current.as_mut() {
+                    purchase
+                } else {
+                    break;
+                };
+                let btc = remaining.min(purchase.btc);
+                parts.push(purchase.part(btc));
+                purchase.btc -= btc;
+                remaining -= btc;
+                if purchase.btc <= Btc::new(0, 0) {
+                    current = purchases.next();
+                }
+            }
+            if !parts.is_empty() {
+                rungs.push((rung.markup, parts));
+            }
+        }
+
+        rungs
+    }
+}
+
+/// Adds given percent to the rate.
+pub fn markup(rate: BtcExchangeRate, markup: Percentage) -> BtcExchangeRate {
+    rate + rate / Decimal::new(100, 0) * markup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_price_offers_above_the_anchor_as_maker() {
+        let mut config = Config {
+            anchor: Anchor::Trend,
+            premium: Percentage::new(5, 1),
+            ..Config::default()
+        };
+        let floor = BtcExchangeRate::new(10_000, 0);
+        let book = TopOfBook {
+            bid: BtcExchangeRate::new(10_090, 0),
+            ask: BtcExchangeRate::new(10_110, 0),
+        };
+
+        // The exchange trades higher than the trend.
+        let last_trade = Some(BtcExchangeRate::new(10_020, 0));
+        assert_eq!(
+            BtcExchangeRate::new(100_701, 1),
+            config.price(floor, last_trade, None)
+        );
+        assert_eq!(
+            BtcExchangeRate::new(10_050, 0),
+            config.price(floor, None, None)
+        );
+        // 10_050 would take the best bid of 10_090.
+        assert_eq!(book.ask, config.price(floor, None, Some(book)));
+
+        config.anchor = Anchor::BestAsk;
+        assert_eq!(
+            BtcExchangeRate::new(1_016_055, 2),
+            config.price(floor, last_trade, Some(book))
+        );
+        assert_eq!(
+            BtcExchangeRate::new(100_701, 1),
+            config.price(floor, last_trade, None)
+        );
+
+        // The seller's rate is the floor, even if the book is lower.
+        let book = TopOfBook {
+            bid: BtcExchangeRate::new(9_000, 0),
+            ask: BtcExchangeRate::new(9_010, 0),
+        };
+        config.premium = Percentage::new(0, 0);
+        assert_eq!(floor, config.price(floor, last_trade, Some(book)));
+    }
+
+    #[test]
+    fn should_split_purchases_into_rungs() {
+        let rung = |markup| Rung {
+            share: Percentage::new(1, 0),
+            markup: Percentage::new(markup, 0),
+        };
+        let config = Config {
+            ladder: vec![rung(0), rung(1), rung(2)],
+            ..Config::default()
+        };
+        let a = Purchase::new(Btc::new(6, 1), BtcExchangeRate::new(100, 0));
+        let b = Purchase::new(Btc::new(4, 1), BtcExchangeRate::new(110, 0));
+
+        // Each rung gets a third, the last one the satoshi lost to rounding.
+        let rungs = config.ladder(vec![a.clone(), b.clone()]);
+        let btc: Vec<Vec<_>> = rungs
+            .iter()
+            .map(|(_, parts)| parts.iter().map(|p| (p.id, p.btc)).collect())
+            .collect();
+        assert_eq!(
+            vec![
+                vec![(a.id, Btc::new(33333333, 8))],
+                vec![
+                    (a.id, Btc::new(26666667, 8)),
+                    (b.id, Btc::new(6666666, 8))
+                ],
+                vec![(b.id, Btc::new(33333334, 8))],
+            ],
+            btc
+        );
+        let markups: Vec<_> = rungs.iter().map(|(markup, _)| *markup).collect();
+        assert_eq!(
+            vec![
+                Percentage::new(0, 0),
+                Percentage::new(1, 0),
+                Percentage::new(2, 0)
+            ],
+            markups
+        );
+
+        // Without a ladder, everything is sold at the price.
+        let rungs = Config::default().ladder(vec![a.clone()]);
+        assert_eq!(vec![(Percentage::new(0, 0), vec![a])], rungs);
+    }
+
+    #[test]
+    fn should_reject_ladders_which_cannot_split_bitcoins() {
+        let rung = |share| Rung {
+            share: Percentage::new(share, 0),
+            markup: Percentage::new(1, 0),
+        };
+        let ladder = |shares: &[i64]| Config {
+            ladder: shares.iter().copied().map(rung).collect(),
+            ..Config::default()
+        };
+        assert!(Config::default().validate().is_ok());
+        assert!(ladder(&[0, 1]).validate().is_ok());
+        assert!(ladder(&[0, 0]).validate().is_err());
+        assert!(ladder(&[2, -1]).validate().is_err());
+
+        // An invalid ladder offers everything at the price.
+        let a = Purchase::new(Btc::new(6, 1), BtcExchangeRate::new(100, 0));
+        let rungs = ladder(&[0, 0]).ladder(vec![a.clone()]);
+        assert_eq!(vec![(Percentage::new(0, 0), vec![a])], rungs);
+    }
+}
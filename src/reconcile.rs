@@ -0,0 +1,347 @@
+//! Our books can drift from what the exchanges actually hold: trades made by
+//! hand, deposits, withdrawals or fills we missed. The reconciler runs
+//! periodically in the router's thread. It compares the totals in the wallet
+//! with the balances of all exchanges and looks for trades in the history of
+//! the exchanges which were not placed by us.
+//!
+//! Unknown buys can be imported as new purchases, so that the seller manages
+//! them. Unknown sells are only flagged, as we cannot tell which purchases
+//! they sold.
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    std::{collections::HashMap, sync::Arc},
+};
+
+use crate::{
+    clock::Clock,
+    marketplaces::Execution,
+    models::{Balances, Metadata, Purchase, Side},
+    prelude::*,
+    router::Router,
+    wallet::{Discrepancy, SharedWallet},
+};
+
+/// Parameters of the reconciliation.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// How often do we reconcile.
+    pub interval: Duration,
+    /// Differences between balances within this tolerance are ignored.
+    pub tolerance: Decimal,
+    /// Whether buys we don't know of are imported as purchases.
+    pub import: bool,
+}
+
+/// What the reconciliation found.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Balances our wallet disagrees about with the exchanges. Empty if any
+    /// exchange failed to tell us its balances.
+    pub discrepancies: Vec<Discrepancy>,
+    /// Trades at the exchanges which were not placed by us.
+    pub unknown: Vec<Execution>,
+    /// Purchases made from the unknown buys, if importing is enabled.
+    pub imported: Vec<Purchase>,
+}
+
+/// Remembers which trades it has already seen.
+pub struct Reconciler {
+    config: Config,
+    clock: Arc<dyn Clock>,
+    // Imported buys are settled in the wallet, so that they don't show up as
+    // discrepancies.
+    wallet: SharedWallet,
+    // When did we last reconcile, successful or not.
+    last_run: DateTime<Utc>,
+    // Trades since this time haven't been reconciled yet. It only moves
+    // forward once all exchanges told us their trades.
+    since: DateTime<Utc>,
+    // Trades since the last reconciliation which we've already seen and when
+    // they happened, as we might fetch them again if an exchange failed.
+    seen: HashMap<String, DateTime<Utc>>,
+}
+
+impl Reconciler {
+    /// Trades made before now are not reconciled.
+    pub fn new(
+        config: Config,
+        clock: Arc<dyn Clock>,
+        wallet: SharedWallet,
+    ) -> Self {
+        let since = clock.now();
+        Self {
+            config,
+            clock,
+            wallet,
+            last_run: since,
+            since,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// How often do we reconcile.
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Whether the interval since the last reconciliation has passed. A
+    /// reconciliation which failed is tried again only after the interval,
+    /// so that we don't flood the exchanges while one of them is down.
+    pub fn is_due(&self) -> bool {
+        self.clock.now() - self.last_run >= self.config.interval
+    }
+
+    /// Overwrites the totals in the wallet with the balances of all
+    /// exchanges the router trades at, e.g. when the broker starts. Fails if
+    /// any exchange doesn't tell us its balances.
+    pub fn seed(&self, router: &mut Router) -> Result<Balances> {
+        let total = total(router)?;
+        self.wallet.lock().sync(&total);
+        Ok(total)
+    }
+
+    /// Compares the wallet with the exchanges the router trades at. Trades
+    /// are reconciled before balances, so that imported buys are accounted
+    /// for in the wallet. If the balances agree within the tolerance, the
+    /// wallet takes them over, e.g. to account for rounding of fees.
+    pub fn run(&mut self, router: &mut Router) -> Report {
+        let now = self.clock.now();
+        self.last_run = now;
+        let mut report = Report::default();
+
+        let mut complete = true;
+        for (venue, fills) in router.fills(self.since) {
+            match fills {
+                Ok(fills) => {
+                    for execution in fills {
+                        self.reconcile_trade(
+                            router,
+                            &venue,
+                            execution,
+                            &mut report,
+                        )
+                    }
+                }
+                Err(e) => {
+                    log::error!("Cannot read trades of {}: {}", venue, e);
+                    complete = false;
+                }
+            }
+        }
+        // The trades of the exchanges which failed are fetched again next
+        // time.
+        if complete {
+            self.since = now;
+            let since = self.since;
+            self.seen.retain(|_, time| *time >= since);
+        }
+
+        let total = match total(router) {
+            Ok(total) => total,
+            Err(e) => {
+                log::error!("{}", e);
+                return report;
+            }
+        };
+        let mut wallet = self.wallet.lock();
+        report.discrepancies = wallet.reconcile(&total, self.config.tolerance);
+        if report.discrepancies.is_empty() {
+            wallet.sync(&total);
+        }
+        for discrepancy in &report.discrepancies {
+            log::warn!(
+                "We hold {} {:?} while the exchanges hold {}",
+                discrepancy.local,
+                discrepancy.asset,
+                discrepancy.exchange
+            );
+        }
+
+        report
+    }
+
+    fn reconcile_trade(
+        &mut self,
+        router: &mut Router,
+        venue: &str,
+        execution: Execution,
+        report: &mut Report,
+    ) {
+        if execution.time < self.since
+            || self
+                .seen
+                .insert(execution.trade_id.clone(), execution.time)
+                .is_some()
+            || router.is_ours(&execution.order_id)
+        {
+            return;
+        }
+
+        log::warn!(
+            "Unknown trade {} at {}: {:?} {} BTC at {}",
+            execution.trade_id,
+            venue,
+            execution.side,
+            execution.btc,
+            execution.rate
+        );
+        if self.config.import && execution.side == Side::Buy {
+            // The rate of a purchase includes the fee we paid.
+            let spent = execution.btc * execution.rate + execution.fee;
+            let metadata = Metadata {
+                bought_at: Some(execution.time),
+                venue: Some(venue.to_string()),
+                order_id: Some(execution.order_id.clone()),
+                trade_id: Some(execution.trade_id.clone()),
+                fee: execution.fee,
+                tags: vec!["reconciled".to_string()],
+                liquidation: None,
+            };
+            let rate = spent / execution.btc;
+            let purchase =
+                Purchase::with_metadata(execution.btc, rate, metadata);
+            log::info!(
+                "Imported trade {} as purchase {}",
+                execution.trade_id,
+                purchase.id
+            );
+            self.wallet
+                .lock()
+                .settle_buy(purchase.id, spent, purchase.btc);
+            router.deposit(venue, purchase.id, purchase.btc);
+            report.imported.push(purchase);
+        }
+        report.unknown.push(execution);
+    }
+}
+
+// The sum of the balances of all exchanges the router trades at.
+fn total(router: &mut Router) -> Result<Balances> {
+    let mut total = Balances::default();
+    for (venue, balances) in router.balances() {
+        let balances = balances.map_err(|e| {
+            Error::invalid_data(format!(
+                "Cannot read balances of {}: {}",
+                venue, e
+            ))
+        })?;
+        total.cash += balances.cash;
+        total.btc += balances.btc;
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        clock::SimulatedClock,
+        marketplaces::{bitstamp::Bitstamp, mock::Server, Credentials},
+        models::{Fee, Offer},
+        pricing,
+        router::Venue,
+        wallet::{Asset, Wallet},
+    };
+
+    #[test]
+    fn should_flag_discrepancies_and_import_unknown_buys() -> Result<()> {
+        let server = Server::start(&[
+            ("POST /api/v2/balance/btcusd/", "bitstamp/balance.json"),
+            ("POST /api/v2/sell/btcusd/", "bitstamp/sell.json"),
+            (
+                "POST /api/v2/user_transactions/btcusd/",
+                "bitstamp/reconcile_transactions.json",
+            ),
+        ]);
+        let credentials = Credentials {
+            key: "key".to_string(),
+            secret: "secret".to_string(),
+        };
+        let bitstamp = Bitstamp::new(server.url.as_str(), credentials);
+        let venue = Venue::new("bitstamp", Box::new(bitstamp), Fee::None)?;
+        let wallet = SharedWallet::new(Wallet::new(
+            Cash::new(240275, 2),
+            Btc::new(1, 0),
+        ));
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 5, 1, 0, 0, 0).unwrap(),
+        );
+        let mut router = Router::new(
+            vec![venue],
+            pricing::Config::default(),
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+        let config = Config {
+            interval: Duration::hours(1),
+            tolerance: Decimal::new(1, 4),
+            import: true,
+        };
+        let mut reconciler =
+            Reconciler::new(config, Arc::new(clock.clone()), wallet.clone());
+
+        // The sell of order 1234567890 is ours.
+        let purchase =
+            Purchase::new(Btc::new(5, 1), BtcExchangeRate::new(9_000, 0));
+        let offer = Offer::new(BtcExchangeRate::new(10_000, 0), vec![purchase]);
+        assert!(router.place_offer(offer).is_none());
+
+        assert!(!reconciler.is_due());
+        clock.set(Utc.with_ymd_and_hms(2020, 5, 21, 0, 0, 0).unwrap());
+        assert!(reconciler.is_due());
+
+        // Someone bought 0.1 BTC for 900 by hand and paid 2.25 fee. After
+        // the import the wallet's cash agrees with the exchange.
+        let report = reconciler.run(&mut router);
+        assert_eq!(1, report.unknown.len());
+        assert_eq!("98766", report.unknown[0].trade_id);
+        assert_eq!(1, report.imported.len());
+        let imported = &report.imported[0];
+        assert_eq!(Btc::new(1, 1), imported.btc);
+        assert_eq!(BtcExchangeRate::new(90225, 1), imported.rate);
+        assert_eq!(Btc::new(1, 1), router.inventory("bitstamp", imported.id));
+        assert_eq!(Some("98766"), imported.metadata.trade_id.as_deref());
+        assert_eq!(Cash::new(225, 2), imported.metadata.fee);
+        assert_eq!(
+            vec![Discrepancy {
+                asset: Asset::Btc,
+                local: Btc::new(11, 1),
+                exchange: Btc::new(125, 2),
+            }],
+            report.discrepancies
+        );
+        assert!(!reconciler.is_due());
+
+        // The same trades are not reported twice.
+        clock.advance(Duration::hours(1));
+        let report = reconciler.run(&mut router);
+        assert!(report.unknown.is_empty());
+        assert_eq!(1, report.discrepancies.len());
+        assert_eq!(Btc::new(11, 1), wallet.lock().btc());
+
+        // Differences within the tolerance are taken over from the
+        // exchanges.
+        let config = Config {
+            tolerance: Decimal::new(2, 1),
+            ..config
+        };
+        let mut reconciler =
+            Reconciler::new(config, Arc::new(clock.clone()), wallet.clone());
+        assert!(reconciler.run(&mut router).discrepancies.is_empty());
+        assert_eq!(Btc::new(125, 2), wallet.lock().btc());
+
+        // When the broker starts, the wallet takes over the balances.
+        let empty = SharedWallet::default();
+        let reconciler =
+            Reconciler::new(config, Arc::new(clock.clone()), empty.clone());
+        reconciler.seed(&mut router)?;
+        assert_eq!(Cash::new(150050, 2), empty.lock().cash());
+        assert_eq!(Btc::new(125, 2), empty.lock().btc());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,568 @@
+//! Risk manager is an actor which sits between the actors which decide to
+//! trade and the marketplace. Every order passes through it and is checked
+//! against configured limits. Orders which would breach a limit are logged
+//! and returned to the actor which made them, the rest is forwarded to the
+//! marketplace. While no buyer runs, the cash of rejected bids is released
+//! by the risk manager.
+//!
+//! An operator pulls the kill switch by creating the kill file, and re-arms
+//! the circuit breaker by removing it.
+
+use {
+    chrono::{DateTime, Duration, NaiveDate, Utc},
+    crossbeam_channel::{Receiver, Sender},
+    std::{collections::VecDeque, fmt, path::PathBuf, sync::Arc, thread, time},
+};
+
+use crate::{
+    breaker::{CircuitBreaker, Trip},
+    buyer,
+    clock::Clock,
+    models::{Bid, Offer, Order},
+    prelude::*,
+    seller,
+    wallet::SharedWallet,
+};
+
+pub enum Message {
+    /// An actor wants to place an order at the marketplace.
+    Order(Order),
+    /// An order was filled and we realised given profit, or loss if negative.
+    Settled { pnl: Cash },
+    /// An order which passed the checks was placed as given number of orders
+    /// at the exchanges, e.g. one for each rung of the ladder. All of them
+    /// count towards the hourly limit.
+    Placed { orders: usize },
+    /// Halts trading and cancels all open orders. Trading resumes once the
+    /// circuit breaker is re-armed.
+    KillSwitch,
+}
+
+/// The limits orders are checked against.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// How many bitcoins at most can we hold, including the bitcoins we are
+    /// about to buy.
+    pub max_exposure: Btc,
+    /// How much cash at most can a single order be worth.
+    pub max_order_notional: Cash,
+    /// How many orders at most can we place within a rolling hour.
+    pub max_orders_per_hour: usize,
+    /// How much at most can we lose within a day (UTC). Once we do, no more
+    /// orders are placed until the next day.
+    pub daily_loss_cap: Cash,
+}
+
+/// Why was an order rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    MaxExposure { exposure: Btc, limit: Btc },
+    MaxOrderNotional { notional: Cash, limit: Cash },
+    MaxOrdersPerHour { limit: usize },
+    DailyLossCap { loss: Cash, limit: Cash },
+    Halted(Trip),
+}
+
+/// Checks orders against the limits and keeps track of what's needed to do
+/// so.
+pub struct RiskManager {
+    limits: Limits,
+    clock: Arc<dyn Clock>,
+    // Tells us how many bitcoins we hold.
+    wallet: SharedWallet,
+    // No orders are placed while the breaker is tripped.
+    breaker: CircuitBreaker,
+    // When were the orders within the last hour placed, oldest first.
+    placed: VecDeque<DateTime<Utc>>,
+    // The day we track realised profit for and the profit so far.
+    today: (NaiveDate, Cash),
+}
+
+/// Spawns a new thread which runs the risk manager. Orders which pass the
+/// checks are sent to the output converted into whatever the next actor in the
+/// chain expects, rejected offers are returned to the seller and rejected bids
+/// to the buyer, if any.
+pub fn spawn<T: From<Order> + Send + 'static>(
+    input: Receiver<Message>,
+    output: Sender<T>,
+    seller: Sender<seller::Message>,
+    buyer: Option<Sender<buyer::Message>>,
+    mut manager: RiskManager,
+) {
+    thread::spawn(move || loop {
+        let message = if let Ok(message) = input.recv() {
+            message
+        } else {
+            log::error!("The risk manager's input channel died. Stopping ...");
+            break;
+        };
+
+        let order = match message {
+            Message::Order(order) => order,
+            Message::Settled { pnl } => {
+                manager.record_settlement(pnl);
+                continue;
+            }
+            Message::Placed { orders } => {
+                manager.record_placement(orders);
+                continue;
+            }
+            Message::KillSwitch => {
+                manager.breaker.kill();
+                Order::CancelAll
+            }
+        };
+
+        match manager.check(&order) {
+            Ok(()) => {
+                if output.send(order.into()).is_err() {
+                    log::error!(
+                        "The risk manager's output channel died. Stopping ..."
+                    );
+                    break;
+                }
+            }
+            Err(violation) => {
+                log::warn!("Rejected {:?}: {}", order, violation);
+                match order {
+                    Order::Sell(offer) => {
+                        let rejected = seller::Message::OfferRejected(offer);
+                        if seller.send(rejected).is_err() {
+                            log::error!(
+                                "The seller's input channel died. Stopping ..."
+                            );
+                            break;
+                        }
+                    }
+                    Order::Buy(bid) => match &buyer {
+                        Some(buyer) => {
+                            let rejected = buyer::Message::BidRejected(bid);
+                            if buyer.send(rejected).is_err() {
+                                log::error!(
+                                    "The buyer's input channel died. \
+                                     Stopping ..."
+                                );
+                                break;
+                            }
+                        }
+                        None => manager.wallet.lock().release(bid.id),
+                    },
+                    Order::CancelAll => (),
+                }
+            }
+        }
+    });
+}
+
+/// Spawns a new thread which checks for the kill file every given interval.
+/// When the file appears, the kill switch is sent to the risk manager. When
+/// it's removed, the breaker is re-armed.
+pub fn watch(
+    path: PathBuf,
+    output: Sender<Message>,
+    breaker: CircuitBreaker,
+    interval: time::Duration,
+) {
+    thread::spawn(move || {
+        let mut killed = false;
+        loop {
+            let exists = path.exists();
+            if exists && !killed {
+                log::warn!("Found kill file {}", path.display());
+                if output.send(Message::KillSwitch).is_err() {
+                    log::error!(
+                        "The risk manager's input channel died. Stopping ..."
+                    );
+                    break;
+                }
+            } else if !exists && killed {
+                log::info!("Kill file {} was removed", path.display());
+                breaker.rearm();
+            }
+            killed = exists;
+            thread::sleep(interval);
+        }
+    });
+}
+
+impl RiskManager {
+    pub fn new(
+        limits: Limits,
+        clock: Arc<dyn Clock>,
+        wallet: SharedWallet,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        let today = (clock.now().date_naive(), Cash::new(0, 0));
+        Self {
+            limits,
+            clock,
+            wallet,
+            breaker,
+            placed: VecDeque::new(),
+            today,
+        }
+    }
+
+    /// Checks the order against all limits. If the order passes, it is
+    /// counted towards the hourly limit. Cancelling orders never adds risk
+    /// and always passes.
+    pub fn check(
+        &mut self,
+        order: &Order,
+    ) -> std::result::Result<(), Violation> {
+        if let Order::CancelAll = order {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        self.roll_over(now);
+        self.breaker.check(now).map_err(Violation::Halted)?;
+
+        let loss = -self.today.1;
+        if loss >= self.limits.daily_loss_cap {
+            return Err(Violation::DailyLossCap {
+                loss,
+                limit: self.limits.daily_loss_cap,
+            });
+        }
+
+        let notional = order.notional();
+        if notional > self.limits.max_order_notional {
+            return Err(Violation::MaxOrderNotional {
+                notional,
+                limit: self.limits.max_order_notional,
+            });
+        }
+
+        match order {
+            Order::Buy(bid) => self.check_exposure(bid)?,
+            Order::Sell(offer) => self.check_loss(offer)?,
+            Order::CancelAll => (),
+        }
+
+        if self.placed.len() >= self.limits.max_orders_per_hour {
+            return Err(Violation::MaxOrdersPerHour {
+                limit: self.limits.max_orders_per_hour,
+            });
+        }
+
+        self.placed.push_back(now);
+        Ok(())
+    }
+
+    /// Records profit or loss realised by a filled order.
+    pub fn record_settlement(&mut self, pnl: Cash) {
+        self.roll_over(self.clock.now());
+        self.today.1 += pnl;
+    }
+
+    /// Counts the orders an order which passed the checks was placed as
+    /// towards the hourly limit. The order itself was counted when checked.
+    pub fn record_placement(&mut self, orders: usize) {
+        let now = self.clock.now();
+        self.roll_over(now);
+        for _ in 1..orders {
+            self.placed.push_back(now);
+        }
+    }
+
+    // Forgets orders older than an hour and the realised profit of past days.
+    fn roll_over(&mut self, now: DateTime<Utc>) {
+        while let Some(placed_at) = self.placed.front() {
+            if now - *placed_at < Duration::hours(1) {
+                break;
+            }
+            self.placed.pop_front();
+        }
+
+        if self.today.0 != now.date_naive() {
+            self.today = (now.date_naive(), Cash::new(0, 0));
+        }
+    }
+
+    fn check_exposure(&self, bid: &Bid) -> std::result::Result<(), Violation> {
+        let exposure = self.wallet.lock().btc() + bid.cash / bid.rate;
+        if exposure > self.limits.max_exposure {
+            Err(Violation::MaxExposure {
+                exposure,
+                limit: self.limits.max_exposure,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    // An offer which would realise a loss can only be placed if the loss
+    // doesn't exceed the daily cap.
+    fn check_loss(&self, offer: &Offer) -> std::result::Result<(), Violation> {
+        let pnl: Cash = offer
+            .purchases
+            .iter()
+            .map(|purchase| purchase.margin(offer.rate))
+            .sum();
+        let loss = -(self.today.1 + pnl);
+        if loss > self.limits.daily_loss_cap {
+            Err(Violation::DailyLossCap {
+                loss,
+                limit: self.limits.daily_loss_cap,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxExposure { exposure, limit } => write!(
+                f,
+                "exposure would be {} BTC, limit is {} BTC",
+                exposure, limit
+            ),
+            Self::MaxOrderNotional { notional, limit } => write!(
+                f,
+                "order is worth ${}, limit is ${}",
+                notional.round_dp(2),
+                limit
+            ),
+            Self::MaxOrdersPerHour { limit } => {
+                write!(f, "already placed {} orders within an hour", limit)
+            }
+            Self::DailyLossCap { loss, limit } => write!(
+                f,
+                "daily loss would be ${}, limit is ${}",
+                loss.round_dp(2),
+                limit
+            ),
+            Self::Halted(trip) => write!(f, "trading is halted: {}", trip),
+        }
+    }
+}
+
+impl From<Offer> for Message {
+    fn from(offer: Offer) -> Self {
+        Self::Order(Order::Sell(offer))
+    }
+}
+
+impl From<Bid> for Message {
+    fn from(bid: Bid) -> Self {
+        Self::Order(Order::Buy(bid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        chrono::TimeZone,
+        crossbeam_channel::unbounded,
+        std::{env, fs},
+        uuid::Uuid,
+    };
+
+    use super::*;
+    use crate::{clock::SimulatedClock, models::Purchase, wallet::Wallet};
+
+    fn limits() -> Limits {
+        Limits {
+            max_exposure: Btc::new(2, 0),
+            max_order_notional: Cash::new(1_000, 0),
+            max_orders_per_hour: 2,
+            daily_loss_cap: Cash::new(100, 0),
+        }
+    }
+
+    fn offer(btc: i64, bought_for: i64, rate: i64) -> Offer {
+        let purchase = Purchase::new(
+            Btc::new(btc, 0),
+            BtcExchangeRate::new(bought_for, 0),
+        );
+        Offer::new(BtcExchangeRate::new(rate, 0), vec![purchase])
+    }
+
+    #[test]
+    fn should_enforce_limits() {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 1, 1, 22, 0, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(15, 1)));
+        let mut manager = RiskManager::new(
+            limits(),
+            Arc::new(clock.clone()),
+            wallet,
+            CircuitBreaker::default(),
+        );
+
+        let too_large = Order::Sell(offer(2, 100, 600));
+        assert!(matches!(
+            manager.check(&too_large),
+            Err(Violation::MaxOrderNotional { .. })
+        ));
+
+        // Buying 1 BTC would bring us to 2.5 BTC.
+        let bid = Bid::new(Cash::new(500, 0), BtcExchangeRate::new(500, 0));
+        assert!(matches!(
+            manager.check(&Order::Buy(bid)),
+            Err(Violation::MaxExposure { .. })
+        ));
+
+        // Selling 1 BTC bought for 600 at 450 loses more than the cap.
+        assert!(matches!(
+            manager.check(&Order::Sell(offer(1, 600, 450))),
+            Err(Violation::DailyLossCap { .. })
+        ));
+
+        assert!(manager.check(&Order::Sell(offer(1, 500, 450))).is_ok());
+        assert!(manager.check(&Order::Sell(offer(1, 100, 200))).is_ok());
+        assert!(matches!(
+            manager.check(&Order::Sell(offer(1, 100, 200))),
+            Err(Violation::MaxOrdersPerHour { limit: 2 })
+        ));
+
+        // An hour later we can place orders again, until we lose too much.
+        clock.advance(Duration::minutes(30));
+        manager.record_settlement(Cash::new(-100, 0));
+        clock.advance(Duration::minutes(30));
+        assert!(matches!(
+            manager.check(&Order::Sell(offer(1, 100, 200))),
+            Err(Violation::DailyLossCap { .. })
+        ));
+
+        // The loss was realised yesterday.
+        clock.advance(Duration::hours(1));
+        assert!(manager.check(&Order::Sell(offer(1, 100, 200))).is_ok());
+
+        // The order was placed as two rungs of the ladder, both count.
+        manager.record_placement(2);
+        assert!(matches!(
+            manager.check(&Order::Sell(offer(1, 100, 200))),
+            Err(Violation::MaxOrdersPerHour { limit: 2 })
+        ));
+    }
+
+    #[test]
+    fn should_return_rejected_offers_to_seller() {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+        );
+        let breaker = CircuitBreaker::default();
+        let manager = RiskManager::new(
+            limits(),
+            Arc::new(clock),
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(2, 0))),
+            breaker.clone(),
+        );
+        let (input, risk_input) = unbounded();
+        let (risk_output, marketplace) = unbounded();
+        let (risk_feedback, seller) = unbounded();
+        spawn(risk_input, risk_output, risk_feedback, None, manager);
+
+        let accepted = offer(1, 100, 200);
+        let accepted_id = accepted.id;
+        input.send(Message::from(accepted)).unwrap();
+        let rejected = offer(2, 100, 600);
+        let rejected_id = rejected.id;
+        input.send(Message::from(rejected)).unwrap();
+
+        let timeout = time::Duration::from_millis(100);
+        match marketplace.recv_timeout(timeout).unwrap() {
+            Order::Sell(offer) => assert_eq!(accepted_id, offer.id),
+            order => panic!("Unexpected order {:?}", order),
+        }
+        match seller.recv_timeout(timeout).unwrap() {
+            seller::Message::OfferRejected(offer) => {
+                assert_eq!(rejected_id, offer.id)
+            }
+            _ => panic!("Expected a rejected offer"),
+        }
+        assert!(marketplace.is_empty());
+
+        // The kill switch cancels open orders and rejects new ones until the
+        // breaker is re-armed.
+        input.send(Message::KillSwitch).unwrap();
+        assert!(matches!(
+            marketplace.recv_timeout(timeout).unwrap(),
+            Order::CancelAll
+        ));
+        input.send(Message::from(offer(1, 100, 200))).unwrap();
+        assert!(seller.recv_timeout(timeout).is_ok());
+        assert_eq!(Err(Trip::KillSwitch), breaker.check(Utc::now()));
+
+        breaker.rearm();
+        input.send(Message::from(offer(1, 100, 200))).unwrap();
+        assert!(matches!(
+            marketplace.recv_timeout(timeout).unwrap(),
+            Order::Sell(_)
+        ));
+    }
+
+    #[test]
+    fn should_return_rejected_bids_to_buyer() {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(500, 0), Btc::new(2, 0)));
+        let manager = RiskManager::new(
+            limits(),
+            Arc::new(clock),
+            wallet.clone(),
+            CircuitBreaker::default(),
+        );
+        let (input, risk_input) = unbounded();
+        let (risk_output, marketplace) = unbounded::<Order>();
+        let (risk_feedback, _seller) = unbounded();
+        let (buyer_feedback, buyer) = unbounded();
+        spawn(
+            risk_input,
+            risk_output,
+            risk_feedback,
+            Some(buyer_feedback),
+            manager,
+        );
+
+        // Buying 1 BTC would bring us to 3 BTC. The buyer releases the cash.
+        let bid = Bid::new(Cash::new(500, 0), BtcExchangeRate::new(500, 0));
+        wallet.lock().reserve_cash(bid.id, bid.cash).unwrap();
+        input.send(Message::from(bid)).unwrap();
+        let timeout = time::Duration::from_millis(100);
+        match buyer.recv_timeout(timeout).unwrap() {
+            buyer::Message::BidRejected(rejected) => {
+                assert_eq!(bid.id, rejected.id)
+            }
+        }
+        assert!(marketplace.is_empty());
+        assert_eq!(Cash::new(0, 0), wallet.lock().available_cash());
+    }
+
+    #[test]
+    fn should_pull_kill_switch_while_kill_file_exists() -> Result<()> {
+        let path = env::temp_dir().join(Uuid::new_v4().to_string());
+        let breaker = CircuitBreaker::default();
+        let (output, input) = unbounded();
+        let interval = time::Duration::from_millis(1);
+        watch(path.clone(), output, breaker.clone(), interval);
+
+        let timeout = time::Duration::from_millis(100);
+        assert!(input.recv_timeout(timeout).is_err());
+        fs::write(&path, "")?;
+        assert!(matches!(
+            input.recv_timeout(timeout).unwrap(),
+            Message::KillSwitch
+        ));
+        // The switch is pulled only once.
+        breaker.kill();
+        assert!(input.recv_timeout(timeout).is_err());
+
+        fs::remove_file(&path)?;
+        let started = time::Instant::now();
+        while breaker.check(Utc::now()).is_err() {
+            assert!(started.elapsed() < timeout, "The breaker wasn't re-armed");
+            thread::sleep(interval);
+        }
+
+        Ok(())
+    }
+}
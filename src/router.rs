@@ -0,0 +1,1329 @@
+//! Router is an actor which places the orders that passed the risk checks at
+//! the marketplaces. When we trade at several exchanges, each offer goes to
+//! the exchange where we get the most for the bitcoins after its fee. An
+//! offer can only be sold where its bitcoins are, therefore the router keeps
+//! track of which exchange holds which purchases. Offers are split across
+//! exchanges when no single one holds all of their bitcoins.
+//!
+//! The rate of each order is set by the [`pricing`](crate::pricing). The
+//! router polls the status of every order it placed, along with the books of
+//! the exchanges when offers are priced at the best ask. Filled orders are
+//! settled in the wallet and filled bids become purchases. Offers which rest
+//! in the books for too long are repriced or returned to the seller.
+
+use {
+    chrono::{DateTime, Utc},
+    crossbeam_channel::RecvTimeoutError,
+    crossbeam_channel::{Receiver, Sender},
+    rust_decimal::RoundingStrategy,
+    std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        thread, time,
+    },
+    uuid::Uuid,
+};
+
+use crate::{
+    clock::Clock,
+    marketplaces::{Execution, Marketplace, OrderStatus},
+    models::{Balances, Bid, Fee, Metadata, Offer, Order, Purchase},
+    prelude::*,
+    pricing::{self, Anchor, Expiry, TopOfBook},
+    reconcile::Reconciler,
+    risk, seller,
+    wallet::SharedWallet,
+};
+
+// How often do we ask the exchanges about the orders we placed.
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(30);
+
+pub enum Message {
+    /// An order which passed the risk checks.
+    Order(Order),
+    /// The latest rate a venue traded at.
+    Quote {
+        venue: String,
+        rate: BtcExchangeRate,
+    },
+    /// Bitcoins of a purchase arrived at a venue.
+    Deposit {
+        venue: String,
+        purchase: Uuid,
+        btc: Btc,
+    },
+}
+
+/// An exchange we trade at and the bitcoins we hold there.
+pub struct Venue {
+    // The name the trend sources use for the exchange.
+    name: String,
+    marketplace: Box<dyn Marketplace>,
+    // The selling fee of the exchange.
+    fee: Fee,
+    // The cash at the exchange which isn't in our bids.
+    cash: Cash,
+    // The rate the exchange last traded at, if we've heard of any trade.
+    quote: Option<BtcExchangeRate>,
+    // The top of the order book of the exchange, if we know it.
+    book: Option<TopOfBook>,
+    // How many bitcoins of each purchase are held at the exchange.
+    lots: HashMap<Uuid, Btc>,
+    // Bitcoins at the exchange which we don't know the purchase of, e.g.
+    // deposited before the broker started.
+    free: Btc,
+}
+
+/// Decides where each order is placed.
+pub struct Router {
+    venues: Vec<Venue>,
+    pricing: pricing::Config,
+    clock: Arc<dyn Clock>,
+    // The offers and bids are reserved in the wallet under their id. The
+    // router moves the reservations to the orders it places.
+    wallet: SharedWallet,
+    // Which venue and under which exchange id was each order placed.
+    placed: HashMap<Uuid, (usize, String)>,
+    // The exchange ids of all orders we've ever placed, so that we can tell
+    // our trades from the others.
+    orders: HashSet<String>,
+    // The offers which haven't been settled yet, keyed by their id.
+    resting: HashMap<Uuid, Resting>,
+    // The bids which haven't been settled yet, with the bitcoins we ordered,
+    // keyed by their id.
+    bids: HashMap<Uuid, (Bid, Btc)>,
+    // When did we last poll the status of the orders.
+    polled_at: Option<DateTime<Utc>>,
+}
+
+/// What the router learnt by polling the orders it placed.
+#[derive(Debug, Default)]
+pub struct Polled {
+    /// Offers, or their unfilled parts, which return to the seller.
+    pub returned: Vec<Offer>,
+    /// The unfilled parts of expired offers which are to be placed again at
+    /// the current price. Like any other offer, they have to pass the risk
+    /// manager first.
+    pub repriced: Vec<Offer>,
+    /// The purchases our filled bids made.
+    pub bought: Vec<Purchase>,
+    /// The profit, or loss if negative, each filled offer realised after the
+    /// fee.
+    pub pnl: Vec<Cash>,
+}
+
+// An offer in the book of an exchange.
+struct Resting {
+    offer: Offer,
+    // The rate the seller decided to sell at, we never ask less.
+    floor: BtcExchangeRate,
+    // Liquidations are never repriced at their stale floor.
+    liquidation: bool,
+    since: DateTime<Utc>,
+}
+
+/// Spawns a new thread which runs the router. The parts of offers which
+/// cannot be placed anywhere are returned to the seller. Between the messages
+/// the books are reconciled with the exchanges whenever due, and imported
+/// purchases are sent to the seller. So are the purchases of filled bids and
+/// the offers which expired, unless they're repriced. Those are sent to the
+/// risk manager, which passes them back to the router if they may be placed,
+/// along with the profit or loss of filled offers.
+pub fn spawn(
+    input: Receiver<Message>,
+    seller: Sender<seller::Message>,
+    risk: Sender<risk::Message>,
+    mut router: Router,
+    mut reconciler: Reconciler,
+) {
+    let interval = router
+        .pricing
+        .timeout
+        .map_or(reconciler.interval(), |timeout| {
+            timeout.min(reconciler.interval())
+        })
+        .to_std()
+        .expect("The reconciliation interval and timeout must be positive")
+        .min(POLL_INTERVAL);
+    thread::spawn(move || loop {
+        let message = match input.recv_timeout(interval) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                log::error!("The router's input channel died. Stopping ...");
+                break;
+            }
+        };
+
+        if reconciler.is_due() {
+            let report = reconciler.run(&mut router);
+            let imported = report.imported.into_iter();
+            let sent = imported
+                .map(seller::Message::NewPurchase)
+                .all(|message| seller.send(message).is_ok());
+            if !sent {
+                log::error!("The seller's input channel died. Stopping ...");
+                break;
+            }
+        }
+
+        let polled = router.poll();
+        let settled = polled.pnl.into_iter();
+        let repriced = polled.repriced.into_iter();
+        let sent = settled
+            .map(|pnl| risk::Message::Settled { pnl })
+            .chain(repriced.map(risk::Message::from))
+            .all(|message| risk.send(message).is_ok());
+        if !sent {
+            log::error!("The risk manager's input channel died. Stopping ...");
+            break;
+        }
+        let returned = polled.returned.into_iter();
+        let bought = polled.bought.into_iter();
+        let sent = returned
+            .map(seller::Message::OfferExpired)
+            .chain(bought.map(seller::Message::NewPurchase))
+            .all(|message| seller.send(message).is_ok());
+        if !sent {
+            log::error!("The seller's input channel died. Stopping ...");
+            break;
+        }
+
+        let message = if let Some(message) = message {
+            message
+        } else {
+            continue;
+        };
+        let rejected = match message {
+            Message::Order(Order::Sell(offer)) => {
+                // Each rung of the ladder counts towards the hourly limit.
+                let open = router.placed.len();
+                let rejected = router.place_offer(offer);
+                let orders = router.placed.len() - open;
+                let placed = risk::Message::Placed { orders };
+                if orders > 1 && risk.send(placed).is_err() {
+                    log::error!(
+                        "The risk manager's input channel died. Stopping ..."
+                    );
+                    break;
+                }
+                rejected
+            }
+            Message::Order(Order::Buy(bid)) => {
+                router.place_bid(bid);
+                None
+            }
+            Message::Order(Order::CancelAll) => {
+                router.cancel_all();
+                None
+            }
+            Message::Quote { venue, rate } => {
+                router.quote(&venue, rate);
+                None
+            }
+            Message::Deposit {
+                venue,
+                purchase,
+                btc,
+            } => {
+                router.deposit(&venue, purchase, btc);
+                None
+            }
+        };
+
+        if let Some(offer) = rejected {
+            let message = seller::Message::OfferRejected(offer);
+            if seller.send(message).is_err() {
+                log::error!("The seller's input channel died. Stopping ...");
+                break;
+            }
+        }
+    });
+}
+
+impl Venue {
+    /// The cash and bitcoins we hold at the exchange are read from its
+    /// balances, all bitcoins are at first considered free.
+    pub fn new(
+        name: impl Into<String>,
+        mut marketplace: Box<dyn Marketplace>,
+        fee: Fee,
+    ) -> Result<Self> {
+        let balances = marketplace.balances()?;
+        Ok(Self {
+            name: name.into(),
+            marketplace,
+            fee,
+            cash: balances.cash,
+            quote: None,
+            book: None,
+            lots: HashMap::new(),
+            free: balances.btc,
+        })
+    }
+
+    /// How many bitcoins do we hold at the exchange.
+    pub fn btc(&self) -> Btc {
+        self.free + self.lots.values().copied().sum::<Btc>()
+    }
+
+    // At what rate would we sell at the exchange. We never sell below the
+    // floor, the rate the seller decided to sell at.
+    fn rate(
+        &self,
+        pricing: &pricing::Config,
+        floor: BtcExchangeRate,
+    ) -> BtcExchangeRate {
+        pricing.price(floor, self.quote, self.book)
+    }
+
+    // How much cash do we get for one bitcoin after the fee.
+    fn net(&self, rate: BtcExchangeRate) -> Cash {
+        match self.fee {
+            Fee::Percentage(p) => rate - rate / Decimal::new(100, 0) * p,
+            Fee::None => rate,
+        }
+    }
+
+    // How much cash do we pay for one bitcoin including the fee.
+    fn gross(&self, rate: BtcExchangeRate) -> Cash {
+        match self.fee {
+            Fee::Percentage(p) => rate + rate / Decimal::new(100, 0) * p,
+            Fee::None => rate,
+        }
+    }
+}
+
+impl Router {
+    pub fn new(
+        venues: Vec<Venue>,
+        pricing: pricing::Config,
+        clock: Arc<dyn Clock>,
+        wallet: SharedWallet,
+    ) -> Self {
+        Self {
+            venues,
+            pricing,
+            clock,
+            wallet,
+            placed: HashMap::new(),
+            orders: HashSet::new(),
+            resting: HashMap::new(),
+            bids: HashMap::new(),
+            polled_at: None,
+        }
+    }
+
+    /// Whether the exchange order id belongs to an order we placed.
+    pub fn is_ours(&self, order_id: &str) -> bool {
+        self.orders.contains(order_id)
+    }
+
+    /// Our trades at each venue since given time.
+    pub fn fills(
+        &mut self,
+        since: DateTime<Utc>,
+    ) -> Vec<(String, Result<Vec<Execution>>)> {
+        self.venues
+            .iter_mut()
+            .map(|venue| (venue.name.clone(), venue.marketplace.fills(since)))
+            .collect()
+    }
+
+    /// The balances we hold at each venue.
+    pub fn balances(&mut self) -> Vec<(String, Result<Balances>)> {
+        self.venues
+            .iter_mut()
+            .map(|venue| (venue.name.clone(), venue.marketplace.balances()))
+            .collect()
+    }
+
+    /// How many bitcoins of given purchase are held at given venue.
+    pub fn inventory(&self, venue: &str, purchase: Uuid) -> Btc {
+        self.venue(venue)
+            .and_then(|index| self.venues[index].lots.get(&purchase))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Which venue and under which exchange id was an order placed.
+    pub fn placement(&self, order: Uuid) -> Option<(&str, &str)> {
+        self.placed.get(&order).map(|(index, order_id)| {
+            (self.venues[*index].name.as_str(), order_id.as_str())
+        })
+    }
+
+    /// Records the latest rate a venue traded at.
+    pub fn quote(&mut self, venue: &str, rate: BtcExchangeRate) {
+        if let Some(index) = self.venue(venue) {
+            self.venues[index].quote = Some(rate);
+        }
+    }
+
+    /// Records that bitcoins of a purchase are held at a venue. They are
+    /// taken from the free bitcoins of the venue if there are any, because
+    /// the balance we've read might already include them.
+    pub fn deposit(&mut self, venue: &str, purchase: Uuid, btc: Btc) {
+        let index = if let Some(index) = self.venue(venue) {
+            index
+        } else {
+            log::warn!("Deposit of {} BTC to unknown venue {}", btc, venue);
+            return;
+        };
+
+        let venue = &mut self.venues[index];
+        venue.free = (venue.free - btc).max(Btc::new(0, 0));
+        *venue.lots.entry(purchase).or_default() += btc;
+    }
+
+    /// Places the offer at the venues with the best net rate which hold its
+    /// bitcoins. Returns the part of the offer which could not be placed.
+    pub fn place_offer(&mut self, offer: Offer) -> Option<Offer> {
+        let (routes, mut rejected) = self.route(&offer);
+
+        // The reservation moves from the offer to the orders we place.
+        self.wallet.lock().release(offer.id);
+        for (index, child) in routes {
+            for (markup, purchases) in self.pricing.ladder(child.purchases) {
+                let rung = Offer {
+                    liquidation: offer.liquidation,
+                    ..Offer::new(pricing::markup(child.rate, markup), purchases)
+                };
+                rejected.extend(self.place(index, rung, offer.rate));
+            }
+        }
+
+        if rejected.is_empty() {
+            None
+        } else {
+            Some(Offer::new(offer.rate, rejected))
+        }
+    }
+
+    /// Checks the status of the orders we've placed, at most once per poll
+    /// interval. Filled orders are settled in the wallet, filled bids become
+    /// purchases held at their venue. Offers which were cancelled are
+    /// settled for the bitcoins which were filled and the rest returns to
+    /// the seller. So does the rest of offers which rested in the books for
+    /// longer than the timeout, unless it's to be repriced.
+    pub fn poll(&mut self) -> Polled {
+        let now = self.clock.now();
+        let interval = chrono::Duration::from_std(POLL_INTERVAL)
+            .expect("The poll interval is within range");
+        if self.polled_at.is_some_and(|at| now - at < interval) {
+            return Polled::default();
+        }
+        self.polled_at = Some(now);
+        // The books are only needed to price offers at the best ask, they're
+        // read before the expired offers are repriced.
+        if self.pricing.anchor == Anchor::BestAsk {
+            self.read_books();
+        }
+
+        let mut polled = Polled::default();
+        let offers: Vec<_> = self.resting.keys().copied().collect();
+        for id in offers {
+            self.poll_offer(id, now, &mut polled);
+        }
+        let bids: Vec<_> = self.bids.keys().copied().collect();
+        for id in bids {
+            if let Some(purchase) = self.poll_bid(id, now) {
+                polled.bought.push(purchase);
+            }
+        }
+
+        polled
+    }
+
+    /// Places the bid at the venue where the bitcoins are cheapest after
+    /// the fee, among those which hold enough cash for it.
+    pub fn place_bid(&mut self, bid: Bid) {
+        let best = (0..self.venues.len())
+            .filter(|index| self.venues[*index].cash >= bid.cash)
+            .min_by_key(|index| {
+                let venue = &self.venues[*index];
+                venue.gross(venue.quote.unwrap_or(bid.rate))
+            });
+        let index = if let Some(index) = best {
+            index
+        } else {
+            log::warn!("No venue has ${} for bid {}", bid.cash, bid.id);
+            self.wallet.lock().release(bid.id);
+            return;
+        };
+
+        let venue = &mut self.venues[index];
+        // The cash of the bid includes the fee. We never order more than it
+        // pays for.
+        let btc = (bid.cash / venue.gross(bid.rate))
+            .round_dp_with_strategy(8, RoundingStrategy::RoundDown);
+        match venue.marketplace.place_bid(&bid, btc) {
+            Ok(order_id) => {
+                log::info!(
+                    "Placed bid {} of ${} for {} BTC at {} for {} as {}",
+                    bid.id,
+                    bid.cash,
+                    btc,
+                    bid.rate,
+                    venue.name,
+                    order_id
+                );
+                venue.cash -= bid.cash;
+                self.orders.insert(order_id.clone());
+                self.placed.insert(bid.id, (index, order_id));
+                self.bids.insert(bid.id, (bid, btc));
+            }
+            Err(e) => {
+                log::warn!("Failed to place bid at {}: {}", venue.name, e);
+                self.wallet.lock().release(bid.id);
+            }
+        }
+    }
+
+    /// Cancels all orders we've placed. They're settled once we poll them
+    /// and the exchanges confirm they were cancelled, so that we account for
+    /// whatever was filled in the meantime.
+    pub fn cancel_all(&mut self) {
+        for (id, (index, order_id)) in &self.placed {
+            let venue = &mut self.venues[*index];
+            if let Err(e) = venue.marketplace.cancel(order_id) {
+                log::error!(
+                    "Failed to cancel {} at {}: {}",
+                    order_id,
+                    venue.name,
+                    e
+                );
+            } else {
+                log::info!("Cancelled {} at {}", id, venue.name);
+            }
+        }
+    }
+
+    // Reads the best rates in the books of all venues. A venue whose book we
+    // cannot read prices at the trend until we can.
+    fn read_books(&mut self) {
+        for venue in &mut self.venues {
+            venue.book = match venue.marketplace.top_of_book() {
+                Ok(book) => Some(book),
+                Err(e) => {
+                    log::warn!("Cannot read book of {}: {}", venue.name, e);
+                    None
+                }
+            };
+        }
+    }
+
+    // Reads the status of the offer and settles it if it's no longer in the
+    // book, or if it expired. The profit it realised and its unfilled part,
+    // if it returns to the seller, are added to what we polled.
+    fn poll_offer(
+        &mut self,
+        id: Uuid,
+        now: DateTime<Utc>,
+        polled: &mut Polled,
+    ) {
+        let (index, order_id) = self.placed[&id].clone();
+        let expired = self
+            .pricing
+            .timeout
+            .is_some_and(|timeout| now - self.resting[&id].since >= timeout);
+        let venue = &mut self.venues[index];
+        let (filled, cancelled) =
+            match venue.marketplace.order_status(&order_id) {
+                Ok(OrderStatus::Filled) => (None, false),
+                Ok(OrderStatus::Open { .. }) if !expired => return,
+                Ok(OrderStatus::Open { filled }) => {
+                    if let Err(e) = venue.marketplace.cancel(&order_id) {
+                        // It's checked again next time.
+                        log::warn!(
+                            "Failed to cancel expired {} at {}: {}",
+                            order_id,
+                            venue.name,
+                            e
+                        );
+                        return;
+                    }
+                    (Some(filled), false)
+                }
+                Ok(OrderStatus::Cancelled { filled }) => (Some(filled), true),
+                Err(e) => {
+                    log::warn!(
+                        "Cannot read status of {} at {}: {}",
+                        order_id,
+                        venue.name,
+                        e
+                    );
+                    return;
+                }
+            };
+
+        // It's safe to unwrap because the ids come from the resting offers.
+        let Resting {
+            offer,
+            floor,
+            liquidation,
+            ..
+        } = self.resting.remove(&id).unwrap();
+        self.placed.remove(&id);
+        let filled = filled.unwrap_or_else(|| offer.btc());
+        let received = filled * venue.net(offer.rate);
+        venue.cash += received;
+        self.wallet.lock().settle_sell(id, filled, received);
+        let bought_for: Cash =
+            offer.purchases.iter().map(Purchase::buying_price).sum();
+        let unfilled = unfilled(offer.purchases, filled);
+        if filled > Btc::new(0, 0) {
+            let unsold: Cash =
+                unfilled.iter().map(Purchase::buying_price).sum();
+            let pnl = received - (bought_for - unsold);
+            log::info!(
+                "Offer {} at {} realised ${}",
+                id,
+                venue.name,
+                pnl.round_dp(2)
+            );
+            polled.pnl.push(pnl);
+        }
+        if unfilled.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "Offer {} at {} {} with {} BTC unfilled",
+            id,
+            venue.name,
+            if cancelled {
+                "was cancelled"
+            } else {
+                "expired"
+            },
+            unfilled.iter().map(|p| p.btc).sum::<Btc>()
+        );
+        // The bitcoins are still held at the venue.
+        for purchase in &unfilled {
+            *venue.lots.entry(purchase.id).or_default() += purchase.btc;
+        }
+        let offer = Offer::new(floor, unfilled);
+        match self.pricing.expiry {
+            // Offers cancelled by the exchange or by the kill switch go back
+            // to the seller. So do liquidations, which the seller offers at
+            // the trend again rather than at their stale floor.
+            Expiry::Reprice if !cancelled && !liquidation => {
+                polled.repriced.push(offer)
+            }
+            _ => polled.returned.push(offer),
+        }
+    }
+
+    // Reads the status of the bid and settles it once it's no longer in the
+    // book. Returns the purchase the bid made, if it was filled at all. The
+    // purchase is held at the bid's venue.
+    fn poll_bid(&mut self, id: Uuid, now: DateTime<Utc>) -> Option<Purchase> {
+        let (index, order_id) = self.placed[&id].clone();
+        let venue = &mut self.venues[index];
+        let filled = match venue.marketplace.order_status(&order_id) {
+            Ok(OrderStatus::Filled) => None,
+            Ok(OrderStatus::Open { .. }) => return None,
+            Ok(OrderStatus::Cancelled { filled }) => Some(filled),
+            Err(e) => {
+                log::warn!(
+                    "Cannot read status of {} at {}: {}",
+                    order_id,
+                    venue.name,
+                    e
+                );
+                return None;
+            }
+        };
+
+        // It's safe to unwrap because the ids come from the bids.
+        let (bid, ordered) = self.bids.remove(&id).unwrap();
+        self.placed.remove(&id);
+        let rate = venue.gross(bid.rate);
+        let btc = filled.unwrap_or(ordered);
+        let spent = btc * rate;
+        venue.cash += bid.cash - spent;
+        self.wallet.lock().settle_buy(id, spent, btc);
+        if btc <= Btc::new(0, 0) {
+            log::info!("Bid {} at {} was cancelled unfilled", id, venue.name);
+            return None;
+        }
+
+        let metadata = Metadata {
+            bought_at: Some(now),
+            venue: Some(venue.name.clone()),
+            order_id: Some(order_id),
+            fee: spent - btc * bid.rate,
+            ..Metadata::default()
+        };
+        let purchase = Purchase::with_metadata(btc, rate, metadata);
+        log::info!(
+            "Bid {} at {} bought {} BTC at {} as purchase {}",
+            id,
+            venue.name,
+            btc,
+            rate,
+            purchase.id
+        );
+        *venue.lots.entry(purchase.id).or_default() += btc;
+
+        Some(purchase)
+    }
+
+    // Places the offer of a rung at given venue and moves the reservation to
+    // it. Returns the purchases of the offer if it failed.
+    fn place(
+        &mut self,
+        index: usize,
+        offer: Offer,
+        floor: BtcExchangeRate,
+    ) -> Vec<Purchase> {
+        let venue = &mut self.venues[index];
+        let order_id = match venue.marketplace.place_offer(&offer) {
+            Ok(order_id) => order_id,
+            Err(e) => {
+                log::warn!("Failed to place offer at {}: {}", venue.name, e);
+                // The bitcoins are still held at the venue.
+                for purchase in &offer.purchases {
+                    *venue.lots.entry(purchase.id).or_default() += purchase.btc;
+                }
+                return offer.purchases;
+            }
+        };
+
+        log::info!(
+            "Placed offer {} of {} BTC at {} for {} as {}",
+            offer.id,
+            offer.btc(),
+            offer.rate,
+            venue.name,
+            order_id
+        );
+        if let Err(e) = self.wallet.lock().reserve_btc(offer.id, offer.btc()) {
+            log::error!("Offer {} is not reserved: {}", offer.id, e);
+        }
+        self.orders.insert(order_id.clone());
+        self.placed.insert(offer.id, (index, order_id));
+        let since = self.clock.now();
+        let resting = Resting {
+            liquidation: offer.liquidation,
+            offer,
+            floor,
+            since,
+        };
+        self.resting.insert(resting.offer.id, resting);
+
+        Vec::new()
+    }
+
+    // Splits the offer's purchases across the venues, best net rate first.
+    // Bitcoins of each purchase are first taken from the venues which are
+    // known to hold them, then from the free bitcoins. Returns an offer for
+    // each venue and the purchases which no venue has bitcoins for.
+    fn route(&mut self, offer: &Offer) -> (Vec<(usize, Offer)>, Vec<Purchase>) {
+        let mut ranking: Vec<_> = (0..self.venues.len()).collect();
+        ranking.sort_by_key(|index| {
+            let venue = &self.venues[*index];
+            std::cmp::Reverse(venue.net(venue.rate(&self.pricing, offer.rate)))
+        });
+
+        let mut routed: Vec<Vec<Purchase>> =
+            self.venues.iter().map(|_| Vec::new()).collect();
+        let mut unrouted = Vec::new();
+        for purchase in &offer.purchases {
+            let mut remaining = purchase.btc;
+            for index in &ranking {
+                let venue = &mut self.venues[*index];
+                let held =
+                    venue.lots.get(&purchase.id).copied().unwrap_or_default();
+                let btc = remaining.min(held);
+                if btc > Btc::new(0, 0) {
+                    if btc == held {
+                        venue.lots.remove(&purchase.id);
+                    } else {
+                        venue.lots.insert(purchase.id, held - btc);
+                    }
+                    remaining -= btc;
+                    routed[*index].push(purchase.part(btc));
+                }
+            }
+            for index in &ranking {
+                let venue = &mut self.venues[*index];
+                let btc = remaining.min(venue.free);
+                if btc > Btc::new(0, 0) {
+                    venue.free -= btc;
+                    remaining -= btc;
+                    routed[*index].push(purchase.part(btc));
+                }
+            }
+            if remaining > Btc::new(0, 0) {
+                unrouted.push(purchase.part(remaining));
+            }
+        }
+
+        let routes = routed
+            .into_iter()
+            .enumerate()
+            .filter(|(_, purchases)| !purchases.is_empty())
+            .map(|(index, purchases)| {
+                let rate = self.venues[index].rate(&self.pricing, offer.rate);
+                (index, Offer::new(rate, purchases))
+            })
+            .collect();
+
+        (routes, unrouted)
+    }
+
+    fn venue(&self, name: &str) -> Option<usize> {
+        self.venues.iter().position(|venue| venue.name == name)
+    }
+}
+
+impl From<Order> for Message {
+    fn from(order: Order) -> Self {
+        Self::Order(order)
+    }
+}
+
+// The parts of the purchases which were not sold when given bitcoins of the
+// offer were filled. The purchases are filled in order.
+fn unfilled(purchases: Vec<Purchase>, mut filled: Btc) -> Vec<Purchase> {
+    purchases
+        .into_iter()
+        .filter_map(|purchase| {
+            let sold = filled.min(purchase.btc);
+            filled -= sold;
+            if sold < purchase.btc {
+                Some(purchase.part(purchase.btc - sold))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        chrono::{DateTime, Duration, TimeZone, Utc},
+        crossbeam_channel::unbounded,
+        std::{sync::Mutex, time},
+    };
+
+    use super::*;
+    use crate::{
+        breaker::CircuitBreaker,
+        clock::SimulatedClock,
+        marketplaces::{Execution, OrderStatus},
+        models::Balances,
+        pricing::{Anchor, Rung},
+        reconcile,
+        wallet::Wallet,
+    };
+
+    // The rate and bitcoins of each offer placed at a venue.
+    type Placed = Arc<Mutex<Vec<(BtcExchangeRate, Btc)>>>;
+
+    // The top of the book of a venue, if it tells us.
+    type Book = Arc<Mutex<Option<TopOfBook>>>;
+
+    // Remembers the orders placed at it and fails to place offers if told
+    // so. All orders are in given status.
+    struct Fake {
+        cash: Cash,
+        btc: Btc,
+        offers: Placed,
+        fail: bool,
+        status: OrderStatus,
+        book: Book,
+    }
+
+    impl Marketplace for Fake {
+        fn balances(&mut self) -> Result<Balances> {
+            Ok(Balances {
+                cash: self.cash,
+                btc: self.btc,
+            })
+        }
+
+        fn place_offer(&mut self, offer: &Offer) -> Result<String> {
+            if self.fail {
+                return Err(Box::new(Error::invalid_data("Maintenance")));
+            }
+            let mut offers = self.offers.lock().unwrap();
+            offers.push((offer.rate, offer.btc()));
+            Ok(offers.len().to_string())
+        }
+
+        fn place_bid(&mut self, bid: &Bid, btc: Btc) -> Result<String> {
+            let mut offers = self.offers.lock().unwrap();
+            offers.push((bid.rate, btc));
+            Ok(offers.len().to_string())
+        }
+
+        fn cancel(&mut self, _: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn order_status(&mut self, _: &str) -> Result<OrderStatus> {
+            Ok(self.status)
+        }
+
+        fn fills(&mut self, _: DateTime<Utc>) -> Result<Vec<Execution>> {
+            Ok(Vec::new())
+        }
+
+        fn top_of_book(&mut self) -> Result<TopOfBook> {
+            let book = *self.book.lock().unwrap();
+            book.ok_or_else(|| Error::invalid_data("The book is empty").into())
+        }
+    }
+
+    fn venue(name: &str, btc: i64, fee: i64, fail: bool) -> (Venue, Placed) {
+        let offers = Arc::new(Mutex::new(Vec::new()));
+        let fake = Fake {
+            cash: Cash::new(0, 0),
+            btc: Btc::new(btc, 1),
+            offers: Arc::clone(&offers),
+            fail,
+            status: OrderStatus::Open {
+                filled: Btc::new(0, 0),
+            },
+            book: Book::default(),
+        };
+        let fee = Fee::Percentage(Percentage::new(fee, 2));
+        (Venue::new(name, Box::new(fake), fee).unwrap(), offers)
+    }
+
+    #[test]
+    fn should_route_offers_to_best_net_rate() {
+        // Kraken holds 0.5 BTC, Bitstamp and Coinbase 0.2 BTC each, but
+        // Coinbase fails to place orders.
+        let (kraken, kraken_offers) = venue("kraken", 5, 26, false);
+        let (bitstamp, bitstamp_offers) = venue("bitstamp", 2, 50, false);
+        let (coinbase, _) = venue("coinbase", 2, 0, true);
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(9, 1)));
+        let clock = SimulatedClock::new(Utc::now());
+        let mut router = Router::new(
+            vec![kraken, bitstamp, coinbase],
+            pricing::Config::default(),
+            Arc::new(clock),
+            wallet.clone(),
+        );
+
+        let a = Purchase::new(Btc::new(4, 1), BtcExchangeRate::new(8_000, 0));
+        let b = Purchase::new(Btc::new(2, 1), BtcExchangeRate::new(8_500, 0));
+        let c = Purchase::new(Btc::new(3, 1), BtcExchangeRate::new(9_000, 0));
+        router.deposit("kraken", a.id, a.btc);
+        assert_eq!(a.btc, router.inventory("kraken", a.id));
+        router.deposit("bitstamp", c.id, Btc::new(1, 1));
+        router.deposit("coinbase", b.id, b.btc);
+
+        // After the fee Coinbase nets 10_200, Bitstamp 10_049.5 and Kraken
+        // 9_974.
+        router.quote("kraken", BtcExchangeRate::new(10_000, 0));
+        router.quote("bitstamp", BtcExchangeRate::new(10_100, 0));
+        router.quote("coinbase", BtcExchangeRate::new(10_200, 0));
+
+        let offer = Offer::new(
+            BtcExchangeRate::new(9_900, 0),
+            vec![a.clone(), b.clone(), c.clone()],
+        );
+        wallet.lock().reserve_btc(offer.id, offer.btc()).unwrap();
+        let rejected = router.place_offer(offer).unwrap();
+
+        // Kraken sells a and Coinbase would sell b as they hold them. The
+        // 0.1 BTC of c held at Bitstamp is sold there, the rest of c is
+        // covered by the free bitcoins of Bitstamp and then Kraken.
+        assert_eq!(
+            vec![(BtcExchangeRate::new(10_100, 0), Btc::new(2, 1))],
+            *bitstamp_offers.lock().unwrap()
+        );
+        assert_eq!(
+            vec![(BtcExchangeRate::new(10_000, 0), Btc::new(5, 1))],
+            *kraken_offers.lock().unwrap()
+        );
+        assert_eq!(vec![b.clone()], rejected.purchases);
+        assert_eq!(Btc::new(0, 0), router.inventory("kraken", a.id));
+        assert_eq!(Btc::new(2, 1), router.inventory("coinbase", b.id));
+
+        // Only the placed bitcoins are still reserved.
+        assert_eq!(Btc::new(2, 1), wallet.lock().available_btc());
+    }
+
+    #[test]
+    fn should_reprice_offers_which_are_not_filled_in_time() {
+        let offers = Arc::new(Mutex::new(Vec::new()));
+        let book = Book::default();
+        let fake = Fake {
+            cash: Cash::new(0, 0),
+            btc: Btc::new(5, 1),
+            offers: Arc::clone(&offers),
+            fail: false,
+            status: OrderStatus::Open {
+                filled: Btc::new(1, 1),
+            },
+            book: Arc::clone(&book),
+        };
+        let kraken = Venue::new("kraken", Box::new(fake), Fee::None).unwrap();
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(5, 1)));
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap(),
+        );
+        let pricing = pricing::Config {
+            anchor: Anchor::BestAsk,
+            premium: Percentage::new(1, 1),
+            timeout: Some(Duration::minutes(10)),
+            expiry: Expiry::Reprice,
+            ladder: Vec::new(),
+        };
+        let mut router = Router::new(
+            vec![kraken],
+            pricing,
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+        *book.lock().unwrap() = Some(TopOfBook {
+            bid: BtcExchangeRate::new(9_990, 0),
+            ask: BtcExchangeRate::new(10_010, 0),
+        });
+        // The book is read when the router polls.
+        assert!(router.poll().returned.is_empty());
+
+        let a = Purchase::new(Btc::new(2, 1), BtcExchangeRate::new(8_000, 0));
+        let b = Purchase::new(Btc::new(1, 1), BtcExchangeRate::new(8_500, 0));
+        let offer = Offer::new(
+            BtcExchangeRate::new(9_900, 0),
+            vec![a.clone(), b.clone()],
+        );
+        wallet.lock().reserve_btc(offer.id, offer.btc()).unwrap();
+        assert!(router.place_offer(offer).is_none());
+
+        // The offer asks 0.1 % over the best ask.
+        clock.advance(Duration::minutes(9));
+        assert!(router.poll().returned.is_empty());
+        assert_eq!(
+            vec![(BtcExchangeRate::new(1_002_001, 2), Btc::new(3, 1))],
+            *offers.lock().unwrap()
+        );
+
+        // 0.1 BTC of a was sold, the rest is repriced at the new best ask
+        // once the risk manager passes it back.
+        *book.lock().unwrap() = Some(TopOfBook {
+            bid: BtcExchangeRate::new(9_940, 0),
+            ask: BtcExchangeRate::new(9_950, 0),
+        });
+        clock.advance(Duration::minutes(1));
+        let polled = router.poll();
+        assert!(polled.returned.is_empty());
+        assert_eq!(1, polled.repriced.len());
+        assert_eq!(BtcExchangeRate::new(9_900, 0), polled.repriced[0].rate);
+        assert_eq!(1, offers.lock().unwrap().len());
+        for offer in polled.repriced {
+            assert!(router.place_offer(offer).is_none());
+        }
+        assert_eq!(
+            (BtcExchangeRate::new(995_995, 2), Btc::new(2, 1)),
+            offers.lock().unwrap()[1]
+        );
+        assert_eq!(Btc::new(4, 1), wallet.lock().btc());
+        assert_eq!(Cash::new(1_002_001, 3), wallet.lock().cash());
+        assert_eq!(Btc::new(2, 1), wallet.lock().available_btc());
+
+        // The book fell below the seller's rate, which is the floor.
+        *book.lock().unwrap() = Some(TopOfBook {
+            bid: BtcExchangeRate::new(9_700, 0),
+            ask: BtcExchangeRate::new(9_710, 0),
+        });
+        clock.advance(Duration::minutes(10));
+        let polled = router.poll();
+        assert!(polled.returned.is_empty());
+        for offer in polled.repriced {
+            assert!(router.place_offer(offer).is_none());
+        }
+        assert_eq!(
+            (BtcExchangeRate::new(9_900, 0), Btc::new(1, 1)),
+            offers.lock().unwrap()[2]
+        );
+    }
+
+    #[test]
+    fn should_place_and_expire_each_rung_of_the_ladder() {
+        let (kraken, offers) = venue("kraken", 10, 0, false);
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(1, 0)));
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap(),
+        );
+        let rung = |share, markup| Rung {
+            share: Percentage::new(share, 0),
+            markup: Percentage::new(markup, 0),
+        };
+        let pricing = pricing::Config {
+            timeout: Some(Duration::minutes(10)),
+            ladder: vec![rung(40, 0), rung(30, 2), rung(30, 4)],
+            ..pricing::Config::default()
+        };
+        let mut router = Router::new(
+            vec![kraken],
+            pricing,
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+
+        let a = Purchase::new(Btc::new(5, 1), BtcExchangeRate::new(8_000, 0));
+        let b = Purchase::new(Btc::new(5, 1), BtcExchangeRate::new(8_500, 0));
+        let offer =
+            Offer::new(BtcExchangeRate::new(10_000, 0), vec![a.clone(), b]);
+        wallet.lock().reserve_btc(offer.id, offer.btc()).unwrap();
+        assert!(router.place_offer(offer).is_none());
+        assert_eq!(
+            vec![
+                (BtcExchangeRate::new(10_000, 0), Btc::new(4, 1)),
+                (BtcExchangeRate::new(10_200, 0), Btc::new(3, 1)),
+                (BtcExchangeRate::new(10_400, 0), Btc::new(3, 1)),
+            ],
+            *offers.lock().unwrap()
+        );
+        assert_eq!(Btc::new(0, 0), wallet.lock().available_btc());
+
+        // Nothing was filled, each rung is returned on its own.
+        clock.advance(Duration::minutes(10));
+        let mut returned: Vec<_> =
+            router.poll().returned.iter().map(Offer::btc).collect();
+        returned.sort();
+        assert_eq!(
+            vec![Btc::new(3, 1), Btc::new(3, 1), Btc::new(4, 1)],
+            returned
+        );
+        assert_eq!(Btc::new(1, 0), wallet.lock().available_btc());
+        assert_eq!(Btc::new(5, 1), router.inventory("kraken", a.id));
+    }
+
+    #[test]
+    fn should_return_expired_liquidations_to_seller() {
+        let (kraken, offers) = venue("kraken", 10, 0, false);
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(1, 0)));
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap(),
+        );
+        let pricing = pricing::Config {
+            timeout: Some(Duration::minutes(10)),
+            expiry: Expiry::Reprice,
+            ..pricing::Config::default()
+        };
+        let mut router = Router::new(
+            vec![kraken],
+            pricing,
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+
+        let a = Purchase::new(Btc::new(1, 0), BtcExchangeRate::new(12_000, 0));
+        let offer = Offer {
+            liquidation: true,
+            ..Offer::new(BtcExchangeRate::new(10_000, 0), vec![a.clone()])
+        };
+        wallet.lock().reserve_btc(offer.id, offer.btc()).unwrap();
+        assert!(router.place_offer(offer).is_none());
+
+        // The trend fell meanwhile, the seller offers it again at the trend
+        // rather than at the stale rate.
+        clock.advance(Duration::minutes(10));
+        let returned = router.poll().returned;
+        assert_eq!(1, returned.len());
+        assert_eq!(vec![a.clone()], returned[0].purchases);
+        assert_eq!(1, offers.lock().unwrap().len());
+        assert_eq!(Btc::new(1, 0), router.inventory("kraken", a.id));
+    }
+
+    #[test]
+    fn should_settle_filled_bids_into_purchases() {
+        let bid_at = |name, cash, status| {
+            let offers = Arc::new(Mutex::new(Vec::new()));
+            let fake = Fake {
+                cash: Cash::new(cash, 0),
+                btc: Btc::new(0, 0),
+                offers: Arc::clone(&offers),
+                fail: false,
+                status,
+                book: Book::default(),
+            };
+            let fee = Fee::Percentage(Percentage::new(1, 0));
+            (Venue::new(name, Box::new(fake), fee).unwrap(), offers)
+        };
+        // Kraken is cheaper but lacks the cash for the bid.
+        let (kraken, kraken_bids) = bid_at("kraken", 500, OrderStatus::Filled);
+        let (bitstamp, bitstamp_bids) =
+            bid_at("bitstamp", 1_500, OrderStatus::Filled);
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(2_000, 0), Btc::new(0, 0)));
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap(),
+        );
+        let mut router = Router::new(
+            vec![kraken, bitstamp],
+            pricing::Config::default(),
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+        router.quote("kraken", BtcExchangeRate::new(9_000, 0));
+        router.quote("bitstamp", BtcExchangeRate::new(10_000, 0));
+
+        let bid =
+            Bid::new(Cash::new(1_010, 0), BtcExchangeRate::new(10_000, 0));
+        wallet.lock().reserve_cash(bid.id, bid.cash).unwrap();
+        router.place_bid(bid);
+        assert!(kraken_bids.lock().unwrap().is_empty());
+        assert_eq!(1, bitstamp_bids.lock().unwrap().len());
+        assert_eq!(Some(("bitstamp", "1")), router.placement(bid.id));
+
+        // The order is sized so that the fee fits into the bid's cash.
+        assert_eq!(
+            (BtcExchangeRate::new(10_000, 0), Btc::new(1, 1)),
+            bitstamp_bids.lock().unwrap()[0]
+        );
+
+        // The bid bought 0.1 BTC and paid 1 % fee.
+        let polled = router.poll();
+        assert!(polled.returned.is_empty());
+        assert_eq!(1, polled.bought.len());
+        let purchase = &polled.bought[0];
+        assert_eq!(Btc::new(1, 1), purchase.btc);
+        assert_eq!(BtcExchangeRate::new(10_100, 0), purchase.rate);
+        assert_eq!(Cash::new(10, 0), purchase.metadata.fee);
+        assert_eq!(Some("bitstamp"), purchase.metadata.venue.as_deref());
+        assert_eq!(Btc::new(1, 1), router.inventory("bitstamp", purchase.id));
+        assert_eq!(Cash::new(990, 0), wallet.lock().cash());
+        assert_eq!(Cash::new(990, 0), wallet.lock().available_cash());
+        assert_eq!(Btc::new(1, 1), wallet.lock().btc());
+        assert_eq!(None, router.placement(bid.id));
+
+        // Bitstamp has only $490 left, no venue can take another bid.
+        let bid =
+            Bid::new(Cash::new(1_000, 0), BtcExchangeRate::new(10_000, 0));
+        router.place_bid(bid);
+        assert_eq!(1, bitstamp_bids.lock().unwrap().len());
+        assert_eq!(None, router.placement(bid.id));
+
+        // The bitcoins settled are those ordered, rounded down to satoshis.
+        let bid = Bid::new(Cash::new(400, 0), BtcExchangeRate::new(9_000, 0));
+        wallet.lock().reserve_cash(bid.id, bid.cash).unwrap();
+        router.place_bid(bid);
+        let (_, ordered) = kraken_bids.lock().unwrap()[0];
+        assert_eq!(Btc::new(4_400_440, 8), ordered);
+        clock.advance(Duration::minutes(1));
+        let polled = router.poll();
+        assert_eq!(ordered, polled.bought[0].btc);
+        assert!(polled.bought[0].buying_price() <= bid.cash);
+    }
+
+    #[test]
+    fn should_trip_daily_loss_cap_once_loss_is_settled() {
+        let offers = Arc::new(Mutex::new(Vec::new()));
+        let fake = Fake {
+            cash: Cash::new(0, 0),
+            btc: Btc::new(1, 1),
+            offers: Arc::clone(&offers),
+            fail: false,
+            status: OrderStatus::Filled,
+            book: Book::default(),
+        };
+        let kraken = Venue::new("kraken", Box::new(fake), Fee::None).unwrap();
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(1, 1)));
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap(),
+        );
+        let router = Router::new(
+            vec![kraken],
+            pricing::Config::default(),
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+        let reconciler = Reconciler::new(
+            reconcile::Config {
+                interval: Duration::hours(1),
+                tolerance: Decimal::new(1, 4),
+                import: false,
+            },
+            Arc::new(clock.clone()),
+            wallet.clone(),
+        );
+        let limits = risk::Limits {
+            max_exposure: Btc::new(1, 0),
+            max_order_notional: Cash::new(5_000, 0),
+            max_orders_per_hour: 10,
+            daily_loss_cap: Cash::new(100, 0),
+        };
+        let manager = risk::RiskManager::new(
+            limits,
+            Arc::new(clock.clone()),
+            wallet.clone(),
+            CircuitBreaker::default(),
+        );
+        let (risk_input, risk_receiver) = unbounded();
+        let (router_input, router_receiver) = unbounded();
+        let (seller_input, seller_receiver) = unbounded();
+        let (settled_input, settled_receiver) = unbounded();
+        risk::spawn(
+            risk_receiver,
+            router_input.clone(),
+            seller_input.clone(),
+            None,
+            manager,
+        );
+        spawn(
+            router_receiver,
+            seller_input,
+            settled_input,
+            router,
+            reconciler,
+        );
+
+        // Selling 0.1 BTC bought at 10_000 for 9_000 loses just the cap.
+        let purchase =
+            Purchase::new(Btc::new(1, 1), BtcExchangeRate::new(10_000, 0));
+        let offer = Offer::new(BtcExchangeRate::new(9_000, 0), vec![purchase]);
+        wallet.lock().reserve_btc(offer.id, offer.btc()).unwrap();
+        risk_input.send(risk::Message::from(offer)).unwrap();
+        let timeout = time::Duration::from_secs(1);
+        let started = time::Instant::now();
+        while offers.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < timeout, "The offer wasn't placed");
+            thread::sleep(time::Duration::from_millis(1));
+        }
+
+        // The router polls the filled offer once woken up after the poll
+        // interval and reports the loss.
+        clock.advance(Duration::minutes(1));
+        let quote = Message::Quote {
+            venue: "kraken".to_string(),
+            rate: BtcExchangeRate::new(9_000, 0),
+        };
+        router_input.send(quote).unwrap();
+        let settled = settled_receiver.recv_timeout(timeout).unwrap();
+        assert!(matches!(
+            settled,
+            risk::Message::Settled { pnl } if pnl == Cash::new(-100, 0)
+        ));
+        assert_eq!(Cash::new(900, 0), wallet.lock().cash());
+
+        // Once the risk manager hears of the loss, no more offers are placed
+        // today.
+        risk_input.send(settled).unwrap();
+        let purchase =
+            Purchase::new(Btc::new(1, 1), BtcExchangeRate::new(8_000, 0));
+        let offer = Offer::new(BtcExchangeRate::new(9_000, 0), vec![purchase]);
+        let id = offer.id;
+        risk_input.send(risk::Message::from(offer)).unwrap();
+        match seller_receiver.recv_timeout(timeout).unwrap() {
+            seller::Message::OfferRejected(offer) => assert_eq!(id, offer.id),
+            _ => panic!("Expected a rejected offer"),
+        }
+        assert_eq!(1, offers.lock().unwrap().len());
+    }
+}
@@ -5,19 +5,34 @@
 //! implements the API sends the request from that message.
 
 use {
+    chrono::{DateTime, Utc},
     crossbeam_channel::{Receiver, Sender},
     std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
         thread,
-        time::{Duration, Instant},
+        time::Duration,
     },
+    uuid::Uuid,
 };
 
 use crate::{
-    models::{Fee, Offer, Purchase, PurchaseAccount},
+    book::SharedBook,
+    breaker::CircuitBreaker,
+    clock::Clock,
+    models::{Fee, Liquidation, Offer, Purchase, PurchaseAccount, Side},
     prelude::*,
+    volatility::{self, Volatility},
+    wallet::SharedWallet,
 };
 
 const _5MIN: Duration = Duration::from_secs(5 * 60);
+// How long are the purchases of a rejected offer held back, so that an offer
+// which breaches a risk limit isn't made again on every reading.
+const REJECTION_BACKOFF: Duration = Duration::from_secs(15 * 60);
+// How long do we keep the peaks of offered purchases in case the offer comes
+// back. Those which don't come back by then were most likely sold.
+const OFFERED_PEAK_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub enum Message {
     /// We've got an update on the current exchange rate.
@@ -25,39 +40,109 @@ pub enum Message {
         current_trend: BtcExchangeRate,
         // We send a timestamp of when was this rate observed. Messages older
         // than N minutes are discarded.
-        observed_at: Instant,
+        observed_at: DateTime<Utc>,
     },
     /// The buyer actor made a purchase that the seller is now going to try to
     /// sell for better price.
     NewPurchase(Purchase),
+    /// The offer was not placed at the marketplace, e.g. because it breached
+    /// a risk limit. Its purchases return to the account, but they're held
+    /// back for a while before they're offered again.
+    OfferRejected(Offer),
+    /// The offer, or its unfilled part, was taken off the marketplace, e.g.
+    /// because it wasn't filled in time. Its purchases return to the account
+    /// and may be offered again straight away.
+    OfferExpired(Offer),
 }
 
-struct State {
+/// Parameters which decide when purchases are sold.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// How much does the market place change us for the transaction.
+    ///
+    /// # Important
+    /// This should only be the selling fee. The fee we paid to buy the
+    /// bitcoins is already accounted for in the purchase exchange rate.
+    pub fee: Fee,
+    /// What's the minimum that we expect to earn on each purchase.
+    pub min_margin: Percentage,
+    /// How long must a purchase be held before it's sold.
+    pub holding_period: HoldingPeriod,
+    /// If set, purchases which make the margin are not sold straight away.
+    /// Instead the seller tracks the peak of the trend and sells once the
+    /// trend retraces by this many percent from the peak.
+    pub trailing: Option<Percentage>,
+    /// When do we sell purchases at a loss or regardless of the margin. A
+    /// purchase can override this in its metadata.
+    pub liquidation: Liquidation,
+    /// How far below the best bid can the expected price of selling an
+    /// offer's bitcoins to the bids of the book be, in percent. Offers are
+    /// made smaller to stay within it. Not limited if not set or while we
+    /// don't know the book. The book is Kraken's, which stands in for the
+    /// books of the venues the offers are routed to.
+    pub max_impact: Option<Percentage>,
+    /// Scales the min margin by the realised volatility of the trend, if
+    /// set.
+    pub volatility: Option<volatility::Config>,
+}
+
+/// Purchases must be held for a while before they're sold, e.g. so that the
+/// profit qualifies as a long-term capital gain. Purchases without a known
+/// time of acquisition are considered held long enough.
+#[derive(Debug, Clone, Copy)]
+pub struct HoldingPeriod {
+    /// How long must a purchase be held. Zero means any purchase can be sold.
+    pub min: chrono::Duration,
+    /// Purchases which will have been held long enough within this duration
+    /// are reported.
+    pub notice: chrono::Duration,
+}
+
+/// Holds the purchases the seller manages and decides when to sell them. The
+/// seller actor wraps this in a thread, but it can be driven directly, e.g.
+/// when replaying historical data.
+pub struct Seller {
     // Lists the purchases that have been done so far.
     account: PurchaseAccount,
-    // How much does the market place change us for the transaction.
-    //
-    // # Important
-    // This should only be the selling fee. The fee we paid to buy the bitcoins
-    // is already accounted for in the purchase exchange rate.
-    fee: Fee,
-    // What's the minimum that we expect to earn on each purchase.
-    min_margin: Percentage,
+    config: Config,
+    // Purchases we've reported as approaching the end of their holding
+    // period, so that they're reported only once.
+    reported: HashSet<Uuid>,
+    // In the trailing mode, the peak of the trend since each purchase made
+    // the margin.
+    peaks: HashMap<Uuid, BtcExchangeRate>,
+    // The peaks of purchases in the offers we've made and when we made them,
+    // so that they're tracked on if the offer comes back.
+    offered: HashMap<Uuid, (BtcExchangeRate, DateTime<Utc>)>,
+    // The purchases of rejected offers and until when they're held back.
+    held_back: HashMap<Uuid, DateTime<Utc>>,
+    // Tells the time against which the age of the readings is judged.
+    clock: Arc<dyn Clock>,
+    // The bitcoins in each offer are reserved in the wallet, so that they
+    // cannot be sold twice.
+    wallet: SharedWallet,
+    // Every reading is checked by the breaker. No offers are made while it's
+    // tripped.
+    breaker: CircuitBreaker,
+    // Tells how many bitcoins the market can absorb, if we follow a book.
+    book: Option<SharedBook>,
+    // Samples the trend if the margin is adjusted to its volatility.
+    volatility: Option<Volatility>,
 }
 
 /// Spawns a new thread which runs the seller logic. Use the parameters of this
-/// method to configure the seller.
-pub fn spawn(
+/// method to configure the seller. The offers are sent to the output converted
+/// into whatever the next actor in the chain expects.
+pub fn spawn<T: From<Offer> + Send + 'static>(
     input: Receiver<Message>,
-    output: Sender<Offer>,
-    fee: Fee,
-    min_margin: Percentage,
+    output: Sender<T>,
+    config: Config,
+    clock: Arc<dyn Clock>,
+    wallet: SharedWallet,
+    breaker: CircuitBreaker,
+    book: Option<SharedBook>,
 ) {
-    let mut state = State {
-        account: PurchaseAccount::default(),
-        fee,
-        min_margin,
-    };
+    let mut seller = Seller::new(config, clock, wallet, breaker, book);
 
     thread::spawn(move || loop {
         let message = if let Ok(message) = input.recv() {
@@ -67,9 +152,9 @@ pub fn spawn(
             break;
         };
 
-        match route(message, &mut state) {
+        match seller.handle(message) {
             Ok(Some(offer)) => {
-                if output.send(offer).is_err() {
+                if output.send(offer.into()).is_err() {
                     log::error!(
                         "The seller's output channel died. Stopping ..."
                     );
@@ -84,62 +169,409 @@ pub fn spawn(
     });
 }
 
-// Considers given message and if appropriate, commands bitcoins to be sold.
-fn route(message: Message, state: &mut State) -> Result<Option<Offer>> {
-    match message {
-        Message::TrendReading {
+impl Default for HoldingPeriod {
+    fn default() -> Self {
+        Self {
+            min: chrono::Duration::zero(),
+            notice: chrono::Duration::zero(),
+        }
+    }
+}
+
+impl Seller {
+    /// Creates a seller with an empty purchase account.
+    pub fn new(
+        config: Config,
+        clock: Arc<dyn Clock>,
+        wallet: SharedWallet,
+        breaker: CircuitBreaker,
+        book: Option<SharedBook>,
+    ) -> Self {
+        Self {
+            account: PurchaseAccount::default(),
+            config,
+            reported: HashSet::new(),
+            peaks: HashMap::new(),
+            offered: HashMap::new(),
+            held_back: HashMap::new(),
+            clock,
+            wallet,
+            breaker,
+            book,
+            volatility: config.volatility.map(Volatility::new),
+        }
+    }
+
+    /// Considers given message and if appropriate, commands bitcoins to be
+    /// sold.
+    pub fn handle(&mut self, message: Message) -> Result<Option<Offer>> {
+        match message {
+            Message::TrendReading {
+                current_trend,
+                observed_at,
+            } => {
+                // Readings from the future have a negative age which cannot be
+                // converted, and they are not outdated.
+                let age = (self.clock.now() - observed_at).to_std();
+                if age.map(|age| age > _5MIN).unwrap_or(false) {
+                    return Err(Box::new(Error::outdated_message()));
+                }
+
+                self.breaker.observe_trend(current_trend, observed_at);
+                if let Some(volatility) = self.volatility.as_mut() {
+                    volatility.observe(current_trend, observed_at);
+                }
+                let now = self.clock.now();
+                self.report_maturing(now);
+                if let Err(trip) = self.breaker.check(now) {
+                    log::info!("Not selling, trading is halted: {}", trip);
+                    Ok(None)
+                } else {
+                    self.offer(current_trend, now)
+                }
+            }
+            Message::NewPurchase(purchase) => {
+                self.account.push(purchase);
+                Ok(None)
+            }
+            Message::OfferRejected(offer) => {
+                let until = self.clock.now()
+                    + chrono::Duration::from_std(REJECTION_BACKOFF)
+                        .expect("The backoff is within range");
+                log::info!(
+                    "Holding back the purchases of rejected offer {} until {}",
+                    offer.id,
+                    until
+                );
+                for purchase in &offer.purchases {
+                    self.held_back.insert(purchase.id, until);
+                }
+                self.give_back(offer);
+                Ok(None)
+            }
+            Message::OfferExpired(offer) => {
+                self.give_back(offer);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The margin we currently require, in percent of the buying price.
+    pub fn min_margin(&self) -> Percentage {
+        let min_margin = self.config.min_margin;
+        self.volatility
+            .as_ref()
+            .map_or(min_margin, |volatility| volatility.margin(min_margin))
+    }
+
+    /// Purchases which haven't been held long enough yet, but will have been
+    /// within the notice period.
+    pub fn maturing(&self, now: DateTime<Utc>) -> Vec<&Purchase> {
+        let HoldingPeriod { min, notice } = self.config.holding_period;
+        self.account
+            .iter()
+            .filter(|purchase| {
+                let held = purchase.held_for(now);
+                held.is_some_and(|held| held < min && held + notice >= min)
+            })
+            .collect()
+    }
+
+    // Decides which purchases to sell at the trend, if any. Liquidated
+    // purchases are sold first, then those which make the margin, as far as
+    // the book absorbs them. Purchases of rejected offers sit out their
+    // backoff.
+    fn offer(
+        &mut self,
+        current_trend: BtcExchangeRate,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Offer>> {
+        let config = Config {
+            min_margin: self.min_margin(),
+            ..self.config
+        };
+        self.held_back.retain(|_, until| *until > now);
+        let retention = chrono::Duration::from_std(OFFERED_PEAK_RETENTION)
+            .expect("The retention is within range");
+        self.offered.retain(|_, (_, at)| now - *at < retention);
+        // The held back purchases keep their peaks, as they're still
+        // candidates.
+        let held_back = &self.held_back;
+        let (held, candidates): (Vec<_>, Vec<_>) = self
+            .account
+            .drain()
+            .partition(|purchase| held_back.contains_key(&purchase.id));
+        self.account.extend(candidates);
+        let held_peaks: Vec<_> = held
+            .iter()
+            .filter_map(|purchase| self.peaks.remove_entry(&purchase.id))
+            .collect();
+
+        // The peaks of liquidated purchases are dropped when collecting
+        // profit, as they're no longer candidates.
+        let mut purchases =
+            liquidate(&mut self.account, current_trend, &config, now);
+        let liquidated: HashSet<_> =
+            purchases.iter().map(|purchase| purchase.id).collect();
+        let profitable = collect_profit(
+            &mut self.account,
             current_trend,
-            observed_at,
-        } => {
-            if Instant::now().duration_since(observed_at) > _5MIN {
-                Err(Box::new(Error::outdated_message()))
-            } else {
-                Ok(collect_profit(
-                    &mut state.account,
-                    current_trend,
-                    state.fee,
-                    state.min_margin,
-                ))
+            &config,
+            now,
+            &mut self.peaks,
+        );
+        purchases.extend(profitable.into_iter().flat_map(|o| o.purchases));
+        let purchases = self.size(purchases);
+        // The peaks of purchases sold whole are set aside until we know the
+        // offer isn't coming back, those held back by the book still
+        // retraced.
+        for purchase in &purchases {
+            if self.account.iter().all(|p| p.id != purchase.id) {
+                if let Some(peak) = self.peaks.remove(&purchase.id) {
+                    self.offered.insert(purchase.id, (peak, now));
+                }
             }
         }
-        Message::NewPurchase(purchase) => {
-            state.account.push(purchase);
-            Ok(None)
+        self.account.extend(held);
+        self.peaks.extend(held_peaks);
+
+        if purchases.is_empty() {
+            return Ok(None);
+        }
+        // An offer which liquidates any purchase follows the trend down
+        // rather than waiting at its rate.
+        let liquidation = purchases
+            .iter()
+            .any(|purchase| liquidated.contains(&purchase.id));
+        let offer = Offer {
+            liquidation,
+            ..Offer::new(current_trend, purchases)
+        };
+        self.reserve(offer).map(Some)
+    }
+
+    // Returns the purchases of an offer which didn't sell to the account,
+    // along with the peaks they had when offered.
+    fn give_back(&mut self, offer: Offer) {
+        self.wallet.lock().release(offer.id);
+        for purchase in &offer.purchases {
+            if let Some((peak, _)) = self.offered.remove(&purchase.id) {
+                self.peaks.insert(purchase.id, peak);
+            }
         }
+        self.restore(offer.purchases);
+    }
+
+    // Logs the purchases which are about to be held long enough, each only
+    // once.
+    fn report_maturing(&mut self, now: DateTime<Utc>) {
+        let min = self.config.holding_period.min;
+        let mut maturing = HashSet::new();
+        for purchase in self.maturing(now) {
+            maturing.insert(purchase.id);
+            if self.reported.contains(&purchase.id) {
+                continue;
+            }
+            // It's safe to unwrap because maturing purchases have a time.
+            let bought_at = purchase.metadata.bought_at.unwrap();
+            log::info!(
+                "Purchase {} of {} BTC at {} can be sold from {}",
+                purchase.id,
+                purchase.btc,
+                purchase.rate,
+                bought_at + min
+            );
+        }
+        self.reported = maturing;
+    }
+
+    // Keeps as many bitcoins of the purchases in the offer, in order, as the
+    // bids of the book absorb within the max impact. The last purchase which
+    // fits is split, its rest and the purchases after it return to the
+    // account.
+    fn size(&mut self, purchases: Vec<Purchase>) -> Vec<Purchase> {
+        let (max_impact, book) = match (self.config.max_impact, &self.book) {
+            (Some(max_impact), Some(book)) => (max_impact, book.lock()),
+            _ => return purchases,
+        };
+        let best_bid = if let Some(best_bid) = book.best_bid() {
+            best_bid
+        } else {
+            return purchases;
+        };
+        let floor = best_bid - best_bid / Decimal::new(100, 0) * max_impact;
+
+        let absorbed = book.absorbs(Side::Sell, floor);
+        let mut remaining = absorbed;
+        let mut sized = Vec::new();
+        for purchase in purchases {
+            let btc = remaining.min(purchase.btc);
+            remaining -= btc;
+            if btc == purchase.btc {
+                sized.push(purchase);
+                continue;
+            }
+            if btc > Btc::new(0, 0) {
+                log::info!(
+                    "Selling more than {} BTC would move the market below {}",
+                    absorbed,
+                    floor
+                );
+                sized.push(purchase.part(btc));
+            }
+            self.account.push(purchase.part(purchase.btc - btc));
+        }
+
+        sized
+    }
+
+    // Returns the purchases to the account. Parts of a purchase which was
+    // split, by the book or by the ladder of the router, are merged back
+    // into one, so that the account never holds two purchases with the same
+    // id.
+    fn restore(&mut self, purchases: Vec<Purchase>) {
+        let mut held = std::mem::take(&mut self.account).into_vec();
+        for purchase in purchases {
+            match held.iter_mut().find(|held| held.id == purchase.id) {
+                Some(held) => held.btc += purchase.btc,
+                None => held.push(purchase),
+            }
+        }
+        self.account = held.into();
+    }
+
+    // Reserves the bitcoins of the offer in the wallet. If we don't have them,
+    // the purchases return to the account and the offer is not made.
+    fn reserve(&mut self, offer: Offer) -> Result<Offer> {
+        let reservation = self.wallet.lock().reserve_btc(offer.id, offer.btc());
+        if let Err(e) = reservation {
+            self.restore(offer.purchases);
+            return Err(e);
+        }
+
+        Ok(offer)
     }
 }
 
-// Looks at the purchases we've made and sells the ones which make profit.
+// Takes the purchases out of the account which hit the stop loss or which
+// we've held for too long, no matter the holding period. Each is logged with
+// the profit or loss that selling it at the trend realises.
+fn liquidate(
+    account: &mut PurchaseAccount,
+    rate: BtcExchangeRate,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Vec<Purchase> {
+    let (liquidated, kept): (Vec<_>, Vec<_>) =
+        account.drain().partition(|purchase| {
+            let policy =
+                purchase.metadata.liquidation.unwrap_or(config.liquidation);
+            let stop_loss = policy.stop_loss.is_some_and(|stop_loss| {
+                let floor = purchase.rate
+                    - purchase.rate / Decimal::new(100, 0) * stop_loss;
+                rate <= floor
+            });
+            let too_old = policy.max_age.is_some_and(|max_age| {
+                purchase.held_for(now).is_some_and(|held| held >= max_age)
+            });
+
+            let reason = match (stop_loss, too_old) {
+                (true, _) => "hit the stop loss",
+                (false, true) => "was held for too long",
+                (false, false) => return false,
+            };
+            log::warn!(
+                "Liquidating purchase {} of {} BTC at {} which {}, selling \
+                 at {} realises {}",
+                purchase.id,
+                purchase.btc,
+                purchase.rate,
+                reason,
+                rate,
+                purchase.margin_after_fee(rate, config.fee).round_dp(2)
+            );
+            true
+        });
+    account.extend(kept);
+
+    liquidated
+}
+
+// Looks at the purchases we've made and sells the ones which make profit and
+// have been held long enough. In the trailing mode, the peaks of the trend of
+// such purchases are tracked, and they're only sold after a retrace.
 fn collect_profit(
     account: &mut PurchaseAccount,
     rate: BtcExchangeRate,
-    fee: Fee,
-    min_margin: Percentage,
+    config: &Config,
+    now: DateTime<Utc>,
+    peaks: &mut HashMap<Uuid, BtcExchangeRate>,
 ) -> Option<Offer> {
     let mut purchases_to_sell = Vec::new();
+    // Profitable purchases which haven't been held long enough.
+    let mut too_young = Vec::new();
 
     loop {
         // Iterates the queue of the purchases, always looking at the one we
         // got for the lowest price.
         if let Some(top_purchase) = account.peek() {
-            let margin = top_purchase.margin_after_fee(rate, fee);
+            let margin = top_purchase.margin_after_fee(rate, config.fee);
 
             // We calculate the minimum margin by finding out how much is
             // N % from the money spent on the bitcoin.
-            let flat_minimum_margin =
-                top_purchase.buying_price() / Decimal::new(100, 0) * min_margin;
+            let flat_minimum_margin = top_purchase.buying_price()
+                / Decimal::new(100, 0)
+                * config.min_margin;
 
-            // If selling this offer yields expected margin, then sell it.
+            // If selling this offer yields expected margin, then sell it
+            // unless it's too young. The purchases further down the queue
+            // might be old enough.
             if margin > flat_minimum_margin {
                 // It's safe to unwrap here because we've just peeked into the
                 // queue and it returned Some.
-                purchases_to_sell.push(account.pop().unwrap());
+                let purchase = account.pop().unwrap();
+                let held = purchase.held_for(now);
+                if held.is_some_and(|held| held < config.holding_period.min) {
+                    too_young.push(purchase);
+                } else {
+                    purchases_to_sell.push(purchase);
+                }
                 continue;
             }
         }
 
         break;
     }
+    account.extend(too_young);
+
+    if let Some(retrace) = config.trailing {
+        // Purchases which no longer make the margin stop being tracked.
+        peaks.retain(|id, _| purchases_to_sell.iter().any(|p| p.id == *id));
+        let (retraced, waiting): (Vec<_>, Vec<_>) =
+            purchases_to_sell.into_iter().partition(|purchase| {
+                let peak = peaks.entry(purchase.id).or_insert_with(|| {
+                    log::info!(
+                        "Tracking the peak for purchase {}",
+                        purchase.id
+                    );
+                    rate
+                });
+                *peak = (*peak).max(rate);
+                rate <= *peak - *peak / Decimal::new(100, 0) * retrace
+            });
+        // The peaks are dropped only once the offer is sized, as some of the
+        // purchases might not fit in it.
+        for purchase in &retraced {
+            log::info!(
+                "Trend {} retraced from peak {} for purchase {}",
+                rate,
+                peaks[&purchase.id],
+                purchase.id
+            );
+        }
+        account.extend(waiting);
+        purchases_to_sell = retraced;
+    }
 
     if !purchases_to_sell.is_empty() {
         Some(Offer::new(rate, purchases_to_sell))
@@ -150,18 +582,43 @@ fn collect_profit(
 
 #[cfg(test)]
 mod tests {
-    use crossbeam_channel::bounded;
+    use {chrono::TimeZone, crossbeam_channel::bounded, std::fs, uuid::Uuid};
 
-    use super::*;
+    use {
+        super::*,
+        crate::{
+            book::OrderBook, clock::SimulatedClock, models::Metadata,
+            wallet::Wallet,
+        },
+    };
 
     #[test]
     fn should_add_new_purchases_and_sell_the_one_with_profit() -> Result<()> {
         let fee = Fee::None;
         let min_margin = Percentage::new(10, 0);
         let (channel_in, seller_input) = bounded(0);
-        let (seller_output, channel_out) = bounded(0);
+        let (seller_output, channel_out) = bounded::<Offer>(0);
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap(),
+        );
 
-        spawn(seller_input, seller_output, fee, min_margin);
+        spawn(
+            seller_input,
+            seller_output,
+            Config {
+                fee,
+                min_margin,
+                holding_period: HoldingPeriod::default(),
+                trailing: None,
+                liquidation: Liquidation::default(),
+                max_impact: None,
+                volatility: None,
+            },
+            Arc::new(clock.clone()),
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(3, 0))),
+            CircuitBreaker::default(),
+            None,
+        );
 
         // Inserts a purchase with rate for 200 into the seller's msg box.
         let purchase_for_200 = {
@@ -179,7 +636,7 @@ mod tests {
         // sending a message we expect to be ignored, sending another message
         // which confirms that the seller has evaluated this message already,
         // and then checking that the channel output is empty.
-        let _10min_ago = Instant::now() - _5MIN - _5MIN;
+        let _10min_ago = clock.now() - chrono::Duration::minutes(10);
         channel_in.send(Message::TrendReading {
             current_trend: trend_500,
             observed_at: _10min_ago,
@@ -204,11 +661,14 @@ mod tests {
         // for 200.
         channel_in.send(Message::TrendReading {
             current_trend: trend_500,
-            observed_at: Instant::now(),
+            observed_at: clock.now(),
         })?;
         let offer =
             channel_out.recv_timeout(Duration::from_millis(10)).unwrap();
-        assert_eq!(&[purchase_for_200.clone()], offer.purchases.as_slice());
+        assert_eq!(
+            std::slice::from_ref(&purchase_for_200),
+            offer.purchases.as_slice()
+        );
         assert_eq!(trend_500, offer.rate);
 
         // Send the same reading and check that the channel is empty. We wait
@@ -219,7 +679,7 @@ mod tests {
         // be clearer what's wrong.
         channel_in.send(Message::TrendReading {
             current_trend: trend_500,
-            observed_at: Instant::now(),
+            observed_at: clock.now(),
         })?;
         assert!(channel_out.recv_timeout(Duration::from_millis(10)).is_err());
 
@@ -233,10 +693,12 @@ mod tests {
 
         assert!(channel_out.is_empty(), "Cannot contain msgs at this point");
 
+        // A jump from 500 to 2000 within minutes would trip the breaker.
+        clock.advance(chrono::Duration::minutes(6));
         let trend_2000 = BtcExchangeRate::new(2000, 0);
         channel_in.send(Message::TrendReading {
             current_trend: trend_2000,
-            observed_at: Instant::now(),
+            observed_at: clock.now(),
         })?;
         let offer =
             channel_out.recv_timeout(Duration::from_millis(10)).unwrap();
@@ -252,6 +714,16 @@ mod tests {
     #[test]
     fn should_collect_all_purchases_which_yield_profit() {
         let fee = Fee::Percentage(Decimal::new(1, 0));
+        let config = |min_margin| Config {
+            fee,
+            min_margin,
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            max_impact: None,
+            volatility: None,
+        };
+        let now = Utc::now();
 
         let purchase_for_1000 = {
             let rate = BtcExchangeRate::new(1000, 0);
@@ -280,21 +752,32 @@ mod tests {
             let trend = BtcExchangeRate::new(1000, 0);
             let min_margin = Percentage::new(20, 0);
             let mut account = account.clone();
-            let offer = collect_profit(&mut account, trend, fee, min_margin)
-                .expect(
-                    "There is one purchase we want to sell with this profit",
-                );
-            assert_eq!(&[purchase_for_450.clone()], offer.purchases.as_slice());
+            let offer = collect_profit(
+                &mut account,
+                trend,
+                &config(min_margin),
+                now,
+                &mut HashMap::new(),
+            )
+            .expect("There is one purchase we want to sell with this profit");
+            assert_eq!(
+                std::slice::from_ref(&purchase_for_450),
+                offer.purchases.as_slice()
+            );
         }
 
         {
             let trend = BtcExchangeRate::new(1000, 0);
             let min_margin = Percentage::new(5, 0);
             let mut account = account.clone();
-            let offer = collect_profit(&mut account, trend, fee, min_margin)
-                .expect(
-                    "There is one purchase we want to sell with this profit",
-                );
+            let offer = collect_profit(
+                &mut account,
+                trend,
+                &config(min_margin),
+                now,
+                &mut HashMap::new(),
+            )
+            .expect("There is one purchase we want to sell with this profit");
             assert_eq!(
                 &[purchase_for_450, purchase_for_900],
                 offer.purchases.as_slice()
@@ -305,9 +788,375 @@ mod tests {
             let trend = BtcExchangeRate::new(400, 0);
             let min_margin = Percentage::new(5, 0);
             let mut account = account.clone();
-            assert!(
-                collect_profit(&mut account, trend, fee, min_margin).is_none()
-            );
+            assert!(collect_profit(
+                &mut account,
+                trend,
+                &config(min_margin),
+                now,
+                &mut HashMap::new(),
+            )
+            .is_none());
+        }
+    }
+
+    #[test]
+    fn should_hold_purchases_for_the_holding_period() -> Result<()> {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(3, 0)));
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod {
+                min: chrono::Duration::days(365),
+                notice: chrono::Duration::days(30),
+            },
+            trailing: None,
+            liquidation: Liquidation::default(),
+            max_impact: None,
+            volatility: None,
+        };
+        let mut seller = Seller::new(
+            config,
+            Arc::new(clock.clone()),
+            wallet,
+            Default::default(),
+            None,
+        );
+
+        let bought = |days_ago, rate| {
+            let metadata = Metadata {
+                bought_at: Some(clock.now() - chrono::Duration::days(days_ago)),
+                ..Metadata::default()
+            };
+            let rate = BtcExchangeRate::new(rate, 0);
+            Purchase::with_metadata(Btc::new(1, 0), rate, metadata)
+        };
+        // The cheapest purchase is too young, but it must not stop the older
+        // one behind it from being sold. The purchase without a time counts
+        // as old enough.
+        let young = bought(100, 100);
+        let maturing = bought(350, 120);
+        let old = bought(400, 150);
+        let unknown =
+            Purchase::new(Btc::new(1, 0), BtcExchangeRate::new(160, 0));
+        for purchase in &[&young, &maturing, &old, &unknown] {
+            seller.handle(Message::NewPurchase((*purchase).clone()))?;
         }
+        assert_eq!(vec![&maturing], seller.maturing(clock.now()));
+
+        let reading = || Message::TrendReading {
+            current_trend: BtcExchangeRate::new(200, 0),
+            observed_at: clock.now(),
+        };
+        let offer = seller.handle(reading())?.expect("Old purchases sell");
+        assert_eq!(&[old, unknown], offer.purchases.as_slice());
+
+        // In 15 days the maturing purchase is held long enough.
+        clock.advance(chrono::Duration::days(15));
+        let offer = seller.handle(reading())?.expect("Purchase matured");
+        assert_eq!(std::slice::from_ref(&maturing), offer.purchases.as_slice());
+        assert!(seller.maturing(clock.now()).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_offer_bitcoins_which_are_not_in_the_wallet() -> Result<()> {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(1, 0)));
+        let breaker = CircuitBreaker::default();
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation::default(),
+            max_impact: None,
+            volatility: None,
+        };
+        let mut seller = Seller::new(
+            config,
+            Arc::new(clock.clone()),
+            wallet.clone(),
+            breaker.clone(),
+            None,
+        );
+        let purchase =
+            Purchase::new(Btc::new(2, 0), BtcExchangeRate::new(100, 0));
+        seller.handle(Message::NewPurchase(purchase.clone()))?;
+
+        let reading = || Message::TrendReading {
+            current_trend: BtcExchangeRate::new(200, 0),
+            observed_at: clock.now(),
+        };
+        assert!(seller.handle(reading()).is_err());
+        assert_eq!(Btc::new(1, 0), wallet.lock().available_btc());
+
+        // Once the bitcoins arrive, the purchase which was returned to the
+        // account is offered.
+        wallet.lock().settle_buy(
+            Uuid::new_v4(),
+            Cash::new(0, 0),
+            Btc::new(1, 0),
+        );
+        let offer = seller.handle(reading())?.expect("Wallet has enough BTC");
+        assert_eq!(std::slice::from_ref(&purchase), offer.purchases.as_slice());
+        assert_eq!(Btc::new(0, 0), wallet.lock().available_btc());
+
+        // A rejected offer frees the bitcoins, its purchase is offered again
+        // once the backoff passed.
+        seller.handle(Message::OfferRejected(offer))?;
+        assert_eq!(Btc::new(2, 0), wallet.lock().available_btc());
+        assert!(seller.handle(reading())?.is_none());
+        clock.advance(chrono::Duration::minutes(15));
+        let offer = seller.handle(reading())?.expect("Purchase is back");
+        assert_eq!(std::slice::from_ref(&purchase), offer.purchases.as_slice());
+
+        // Nothing is offered while trading is halted.
+        seller.handle(Message::OfferRejected(offer))?;
+        breaker.kill();
+        assert!(seller.handle(reading())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_sell_after_trend_retraces_from_peak() -> Result<()> {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(2, 0)));
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: Some(Percentage::new(2, 0)),
+            liquidation: Liquidation::default(),
+            max_impact: None,
+            volatility: None,
+        };
+        let mut seller = Seller::new(
+            config,
+            Arc::new(clock.clone()),
+            wallet,
+            CircuitBreaker::default(),
+            None,
+        );
+        let cheap = Purchase::new(Btc::new(1, 0), BtcExchangeRate::new(100, 0));
+        let pricey =
+            Purchase::new(Btc::new(1, 0), BtcExchangeRate::new(116, 0));
+        seller.handle(Message::NewPurchase(cheap.clone()))?;
+        seller.handle(Message::NewPurchase(pricey.clone()))?;
+
+        // The readings are far enough apart not to trip the breaker.
+        let mut reading = |trend| {
+            clock.advance(chrono::Duration::minutes(6));
+            seller.handle(Message::TrendReading {
+                current_trend: BtcExchangeRate::new(trend, 0),
+                observed_at: clock.now(),
+            })
+        };
+
+        // Both purchases make the margin at 130, the peak. 128 is within 2 %
+        // of it.
+        assert!(reading(120)?.is_none());
+        assert!(reading(130)?.is_none());
+        assert!(reading(128)?.is_none());
+
+        // At 127 the trend retraced enough, but the pricey purchase needs
+        // 127.6 to make the margin. It's no longer tracked.
+        let offer = reading(127)?.expect("Trend retraced from the peak");
+        assert_eq!(std::slice::from_ref(&cheap), offer.purchases.as_slice());
+        assert_eq!(BtcExchangeRate::new(127, 0), offer.rate);
+
+        // The pricey purchase starts tracking a new peak.
+        assert!(reading(128)?.is_none());
+        assert!(reading(140)?.is_none());
+        let offer = reading(137)?.expect("Trend retraced from the new peak");
+        assert_eq!(std::slice::from_ref(&pricey), offer.purchases.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_liquidate_stuck_purchases() -> Result<()> {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(3, 0)));
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: None,
+            liquidation: Liquidation {
+                stop_loss: Some(Percentage::new(20, 0)),
+                max_age: None,
+            },
+            max_impact: None,
+            volatility: None,
+        };
+        let mut seller = Seller::new(
+            config,
+            Arc::new(clock.clone()),
+            wallet,
+            CircuitBreaker::default(),
+            None,
+        );
+        let cheap = Purchase::new(Btc::new(1, 0), BtcExchangeRate::new(100, 0));
+        // Overrides the stop loss with a forced exit after 90 days.
+        let old = Purchase::with_metadata(
+            Btc::new(1, 0),
+            BtcExchangeRate::new(150, 0),
+            Metadata {
+                bought_at: Some(clock.now() - chrono::Duration::days(80)),
+                liquidation: Some(Liquidation {
+                    stop_loss: None,
+                    max_age: Some(chrono::Duration::days(90)),
+                }),
+                ..Metadata::default()
+            },
+        );
+        // Is never liquidated.
+        let held = Purchase::with_metadata(
+            Btc::new(1, 0),
+            BtcExchangeRate::new(300, 0),
+            Metadata {
+                liquidation: Some(Liquidation::default()),
+                ..Metadata::default()
+            },
+        );
+        for purchase in &[&cheap, &old, &held] {
+            seller.handle(Message::NewPurchase((*purchase).clone()))?;
+        }
+
+        // The readings are far enough apart not to trip the breaker.
+        let reading = |seller: &mut Seller, trend| {
+            clock.advance(chrono::Duration::minutes(6));
+            seller.handle(Message::TrendReading {
+                current_trend: BtcExchangeRate::new(trend, 0),
+                observed_at: clock.now(),
+            })
+        };
+
+        // The cheap purchase hits the stop loss at 80.
+        assert!(reading(&mut seller, 90)?.is_none());
+        let offer = reading(&mut seller, 80)?
+            .expect("Cheap purchase hit the stop loss");
+        assert_eq!(std::slice::from_ref(&cheap), offer.purchases.as_slice());
+        assert_eq!(BtcExchangeRate::new(80, 0), offer.rate);
+        assert!(offer.liquidation);
+        assert!(reading(&mut seller, 70)?.is_none());
+
+        // The liquidation expired unfilled and follows the trend down.
+        seller.handle(Message::OfferExpired(offer))?;
+        let offer = reading(&mut seller, 50)?
+            .expect("Cheap purchase is still liquidated");
+        assert_eq!(BtcExchangeRate::new(50, 0), offer.rate);
+        assert!(reading(&mut seller, 50)?.is_none());
+
+        // A rejected liquidation, e.g. by the daily loss cap, isn't offered
+        // on every reading, only once the backoff passed.
+        seller.handle(Message::OfferRejected(offer))?;
+        assert!(reading(&mut seller, 50)?.is_none());
+        assert!(reading(&mut seller, 50)?.is_none());
+        let offer = reading(&mut seller, 50)?.expect("Backoff passed");
+        assert_eq!(std::slice::from_ref(&cheap), offer.purchases.as_slice());
+
+        // After 90 days the old purchase is sold at a loss.
+        clock.advance(chrono::Duration::days(10));
+        let offer =
+            reading(&mut seller, 100)?.expect("Old purchase was held too long");
+        assert_eq!(std::slice::from_ref(&old), offer.purchases.as_slice());
+
+        // The last purchase waits for the margin.
+        assert!(reading(&mut seller, 320)?.is_none());
+        let offer =
+            reading(&mut seller, 340)?.expect("Purchase makes the margin");
+        assert_eq!(std::slice::from_ref(&held), offer.purchases.as_slice());
+        assert!(!offer.liquidation);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_offer_only_what_the_book_absorbs() -> Result<()> {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 5, 20, 18, 40, 0).unwrap(),
+        );
+        let wallet =
+            SharedWallet::new(Wallet::new(Cash::new(0, 0), Btc::new(2, 0)));
+        let config = Config {
+            fee: Fee::None,
+            min_margin: Percentage::new(10, 0),
+            holding_period: HoldingPeriod::default(),
+            trailing: Some(Percentage::new(1, 0)),
+            liquidation: Liquidation::default(),
+            max_impact: Some(Percentage::new(5, 3)),
+            volatility: None,
+        };
+        // The best bid is 0.3 BTC at 9_200.5, followed by 1 BTC at each of
+        // 9_200, 9_199 and lower.
+        let book = SharedBook::new(OrderBook::new(10));
+        let messages = fs::read_to_string("tests/data/ws/kraken_book.jsonl")?;
+        for message in messages.lines().take(7) {
+            book.lock().apply(message)?;
+        }
+        let mut seller = Seller::new(
+            config,
+            Arc::new(clock.clone()),
+            wallet,
+            CircuitBreaker::default(),
+            Some(book.clone()),
+        );
+        let a = Purchase::new(Btc::new(3, 1), BtcExchangeRate::new(8_000, 0));
+        let b = Purchase::new(Btc::new(5, 1), BtcExchangeRate::new(8_100, 0));
+        let c = Purchase::new(Btc::new(1, 0), BtcExchangeRate::new(8_200, 0));
+        for purchase in &[&a, &b, &c] {
+            seller.handle(Message::NewPurchase((*purchase).clone()))?;
+        }
+        let reading = |trend| Message::TrendReading {
+            current_trend: BtcExchangeRate::new(trend, 0),
+            observed_at: clock.now(),
+        };
+
+        // The trend retraces from its peak of 9_300.
+        assert!(seller.handle(reading(9_300))?.is_none());
+
+        // Selling all 1.8 BTC would fetch 9_199.8 on average, below the
+        // 9_200.04 we allow. The book absorbs a, b and a part of c.
+        let first = seller.handle(reading(9_200))?.expect("Book absorbs some");
+        assert_eq!(
+            &[a.clone(), b.clone(), c.clone()],
+            first.purchases.as_slice()
+        );
+        assert_eq!(Btc::new(59426428, 8), first.purchases[2].btc);
+
+        // The rest of c still retraced from the peak and nothing holds it
+        // back without the book.
+        book.lock().clear();
+        let offer = seller.handle(reading(9_200))?.expect("Book is unknown");
+        assert_eq!(std::slice::from_ref(&c), offer.purchases.as_slice());
+        assert_eq!(Btc::new(40573572, 8), offer.purchases[0].btc);
+
+        // Both offers are rejected, the parts of c return as one purchase.
+        // Once the backoff passed, all are still retraced from their peaks.
+        seller.handle(Message::OfferRejected(first))?;
+        seller.handle(Message::OfferRejected(offer))?;
+        assert!(seller.handle(reading(9_200))?.is_none());
+        clock.advance(chrono::Duration::minutes(15));
+        let offer = seller.handle(reading(9_200))?.expect("Peaks were kept");
+        assert_eq!(&[a, b, c], offer.purchases.as_slice());
+        assert_eq!(Btc::new(1, 0), offer.purchases[2].btc);
+
+        Ok(())
     }
 }
@@ -0,0 +1,372 @@
+//! Trend is an actor which reads the trades of several exchanges at once and
+//! derives the current trend from them. Relying on a single exchange's ticker
+//! is fragile, therefore sources which diverge from the rest or which haven't
+//! traded for a while are excluded from the consensus.
+//!
+//! Every source is read by its own thread. The ticks are collected by the
+//! actor which reports them to the watchdog and the circuit breaker, passes
+//! the rate of each exchange on to the router, and sends the consensus to the
+//! seller.
+
+pub mod source;
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    crossbeam_channel::{unbounded, Sender},
+    std::{
+        collections::{HashMap, VecDeque},
+        sync::Arc,
+        thread, time,
+    },
+    tungstenite::Message as WsMessage,
+};
+
+use crate::{
+    breaker::CircuitBreaker, clock::Clock, prelude::*, router, seller, watchdog,
+};
+
+pub use source::{Source, Tick};
+
+// How long do we wait before reconnecting to a source.
+const RECONNECT_AFTER: time::Duration = time::Duration::from_secs(5);
+
+/// How are the rates of the sources combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consensus {
+    /// The median of the latest rates.
+    Median,
+    /// The average of the latest rates weighted by the volume each source
+    /// traded within the max age.
+    VolumeWeighted,
+}
+
+/// Parameters of the aggregation.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub consensus: Consensus,
+    /// How far from the median of all sources can a source be before it is
+    /// excluded, in percent of the median.
+    pub max_divergence: Percentage,
+    /// Sources which haven't traded within this duration are excluded.
+    pub max_age: Duration,
+}
+
+/// Collects the ticks of all sources and computes the consensus rate.
+pub struct Aggregator {
+    config: Config,
+    // The ticks within the max age of each source, oldest first.
+    ticks: HashMap<Source, VecDeque<Tick>>,
+}
+
+/// Spawns a thread for each source which reads its trades from given url, and
+/// a thread which aggregates them. The consensus is sent to the seller every
+/// time a tick arrives. The router is told the rate of every tick as the
+/// quote of its exchange.
+pub fn spawn(
+    sources: Vec<(Source, String)>,
+    output: Sender<seller::Message>,
+    watchdog: Sender<watchdog::Message>,
+    quotes: Sender<router::Message>,
+    breaker: CircuitBreaker,
+    mut aggregator: Aggregator,
+    clock: Arc<dyn Clock>,
+) {
+    let (ticks, input) = unbounded();
+    for (source, url) in sources {
+        let ticks = ticks.clone();
+        thread::spawn(move || listen(source, &url, ticks));
+    }
+
+    thread::spawn(move || loop {
+        let tick = if let Ok(tick) = input.recv() {
+            tick
+        } else {
+            log::error!("The trend's input channel died. Stopping ...");
+            break;
+        };
+
+        let source = tick.source.to_string();
+        breaker.observe_source(&source, tick.rate, tick.observed_at);
+        let reading = watchdog::Message::Reading {
+            source: source.clone(),
+            observed_at: tick.observed_at,
+        };
+        if watchdog.send(reading).is_err() {
+            log::error!("The watchdog's input channel died. Stopping ...");
+            break;
+        }
+        let quote = router::Message::Quote {
+            venue: source,
+            rate: tick.rate,
+        };
+        if quotes.send(quote).is_err() {
+            log::error!("The router's input channel died. Stopping ...");
+            break;
+        }
+
+        aggregator.update(tick);
+        if let Some((current_trend, observed_at)) =
+            aggregator.consensus(clock.now())
+        {
+            let reading = seller::Message::TrendReading {
+                current_trend,
+                observed_at,
+            };
+            if output.send(reading).is_err() {
+                log::error!("The trend's output channel died. Stopping ...");
+                break;
+            }
+        }
+    });
+}
+
+impl Aggregator {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ticks: HashMap::new(),
+        }
+    }
+
+    /// Records a trade of a source.
+    pub fn update(&mut self, tick: Tick) {
+        self.ticks.entry(tick.source).or_default().push_back(tick);
+    }
+
+    /// Computes the consensus of the sources which traded recently and agree
+    /// with the others, and when the newest tick it's computed from was
+    /// observed. Returns none if no source qualifies.
+    pub fn consensus(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Option<(BtcExchangeRate, DateTime<Utc>)> {
+        let max_age = self.config.max_age;
+        // The latest tick and the recent volume of each fresh source.
+        let mut fresh = Vec::new();
+        for ticks in self.ticks.values_mut() {
+            ticks.retain(|tick| now - tick.observed_at <= max_age);
+            let latest = ticks.iter().max_by_key(|tick| tick.observed_at);
+            if let Some(latest) = latest {
+                let volume = ticks.iter().map(|tick| tick.volume).sum::<Btc>();
+                fresh.push((*latest, volume));
+            }
+        }
+
+        let all: Vec<_> = fresh.iter().map(|(tick, _)| tick.rate).collect();
+        let middle = median(&all)?;
+        if middle <= Decimal::new(0, 0) {
+            log::warn!("The median of the sources is {}, ignoring it", middle);
+            return None;
+        }
+        let max_divergence = self.config.max_divergence;
+        fresh.retain(|(Tick { source, rate, .. }, _)| {
+            let divergence =
+                ((rate - middle) / middle * Decimal::new(100, 0)).abs();
+            if divergence > max_divergence {
+                log::debug!(
+                    "Excluding {} at {} which is {}% off the median",
+                    source,
+                    rate,
+                    divergence.round_dp(2)
+                );
+            }
+            divergence <= max_divergence
+        });
+
+        let rates: Vec<_> = fresh.iter().map(|(tick, _)| tick.rate).collect();
+        let volume: Btc = fresh.iter().map(|(_, volume)| *volume).sum();
+        let rate = match self.config.consensus {
+            Consensus::VolumeWeighted if volume > Btc::new(0, 0) => {
+                let notional: Cash =
+                    fresh.iter().map(|(tick, volume)| tick.rate * volume).sum();
+                notional / volume
+            }
+            _ => median(&rates)?,
+        };
+        let observed_at =
+            fresh.iter().map(|(tick, _)| tick.observed_at).max()?;
+
+        Some((rate, observed_at))
+    }
+}
+
+// Reads the trades of the source, reconnecting when the connection drops,
+// until the aggregator stops.
+fn listen(source: Source, url: &str, ticks: Sender<Tick>) {
+    loop {
+        match stream(source, url, &ticks) {
+            Ok(()) => break,
+            Err(e) => {
+                log::warn!("The {} feed failed due to: {}", source, e);
+                thread::sleep(RECONNECT_AFTER);
+            }
+        }
+    }
+}
+
+// Subscribes to the source's trades and sends them as ticks. Returns an error
+// if the connection fails, and ok if the ticks channel died.
+fn stream(source: Source, url: &str, ticks: &Sender<Tick>) -> Result<()> {
+    let (mut socket, _) = tungstenite::connect(url)?;
+    socket.write_message(WsMessage::Text(source.subscription()))?;
+    log::info!("Subscribed to {} trades at {}", source, url);
+
+    loop {
+        let text = match socket.read_message()? {
+            WsMessage::Text(text) => text,
+            WsMessage::Close(_) => {
+                return Err(Box::new(Error::invalid_data("Connection closed")))
+            }
+            _ => continue,
+        };
+
+        match source.parse(&text) {
+            Ok(parsed) => {
+                for tick in parsed {
+                    if ticks.send(tick).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) => log::warn!("Cannot parse {} message: {}", source, e),
+        }
+    }
+}
+
+fn median(rates: &[BtcExchangeRate]) -> Option<BtcExchangeRate> {
+    let mut rates = rates.to_vec();
+    rates.sort();
+    let middle = rates.len() / 2;
+    match rates.len() {
+        0 => None,
+        n if n % 2 == 0 => {
+            Some((rates[middle - 1] + rates[middle]) / Decimal::new(2, 0))
+        }
+        _ => Some(rates[middle]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        chrono::TimeZone,
+        std::{fs, net::TcpListener},
+    };
+
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    fn config(consensus: Consensus) -> Config {
+        Config {
+            consensus,
+            max_divergence: Percentage::new(1, 0),
+            max_age: Duration::minutes(1),
+        }
+    }
+
+    // Serves the recorded messages of the source over a WebSocket to the first
+    // client which subscribes. Returns the url of the server.
+    fn serve(source: Source, file: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let subscription = socket.read_message().unwrap();
+            assert_eq!(
+                source.subscription(),
+                subscription.into_text().unwrap()
+            );
+
+            let path = format!("tests/data/ws/{}", file);
+            for line in fs::read_to_string(path).unwrap().lines() {
+                socket.write_message(WsMessage::Text(line.into())).unwrap();
+            }
+            // Keeps the connection open until the client goes away.
+            while socket.read_message().is_ok() {}
+        });
+
+        url
+    }
+
+    #[test]
+    fn should_exclude_stale_and_diverging_sources() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let tick = |source, rate, volume, seconds| Tick {
+            source,
+            rate: BtcExchangeRate::new(rate, 0),
+            volume: Btc::new(volume, 0),
+            observed_at: start + Duration::seconds(seconds),
+        };
+        let mut aggregator = Aggregator::new(config(Consensus::Median));
+        assert_eq!(None, aggregator.consensus(start));
+
+        aggregator.update(tick(Source::Kraken, 100, 1, 0));
+        aggregator.update(tick(Source::Bitstamp, 101, 1, 30));
+        aggregator.update(tick(Source::Coinbase, 150, 1, 30));
+        // The reading is as old as the newest tick it's computed from.
+        let now = start + Duration::seconds(45);
+        assert_eq!(
+            Some((
+                BtcExchangeRate::new(1005, 1),
+                start + Duration::seconds(30)
+            )),
+            aggregator.consensus(now)
+        );
+
+        // Kraken hasn't traded for too long. The two remaining sources are
+        // both too far from their median, we cannot tell which one is right.
+        let later = start + Duration::seconds(61);
+        assert_eq!(None, aggregator.consensus(later));
+
+        aggregator.update(tick(Source::Coinbase, 102, 1, 61));
+        assert_eq!(
+            Some((BtcExchangeRate::new(1015, 1), later)),
+            aggregator.consensus(later)
+        );
+
+        // A source which reports a zero rate doesn't panic the aggregator.
+        let mut aggregator = Aggregator::new(config(Consensus::Median));
+        aggregator.update(tick(Source::Kraken, 0, 1, 0));
+        assert_eq!(None, aggregator.consensus(start));
+    }
+
+    #[test]
+    fn should_aggregate_sources_served_over_websocket() {
+        let sources = vec![
+            (Source::Kraken, serve(Source::Kraken, "kraken.jsonl")),
+            (Source::Bitstamp, serve(Source::Bitstamp, "bitstamp.jsonl")),
+            (Source::Coinbase, serve(Source::Coinbase, "coinbase.jsonl")),
+        ];
+        let clock =
+            SimulatedClock::new(Utc.timestamp_opt(1590000006, 0).unwrap());
+        let (output, readings) = unbounded();
+        let (watchdog, watchdog_readings) = unbounded();
+        let (router, quotes) = unbounded();
+
+        spawn(
+            sources,
+            output,
+            watchdog,
+            router,
+            CircuitBreaker::default(),
+            Aggregator::new(config(Consensus::VolumeWeighted)),
+            Arc::new(clock),
+        );
+
+        let mut last = None;
+        let timeout = time::Duration::from_millis(500);
+        while let Ok(reading) = readings.recv_timeout(timeout) {
+            if let seller::Message::TrendReading { current_trend, .. } = reading
+            {
+                last = Some(current_trend);
+            }
+        }
+
+        // Coinbase is too far off. Kraken traded 0.45 BTC and was last at
+        // 9202, Bitstamp traded 0.75 BTC and was last at 9203.
+        assert_eq!(Some(BtcExchangeRate::new(9202625, 3)), last);
+        assert_eq!(7, watchdog_readings.len());
+        assert_eq!(7, quotes.len());
+    }
+}
@@ -0,0 +1,205 @@
+//! WebSocket formats of the exchanges we read trades from. Each source knows
+//! how to subscribe to the BTC/USD trades and how to parse the messages it
+//! sends into ticks.
+
+use {
+    chrono::{DateTime, Utc},
+    serde_json::{json, Value},
+    std::{fmt, str::FromStr},
+};
+
+use crate::{history::parse_unix_time, prelude::*};
+
+/// An exchange which publishes its trades over a WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    /// Kraken's public `trade` channel.
+    Kraken,
+    /// Bitstamp's `live_trades_btcusd` channel.
+    Bitstamp,
+    /// Coinbase's `matches` channel.
+    Coinbase,
+}
+
+/// A trade which happened at a source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tick {
+    pub source: Source,
+    pub rate: BtcExchangeRate,
+    pub volume: Btc,
+    pub observed_at: DateTime<Utc>,
+}
+
+impl Source {
+    /// The public endpoint of the source.
+    pub fn url(self) -> &'static str {
+        match self {
+            Self::Kraken => "wss://ws.kraken.com",
+            Self::Bitstamp => "wss://ws.bitstamp.net",
+            Self::Coinbase => "wss://ws-feed.exchange.coinbase.com",
+        }
+    }
+
+    /// The message which subscribes to BTC/USD trades.
+    pub fn subscription(self) -> String {
+        let message = match self {
+            Self::Kraken => json!({
+                "event": "subscribe",
+                "pair": ["XBT/USD"],
+                "subscription": { "name": "trade" },
+            }),
+            Self::Bitstamp => json!({
+                "event": "bts:subscribe",
+                "data": { "channel": "live_trades_btcusd" },
+            }),
+            Self::Coinbase => json!({
+                "type": "subscribe",
+                "product_ids": ["BTC-USD"],
+                "channels": ["matches"],
+            }),
+        };
+
+        message.to_string()
+    }
+
+    /// Parses the trades from a message. Messages which don't carry trades,
+    /// such as heartbeats or subscription confirmations, yield no ticks.
+    pub fn parse(self, message: &str) -> Result<Vec<Tick>> {
+        let message: Value = serde_json::from_str(message)?;
+        match self {
+            Self::Kraken => self.parse_kraken(&message),
+            Self::Bitstamp => self.parse_bitstamp(&message),
+            Self::Coinbase => self.parse_coinbase(&message),
+        }
+    }
+
+    // [channel_id, [[price, volume, time, side, type, misc], ...], "trade",
+    // "XBT/USD"]
+    fn parse_kraken(self, message: &Value) -> Result<Vec<Tick>> {
+        if message.get(2).and_then(Value::as_str) != Some("trade") {
+            return Ok(Vec::new());
+        }
+
+        let trades = message
+            .get(1)
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid("Kraken trade message has no trades"))?;
+        trades
+            .iter()
+            .map(|trade| {
+                Ok(Tick {
+                    source: self,
+                    rate: decimal(trade.get(0))?,
+                    volume: decimal(trade.get(1))?,
+                    observed_at: parse_unix_time(string(trade.get(2))?)?,
+                })
+            })
+            .collect()
+    }
+
+    // {"event": "trade", "data": {"price_str": .., "amount_str": ..,
+    // "timestamp": ..}}
+    fn parse_bitstamp(self, message: &Value) -> Result<Vec<Tick>> {
+        if message.get("event").and_then(Value::as_str) != Some("trade") {
+            return Ok(Vec::new());
+        }
+
+        let data = &message["data"];
+        Ok(vec![Tick {
+            source: self,
+            rate: decimal(data.get("price_str"))?,
+            volume: decimal(data.get("amount_str"))?,
+            observed_at: parse_unix_time(string(data.get("timestamp"))?)?,
+        }])
+    }
+
+    // {"type": "match", "price": .., "size": .., "time": RFC 3339}
+    fn parse_coinbase(self, message: &Value) -> Result<Vec<Tick>> {
+        let kind = message.get("type").and_then(Value::as_str);
+        if kind != Some("match") && kind != Some("last_match") {
+            return Ok(Vec::new());
+        }
+
+        let time = string(message.get("time"))?;
+        Ok(vec![Tick {
+            source: self,
+            rate: decimal(message.get("price"))?,
+            volume: decimal(message.get("size"))?,
+            observed_at: DateTime::parse_from_rfc3339(time)?
+                .with_timezone(&Utc),
+        }])
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Kraken => "kraken",
+            Self::Bitstamp => "bitstamp",
+            Self::Coinbase => "coinbase",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn string(value: Option<&Value>) -> Result<&str> {
+    value
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("Expected a string field"))
+}
+
+// Exchanges send numbers as strings to avoid losing precision.
+fn decimal(value: Option<&Value>) -> Result<Decimal> {
+    Ok(Decimal::from_str(string(value)?)?)
+}
+
+fn invalid(reason: &'static str) -> Box<dyn std::error::Error> {
+    Box::new(Error::invalid_data(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use {chrono::TimeZone, std::fs};
+
+    use super::*;
+
+    #[test]
+    fn should_parse_recorded_messages() -> Result<()> {
+        let ticks = |source: Source, file: &str| -> Result<Vec<Tick>> {
+            let path = format!("tests/data/ws/{}", file);
+            let mut ticks = Vec::new();
+            for line in fs::read_to_string(path)?.lines() {
+                ticks.extend(source.parse(line)?);
+            }
+            Ok(ticks)
+        };
+
+        let kraken = ticks(Source::Kraken, "kraken.jsonl")?;
+        assert_eq!(3, kraken.len());
+        assert_eq!(
+            Tick {
+                source: Source::Kraken,
+                rate: BtcExchangeRate::new(92005, 1),
+                volume: Btc::new(15, 2),
+                observed_at: Utc
+                    .timestamp_opt(1590000000, 321_000_000)
+                    .unwrap(),
+            },
+            kraken[0]
+        );
+
+        let bitstamp = ticks(Source::Bitstamp, "bitstamp.jsonl")?;
+        assert_eq!(2, bitstamp.len());
+        assert_eq!(BtcExchangeRate::new(92010, 1), bitstamp[0].rate);
+        assert_eq!(Btc::new(5, 1), bitstamp[0].volume);
+
+        let coinbase = ticks(Source::Coinbase, "coinbase.jsonl")?;
+        assert_eq!(2, coinbase.len());
+        assert_eq!(
+            Utc.timestamp_opt(1590000002, 28_459_000).unwrap(),
+            coinbase[0].observed_at
+        );
+
+        Ok(())
+    }
+}
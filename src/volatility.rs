@@ -0,0 +1,161 @@
+//! A fixed min margin is too tight when the trend swings wildly and too wide
+//! when it's calm. The seller can instead scale the margin it requires by the
+//! realised volatility of the trend, the standard deviation of its changes
+//! between samples taken at a fixed interval.
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    rust_decimal::prelude::ToPrimitive,
+    std::collections::VecDeque,
+};
+
+use crate::prelude::*;
+
+/// Parameters of the volatility adjustment.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// How many changes of the trend is the volatility computed from.
+    pub window: usize,
+    /// How far apart are the samples of the trend, e.g. a day for the daily
+    /// volatility.
+    pub sampling: Duration,
+    /// The volatility at which the min margin applies as is, in percent. The
+    /// margin scales linearly with the volatility.
+    pub reference: Percentage,
+    /// The least margin we require, in percent.
+    pub floor: Percentage,
+    /// The most margin we require, in percent.
+    pub ceiling: Percentage,
+}
+
+/// Samples the trend and tells the margin we require.
+#[derive(Debug)]
+pub struct Volatility {
+    config: Config,
+    // The samples within the window, oldest first.
+    samples: VecDeque<(DateTime<Utc>, BtcExchangeRate)>,
+}
+
+impl Volatility {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Takes a sample of the trend if the sampling interval has passed since
+    /// the last one.
+    pub fn observe(&mut self, rate: BtcExchangeRate, at: DateTime<Utc>) {
+        let due = self
+            .samples
+            .back()
+            .is_none_or(|(last, _)| at - *last >= self.config.sampling);
+        if due {
+            self.samples.push_back((at, rate));
+            while self.samples.len() > self.config.window + 1 {
+                self.samples.pop_front();
+            }
+        }
+    }
+
+    /// The standard deviation of the changes between the samples in
+    /// percent, once the window is full.
+    pub fn realised(&self) -> Option<Percentage> {
+        if self.samples.len() <= self.config.window {
+            None
+        } else {
+            Some(volatility(&self.samples))
+        }
+    }
+
+    /// Scales the min margin by the realised volatility within the floor
+    /// and the ceiling, rounded to hundredths of a percent. The min margin
+    /// applies as is until we know the volatility.
+    pub fn margin(&self, min_margin: Percentage) -> Percentage {
+        let Config {
+            reference,
+            floor,
+            ceiling,
+            ..
+        } = self.config;
+        match self.realised() {
+            Some(realised) if reference > Percentage::new(0, 0) => {
+                let margin = (min_margin * realised / reference).round_dp(2);
+                margin.max(floor).min(ceiling)
+            }
+            _ => min_margin,
+        }
+    }
+}
+
+// Sample standard deviation of the changes between consecutive samples, in
+// percent. It's computed in floats, as it's only a rough measure and the
+// decimals are slow to divide.
+fn volatility(
+    samples: &VecDeque<(DateTime<Utc>, BtcExchangeRate)>,
+) -> Percentage {
+    let rates: Vec<f64> = samples
+        .iter()
+        .filter_map(|(_, rate)| rate.to_f64())
+        .collect();
+    let changes: Vec<f64> = rates
+        .windows(2)
+        .filter(|w| w[0] != 0.0)
+        .map(|w| (w[1] - w[0]) / w[0] * 100.0)
+        .collect();
+    if changes.len() < 2 {
+        return Percentage::new(0, 0);
+    }
+
+    let n = changes.len() as f64;
+    let mean = changes.iter().sum::<f64>() / n;
+    let variance =
+        changes.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    // Millionths of a percent are precise enough, and converting them is
+    // much faster than converting the float as is.
+    Percentage::new((variance.sqrt() * 1e6).round() as i64, 6)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn should_scale_margin_by_realised_volatility() {
+        let mut volatility = Volatility::new(Config {
+            window: 3,
+            sampling: Duration::days(1),
+            reference: Percentage::new(2, 0),
+            floor: Percentage::new(3, 0),
+            ceiling: Percentage::new(15, 0),
+        });
+        let start = Utc.with_ymd_and_hms(2020, 3, 1, 0, 0, 0).unwrap();
+        let mut observe = |rate, hours| {
+            volatility.observe(
+                BtcExchangeRate::new(rate, 0),
+                start + Duration::hours(hours),
+            );
+            volatility.margin(Percentage::new(5, 0))
+        };
+
+        // Readings within a day of the last sample are skipped.
+        assert_eq!(Percentage::new(5, 0), observe(100, 0));
+        assert_eq!(Percentage::new(5, 0), observe(200, 12));
+        assert_eq!(Percentage::new(5, 0), observe(102, 24));
+        assert_eq!(Percentage::new(5, 0), observe(100, 48));
+
+        // The trend changed by 2 %, -1.96 % and 2 %, whose standard
+        // deviation is about 2.28 %.
+        let margin = observe(102, 72);
+        assert!(margin > Percentage::new(57, 1));
+        assert!(margin < Percentage::new(58, 1));
+
+        // A calm trend hits the floor and a wild one the ceiling.
+        observe(102, 96);
+        assert_eq!(Percentage::new(3, 0), observe(102, 120));
+        assert_eq!(Percentage::new(15, 0), observe(130, 144));
+    }
+}
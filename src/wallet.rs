@@ -0,0 +1,224 @@
+//! The wallet keeps track of how much cash and bitcoin we hold. It is shared
+//! by the buyer and the seller: before an order is placed at the marketplace
+//! the funds it needs are reserved, so that two orders can never spend the
+//! same money.
+
+use {
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+    uuid::Uuid,
+};
+
+use crate::{models::Balances, prelude::*};
+
+/// Balances and reservations for pending orders.
+#[derive(Debug, Default, Clone)]
+pub struct Wallet {
+    // Total cash, including the reserved cash.
+    cash: Cash,
+    // Total bitcoins, including the reserved bitcoins.
+    btc: Btc,
+    // Cash reserved for pending buy orders, by order id.
+    reserved_cash: HashMap<Uuid, Cash>,
+    // Bitcoins reserved for pending sell orders, by order id.
+    reserved_btc: HashMap<Uuid, Btc>,
+}
+
+/// A wallet which can be shared between actors.
+#[derive(Debug, Default, Clone)]
+pub struct SharedWallet(Arc<Mutex<Wallet>>);
+
+/// Which balance differs between our books and the exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Asset {
+    Cash,
+    Btc,
+}
+
+/// A balance our books disagree about with the exchange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Discrepancy {
+    pub asset: Asset,
+    pub local: Decimal,
+    pub exchange: Decimal,
+}
+
+impl Wallet {
+    pub fn new(cash: Cash, btc: Btc) -> Self {
+        Self {
+            cash,
+            btc,
+            ..Self::default()
+        }
+    }
+
+    /// Total cash, including the cash reserved for pending orders.
+    pub fn cash(&self) -> Cash {
+        self.cash
+    }
+
+    /// Total bitcoins, including those reserved for pending orders.
+    pub fn btc(&self) -> Btc {
+        self.btc
+    }
+
+    /// Cash which is not reserved for any order.
+    pub fn available_cash(&self) -> Cash {
+        self.cash - self.reserved_cash.values().copied().sum::<Cash>()
+    }
+
+    /// Bitcoins which are not reserved for any order.
+    pub fn available_btc(&self) -> Btc {
+        self.btc - self.reserved_btc.values().copied().sum::<Btc>()
+    }
+
+    /// Reserves cash for a buy order. Fails if there isn't enough cash which
+    /// is not already reserved.
+    pub fn reserve_cash(&mut self, order_id: Uuid, cash: Cash) -> Result<()> {
+        if cash > self.available_cash() {
+            return Err(Box::new(Error::insufficient_funds(format!(
+                "Cannot reserve ${} out of available ${}",
+                cash,
+                self.available_cash()
+            ))));
+        }
+
+        *self.reserved_cash.entry(order_id).or_default() += cash;
+        Ok(())
+    }
+
+    /// Reserves bitcoins for a sell order. Fails if there aren't enough
+    /// bitcoins which are not already reserved.
+    pub fn reserve_btc(&mut self, order_id: Uuid, btc: Btc) -> Result<()> {
+        if btc > self.available_btc() {
+            return Err(Box::new(Error::insufficient_funds(format!(
+                "Cannot reserve {} BTC out of available {} BTC",
+                btc,
+                self.available_btc()
+            ))));
+        }
+
+        *self.reserved_btc.entry(order_id).or_default() += btc;
+        Ok(())
+    }
+
+    /// Frees the funds reserved for an order which was cancelled or expired.
+    pub fn release(&mut self, order_id: Uuid) {
+        self.reserved_cash.remove(&order_id);
+        self.reserved_btc.remove(&order_id);
+    }
+
+    /// Records a filled buy order and frees its reservation.
+    pub fn settle_buy(&mut self, order_id: Uuid, spent: Cash, bought: Btc) {
+        self.release(order_id);
+        self.cash -= spent;
+        self.btc += bought;
+    }
+
+    /// Records a filled sell order and frees its reservation.
+    pub fn settle_sell(&mut self, order_id: Uuid, sold: Btc, received: Cash) {
+        self.release(order_id);
+        self.btc -= sold;
+        self.cash += received;
+    }
+
+    /// Compares our totals with the balances reported by the exchange. The
+    /// exchange counts funds in open orders towards the balance, therefore
+    /// so do we. Differences within the tolerance are ignored.
+    pub fn reconcile(
+        &self,
+        balances: &Balances,
+        tolerance: Decimal,
+    ) -> Vec<Discrepancy> {
+        let mut discrepancies = Vec::new();
+        for (asset, local, exchange) in &[
+            (Asset::Cash, self.cash, balances.cash),
+            (Asset::Btc, self.btc, balances.btc),
+        ] {
+            if (local - exchange).abs() > tolerance {
+                discrepancies.push(Discrepancy {
+                    asset: *asset,
+                    local: *local,
+                    exchange: *exchange,
+                });
+            }
+        }
+
+        discrepancies
+    }
+
+    /// Overwrites our totals with the balances reported by the exchange. The
+    /// reservations are kept.
+    pub fn sync(&mut self, balances: &Balances) {
+        self.cash = balances.cash;
+        self.btc = balances.btc;
+    }
+}
+
+impl SharedWallet {
+    pub fn new(wallet: Wallet) -> Self {
+        Self(Arc::new(Mutex::new(wallet)))
+    }
+
+    /// Locks the wallet for the duration of the returned guard.
+    pub fn lock(&self) -> MutexGuard<'_, Wallet> {
+        // None of the wallet's methods can panic half way through an update,
+        // so the wallet is consistent even if the lock is poisoned.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_reject_reservations_beyond_available_funds() {
+        let mut wallet = Wallet::new(Cash::new(100, 0), Btc::new(1, 0));
+        let (bid, offer) = (Uuid::new_v4(), Uuid::new_v4());
+
+        wallet.reserve_cash(bid, Cash::new(60, 0)).unwrap();
+        assert_eq!(Cash::new(40, 0), wallet.available_cash());
+        assert!(wallet
+            .reserve_cash(Uuid::new_v4(), Cash::new(50, 0))
+            .is_err());
+
+        wallet.reserve_btc(offer, Btc::new(1, 0)).unwrap();
+        assert!(wallet.reserve_btc(Uuid::new_v4(), Btc::new(1, 8)).is_err());
+
+        wallet.settle_buy(bid, Cash::new(60, 0), Btc::new(5, 1));
+        assert_eq!(Cash::new(40, 0), wallet.cash());
+        assert_eq!(Cash::new(40, 0), wallet.available_cash());
+        assert_eq!(Btc::new(15, 1), wallet.btc());
+        assert_eq!(Btc::new(5, 1), wallet.available_btc());
+
+        wallet.release(offer);
+        assert_eq!(Btc::new(15, 1), wallet.available_btc());
+    }
+
+    #[test]
+    fn should_reconcile_with_exchange_balances() {
+        let mut wallet = Wallet::new(Cash::new(100, 0), Btc::new(1, 0));
+        wallet.reserve_btc(Uuid::new_v4(), Btc::new(1, 0)).unwrap();
+        let balances = Balances {
+            cash: Cash::new(10001, 2),
+            btc: Btc::new(9, 1),
+        };
+
+        assert_eq!(
+            vec![Discrepancy {
+                asset: Asset::Btc,
+                local: Btc::new(1, 0),
+                exchange: Btc::new(9, 1),
+            }],
+            wallet.reconcile(&balances, Decimal::new(1, 2))
+        );
+
+        wallet.sync(&balances);
+        assert!(wallet.reconcile(&balances, Decimal::new(0, 0)).is_empty());
+        // We have reserved more than the exchange says we have.
+        assert_eq!(Btc::new(-1, 1), wallet.available_btc());
+    }
+}
@@ -0,0 +1,186 @@
+//! Watchdog is an actor which makes sure the feed we trade on is alive. The
+//! seller drops individual readings which are too old, but it has no way of
+//! noticing that readings stopped arriving altogether.
+//!
+//! The watchdog is told about every reading of every source. When a source is
+//! silent for longer than the threshold, an alert is raised. When all sources
+//! are silent, trading is paused via the circuit breaker, and resumed once a
+//! fresh reading arrives.
+
+use {
+    chrono::{DateTime, Duration, Utc},
+    crossbeam_channel::{Receiver, RecvTimeoutError},
+    std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        thread, time,
+    },
+};
+
+use crate::{
+    breaker::{CircuitBreaker, Trip},
+    clock::Clock,
+};
+
+pub enum Message {
+    /// A source sent a reading.
+    Reading {
+        source: String,
+        observed_at: DateTime<Utc>,
+    },
+}
+
+/// Tracks when each source was last heard from.
+pub struct Watchdog {
+    // How long can a source be silent before it's considered stale.
+    max_silence: Duration,
+    clock: Arc<dyn Clock>,
+    // Trading is paused via the breaker when all sources are stale.
+    breaker: CircuitBreaker,
+    // When was the last reading of each source observed.
+    last_readings: HashMap<String, DateTime<Utc>>,
+    // Sources we've already raised an alert about.
+    stale: HashSet<String>,
+    // Whether we have paused trading.
+    paused: bool,
+}
+
+/// Spawns a new thread which runs the watchdog. The sources are checked every
+/// time a reading arrives, and at least once per given interval.
+pub fn spawn(
+    input: Receiver<Message>,
+    mut watchdog: Watchdog,
+    interval: time::Duration,
+) {
+    thread::spawn(move || loop {
+        match input.recv_timeout(interval) {
+            Ok(message) => watchdog.handle(message),
+            Err(RecvTimeoutError::Timeout) => watchdog.check(),
+            Err(RecvTimeoutError::Disconnected) => {
+                log::error!("The watchdog's input channel died. Stopping ...");
+                break;
+            }
+        }
+    });
+}
+
+impl Watchdog {
+    /// Creates a watchdog for given sources. The sources are given the
+    /// threshold from now to send their first reading.
+    pub fn new(
+        sources: &[&str],
+        max_silence: Duration,
+        clock: Arc<dyn Clock>,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        let now = clock.now();
+        Self {
+            max_silence,
+            clock,
+            breaker,
+            last_readings: sources
+                .iter()
+                .map(|source| (source.to_string(), now))
+                .collect(),
+            stale: HashSet::new(),
+            paused: false,
+        }
+    }
+
+    pub fn handle(&mut self, message: Message) {
+        match message {
+            Message::Reading {
+                source,
+                observed_at,
+            } => {
+                let last =
+                    self.last_readings.entry(source).or_insert(observed_at);
+                *last = (*last).max(observed_at);
+                self.check();
+            }
+        }
+    }
+
+    /// Raises alerts about sources which went silent, and pauses or resumes
+    /// trading depending on whether any source is alive.
+    pub fn check(&mut self) {
+        let now = self.clock.now();
+        let mut shortest_silence = None;
+        for (source, last) in &self.last_readings {
+            let silence = now - *last;
+            if silence > self.max_silence {
+                if self.stale.insert(source.clone()) {
+                    log::error!(
+                        "No reading from {} for {}s",
+                        source,
+                        silence.num_seconds()
+                    );
+                }
+            } else if self.stale.remove(source) {
+                log::info!("{} is sending readings again", source);
+            }
+
+            shortest_silence = Some(
+                shortest_silence.map_or(silence, |s: Duration| s.min(silence)),
+            );
+        }
+
+        let all_stale = !self.last_readings.is_empty()
+            && self.stale.len() == self.last_readings.len();
+        if all_stale && !self.paused {
+            let trip = Trip::StaleFeed(
+                shortest_silence.unwrap_or_else(Duration::zero),
+            );
+            log::error!("Pausing trading: {}", trip);
+            self.breaker.pause(trip);
+            self.paused = true;
+        } else if !all_stale && self.paused {
+            log::info!("The feed is alive again, resuming trading");
+            self.breaker.resume();
+            self.paused = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn should_pause_trading_while_all_sources_are_silent() {
+        let clock = SimulatedClock::new(
+            Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+        );
+        let breaker = CircuitBreaker::default();
+        let mut watchdog = Watchdog::new(
+            &["kraken", "bitstamp"],
+            Duration::minutes(1),
+            Arc::new(clock.clone()),
+            breaker.clone(),
+        );
+        let reading = |source: &str| Message::Reading {
+            source: source.to_string(),
+            observed_at: clock.now(),
+        };
+
+        clock.advance(Duration::seconds(50));
+        watchdog.handle(reading("kraken"));
+        clock.advance(Duration::seconds(50));
+        watchdog.check();
+        // Bitstamp is silent, but kraken is still alive.
+        assert!(breaker.check(clock.now()).is_ok());
+
+        clock.advance(Duration::seconds(20));
+        watchdog.check();
+        assert_eq!(
+            Err(Trip::StaleFeed(Duration::seconds(70))),
+            breaker.check(clock.now())
+        );
+
+        watchdog.handle(reading("bitstamp"));
+        assert!(breaker.check(clock.now()).is_ok());
+    }
+}